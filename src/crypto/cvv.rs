@@ -0,0 +1,80 @@
+//! This module contains the TDES card verification value algorithm (CVV/CVC) used for the CVV of the magnetic
+//! stripe, the iCVV of the track 2 equivalent data of chip cards and the CVV2 printed on the card
+
+use crate::crypto::{Cipher, CryptoError};
+
+/// The service code the iCVV is computed with
+pub const ICVV_SERVICE_CODE: &str = "999";
+/// The service code the CVV2 is computed with
+pub const CVV2_SERVICE_CODE: &str = "000";
+
+/// This enum represents the kind of a card verification value
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CvvType {
+    /// The CVV (CVC1) in the track data of the magnetic stripe, with the service code of the track
+    Cvv,
+    /// The iCVV in the track 2 equivalent data of a chip card, with the service code 999
+    Icvv,
+    /// The CVV2 (CVC2) printed on the card, with the service code 000
+    Cvv2,
+}
+
+impl CvvType {
+    /// Returns the service code the CVV is computed with, track_service_code for a CVV
+    pub fn service_code<'a>(&self, track_service_code: &'a str) -> &'a str {
+        match self {
+            CvvType::Cvv => track_service_code,
+            CvvType::Icvv => ICVV_SERVICE_CODE,
+            CvvType::Cvv2 => CVV2_SERVICE_CODE
+        }
+    }
+}
+
+/// Returns the 3 digit CVV of the card (pan, expiry as YYMM and service code) with cvk (the double length key of
+/// CVK A and CVK B)
+pub fn generate_cvv(cvk: &[u8], pan: &str, expiry: &str, service_code: &str) -> Result<String, CryptoError> {
+    if cvk.len() != 16 {
+        return Err(CryptoError { msg: format!("invalid CVK length: {} (expected 16)", cvk.len()) });
+    }
+    for (name, val, min, max) in [("PAN", pan, 12, 19), ("expiry", expiry, 4, 4), ("service code", service_code, 3, 3)] {
+        if val.len() < min || val.len() > max || !val.chars().all(|c| c.is_ascii_digit()) {
+            return Err(CryptoError { msg: format!("invalid {}: {}", name, val) });
+        }
+    }
+
+    let data = hex::decode(format!("{:0<32}", format!("{}{}{}", pan, expiry, service_code))).unwrap();
+    let res = Cipher::Des.encrypt_ecb(&data[0..8], &cvk[0..8])?;
+    let res: Vec<u8> = res.iter().zip(data[8..16].iter()).map(|(a, b)| a ^ b).collect();
+    let res = hex::encode_upper(Cipher::TdesEde2.encrypt_ecb(&res, cvk)?);
+
+    let digits = res.chars().filter(|c| c.is_ascii_digit());
+    let letters = res.chars().filter(|c| !c.is_ascii_digit()).map(|c| std::char::from_digit(c.to_digit(16).unwrap() - 10, 10).unwrap());
+    Ok(digits.chain(letters).take(3).collect())
+}
+
+/// Verifies the CVV of the card (pan, expiry as YYMM and service code) with cvk
+pub fn verify_cvv(cvk: &[u8], pan: &str, expiry: &str, service_code: &str, cvv: &str) -> Result<bool, CryptoError> {
+    Ok(generate_cvv(cvk, pan, expiry, service_code)? == cvv)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::cvv::{generate_cvv, verify_cvv, CvvType};
+
+    #[test]
+    fn test_cvv() {
+        let cvk = hex::decode("0123456789ABCDEFFEDCBA9876543210").unwrap();
+        assert_eq!(generate_cvv(&cvk, "4123456789012345", "8701", "101").unwrap(), "561");
+        assert_eq!(generate_cvv(&cvk, "4123456789012345", "8701", CvvType::Icvv.service_code("101")).unwrap(), "651");
+        assert_eq!(generate_cvv(&cvk, "4123456789012345", "8701", CvvType::Cvv2.service_code("101")).unwrap(), "636");
+        // less than 3 decimal digits in the result
+        assert_eq!(generate_cvv(&cvk, "4111111111111111", "2512", "000").unwrap(), "765");
+        assert_eq!(generate_cvv(&cvk, "5413330089020011", "2512", "101").unwrap(), "536");
+
+        assert!(verify_cvv(&cvk, "4123456789012345", "8701", "101", "561").unwrap());
+        assert!(!verify_cvv(&cvk, "4123456789012345", "8702", "101", "561").unwrap());
+        assert!(generate_cvv(&cvk, "4123456789012345", "870", "101").is_err());
+        assert!(generate_cvv(&cvk[0..8], "4123456789012345", "8701", "101").is_err());
+    }
+}
@@ -0,0 +1,283 @@
+//! This module implements DUKPT (ANSI X9.24-1:2009, TDES) key derivation - the initial PIN encryption key (IPEK)
+//! from the base derivation key (BDK) and the key serial number (KSN) and the per-transaction PIN, MAC and data
+//! encryption keys. AES DUKPT (ANSI X9.24-3:2017) with a 12 byte KSN is supported via `derive_aes_key`
+
+use crate::crypto::{tdes_encrypt, aes_encrypt, Cipher, CryptoError};
+
+const KEY_MASK: [u8; 16] = [0xC0, 0xC0, 0xC0, 0xC0, 0, 0, 0, 0, 0xC0, 0xC0, 0xC0, 0xC0, 0, 0, 0, 0];
+
+/// This enum represents the usage of a DUKPT transaction key, each usage applies a different variant to the key
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum KeyUsage {
+    Pin,
+    MacRequest,
+    MacResponse,
+    DataRequest,
+    DataResponse,
+}
+
+impl KeyUsage {
+    fn variant(&self) -> [u8; 16] {
+        let mut v = [0u8; 16];
+        let pos = match self {
+            KeyUsage::Pin => 7,
+            KeyUsage::MacRequest => 6,
+            KeyUsage::DataRequest => 5,
+            KeyUsage::MacResponse => 4,
+            KeyUsage::DataResponse => 3,
+        };
+        v[pos] = 0xFF;
+        v[pos + 8] = 0xFF;
+        v
+    }
+}
+
+/// Derives the AES DUKPT initial key from a AES BDK and the 8 byte initial key ID (the leftmost 8 bytes of the KSN)
+pub fn derive_aes_initial_key(bdk: &[u8], ik_id: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let bdk_type = Cipher::aes(bdk)?;
+    if ik_id.len() != 8 {
+        return Err(CryptoError { msg: format!("invalid initial key ID length: {} (expected 8)", ik_id.len()) });
+    }
+    aes_derive(bdk, &aes_derivation_data(0x8001, bdk_type, ik_id, None))
+}
+
+/// Derives the AES DUKPT working key of key_type (TdesEde2, TdesEde3 or AES) for usage for the transaction with the
+/// given (12 byte) KSN from a AES BDK. For MAC and data keys, the Request usages derive the generation/encryption
+/// keys and the Response usages the verification/decryption keys
+pub fn derive_aes_key(bdk: &[u8], ksn: &[u8], usage: KeyUsage, key_type: Cipher) -> Result<Vec<u8>, CryptoError> {
+    let bdk_type = Cipher::aes(bdk)?;
+    if ksn.len() != 12 {
+        return Err(CryptoError { msg: format!("invalid AES DUKPT KSN length: {} (expected 12)", ksn.len()) });
+    }
+    if key_type == Cipher::Des || key_type.key_len() > bdk_type.key_len() {
+        return Err(CryptoError { msg: format!("{:?} working key can't be derived from a {:?} BDK", key_type, bdk_type) });
+    }
+
+    let ik_id = &ksn[0..8];
+    let counter = u32::from_be_bytes([ksn[8], ksn[9], ksn[10], ksn[11]]);
+
+    let mut key = derive_aes_initial_key(bdk, ik_id)?;
+    let mut working_counter = 0u32;
+    let mut bit = 0x8000_0000u32;
+    while bit > 0 {
+        if counter & bit != 0 {
+            working_counter |= bit;
+            key = aes_derive(&key, &aes_derivation_data(0x8000, bdk_type, ik_id, Some(working_counter)))?;
+        }
+        bit >>= 1;
+    }
+
+    let usage = match usage {
+        KeyUsage::Pin => 0x1000,
+        KeyUsage::MacRequest => 0x2000,
+        KeyUsage::MacResponse => 0x2001,
+        KeyUsage::DataRequest => 0x3000,
+        KeyUsage::DataResponse => 0x3001,
+    };
+    aes_derive(&key, &aes_derivation_data(usage, key_type, ik_id, Some(counter)))
+}
+
+/// Returns the 16 byte derivation data - version, key block counter, key usage, algorithm, key length (in bits) and
+/// the initial key ID (or its rightmost 4 bytes followed by the transaction counter)
+fn aes_derivation_data(usage: u16, key_type: Cipher, ik_id: &[u8], counter: Option<u32>) -> Vec<u8> {
+    let algo: u16 = match key_type {
+        Cipher::TdesEde2 => 0,
+        Cipher::TdesEde3 => 1,
+        Cipher::Aes128 => 2,
+        Cipher::Aes192 => 3,
+        _ => 4,
+    };
+
+    let mut data = vec![0x01, 0x01];
+    data.extend_from_slice(&usage.to_be_bytes());
+    data.extend_from_slice(&algo.to_be_bytes());
+    data.extend_from_slice(&((key_type.key_len() * 8) as u16).to_be_bytes());
+    match counter {
+        Some(c) => {
+            data.extend_from_slice(&ik_id[4..8]);
+            data.extend_from_slice(&c.to_be_bytes());
+        }
+        None => data.extend_from_slice(ik_id)
+    }
+    data
+}
+
+/// Derives a key (of the length in the derivation data) by encrypting the derivation data (with an incrementing
+/// key block counter) with key
+fn aes_derive(key: &[u8], data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let key_len = u16::from_be_bytes([data[6], data[7]]) as usize / 8;
+    let mut res = vec![];
+    let mut data = data.to_vec();
+    while res.len() < key_len {
+        res.extend(aes_encrypt(&data, key)?);
+        data[1] += 1;
+    }
+    res.truncate(key_len);
+    Ok(res)
+}
+
+/// Derives the IPEK from a (2 key TDES) BDK and a 10 byte KSN
+pub fn derive_ipek(bdk: &[u8], ksn: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    check(bdk, ksn)?;
+
+    let mut ksn_8 = ksn[0..8].to_vec();
+    ksn_8[7] &= 0xE0;
+
+    let mut ipek = tdes_encrypt(&ksn_8, bdk)?;
+    ipek.extend(tdes_encrypt(&ksn_8, xor(bdk, &KEY_MASK).as_slice())?);
+    Ok(ipek)
+}
+
+/// Derives the (future) key of the transaction counter in the KSN from the IPEK without a variant
+pub fn derive_transaction_key(ipek: &[u8], ksn: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    check(ipek, ksn)?;
+
+    let counter = u32::from_be_bytes([0, ksn[7], ksn[8], ksn[9]]) & 0x1F_FFFF;
+    let mut ksn_reg = ksn[2..10].to_vec();
+    ksn_reg[5] &= 0xE0;
+    ksn_reg[6] = 0;
+    ksn_reg[7] = 0;
+
+    let mut key = ipek.to_vec();
+    let mut bit = 0x10_0000u32;
+    while bit > 0 {
+        if counter & bit != 0 {
+            ksn_reg[5] |= (bit >> 16) as u8;
+            ksn_reg[6] |= (bit >> 8) as u8;
+            ksn_reg[7] |= bit as u8;
+            key = non_reversible_key(&key, &ksn_reg)?;
+        }
+        bit >>= 1;
+    }
+    Ok(key)
+}
+
+/// Derives the key for usage (PIN, MAC or data encryption) for the transaction with the given KSN from the BDK
+pub fn derive_key(bdk: &[u8], ksn: &[u8], usage: KeyUsage) -> Result<Vec<u8>, CryptoError> {
+    let ipek = derive_ipek(bdk, ksn)?;
+    let key = xor(&derive_transaction_key(&ipek, ksn)?, &usage.variant());
+
+    match usage {
+        KeyUsage::DataRequest | KeyUsage::DataResponse => {
+            // the data encryption key is the variant key encrypted with itself
+            let mut res = tdes_encrypt(&key[0..8], &key)?;
+            res.extend(tdes_encrypt(&key[8..16], &key)?);
+            Ok(res)
+        }
+        _ => Ok(key)
+    }
+}
+
+/// The non-reversible key generation process
+fn non_reversible_key(key: &[u8], ksn_reg: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let (kl, kr) = (&key[0..8], &key[8..16]);
+    let right = xor(&tdes_encrypt(&xor(ksn_reg, kr), kl)?, kr);
+
+    let masked = xor(key, &KEY_MASK);
+    let (kl, kr) = (&masked[0..8], &masked[8..16]);
+    let mut left = xor(&tdes_encrypt(&xor(ksn_reg, kr), kl)?, kr);
+
+    left.extend(right);
+    Ok(left)
+}
+
+fn check(key: &[u8], ksn: &[u8]) -> Result<(), CryptoError> {
+    if key.len() != 16 {
+        return Err(CryptoError { msg: format!("invalid DUKPT key length: {} (expected 16)", key.len()) });
+    }
+    if ksn.len() != 10 {
+        return Err(CryptoError { msg: format!("invalid KSN length: {} (expected 10)", ksn.len()) });
+    }
+    Ok(())
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(x, y)| x ^ y).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::dukpt::{derive_ipek, derive_key, derive_transaction_key, derive_aes_initial_key, derive_aes_key, KeyUsage};
+    use crate::crypto::Cipher;
+    use crate::crypto::pin::{generate_pin_block, PinFormat};
+
+    const BDK: &str = "0123456789ABCDEFFEDCBA9876543210";
+
+    fn key(ksn: &str, usage: KeyUsage) -> String {
+        hex::encode_upper(derive_key(&hex::decode(BDK).unwrap(), &hex::decode(ksn).unwrap(), usage).unwrap())
+    }
+
+    #[test]
+    fn test_ipek() {
+        let ipek = derive_ipek(&hex::decode(BDK).unwrap(), &hex::decode("FFFF9876543210E00000").unwrap()).unwrap();
+        assert_eq!(hex::encode_upper(&ipek), "6AC292FAA1315B4D858AB3A3D7D5933A");
+
+        assert!(derive_ipek(&hex::decode(BDK).unwrap(), &hex::decode("FFFF9876543210E000").unwrap()).is_err());
+        assert!(derive_ipek(&hex::decode("0123456789ABCDEF").unwrap(), &hex::decode("FFFF9876543210E00000").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_transaction_keys() {
+        let ipek = hex::decode("6AC292FAA1315B4D858AB3A3D7D5933A").unwrap();
+        let tk = |ksn: &str| hex::encode_upper(derive_transaction_key(&ipek, &hex::decode(ksn).unwrap()).unwrap());
+
+        // ANSI X9.24-1:2009 Annex A
+        assert_eq!(tk("FFFF9876543210E00001"), "042666B49184CFA368DE9628D0397BC9");
+        assert_eq!(tk("FFFF9876543210E00002"), "C46551CEF9FD24B0AA9AD834130D3BC7");
+        assert_eq!(tk("FFFF9876543210E00003"), "0DF3D9422ACA56E547676D07AD6BADFA");
+        assert_eq!(tk("FFFF9876543210E00012"), "9CF640F279C2AEE615F725EEEAC2CBAF");
+        assert_eq!(tk("FFFF9876543210EFF800"), "F9CDFEBF4F5B1D9EB3EC12454527E176");
+    }
+
+    #[test]
+    fn test_key_variants() {
+        assert_eq!(key("FFFF9876543210E00001", KeyUsage::Pin), "042666B49184CF5C68DE9628D0397B36");
+        assert_eq!(key("FFFF9876543210E00001", KeyUsage::MacRequest), "042666B4918430A368DE9628D03984C9");
+        assert_eq!(key("FFFF9876543210E00001", KeyUsage::DataRequest), "448D3F076D8304036A55A3D7E0055A78");
+        assert_eq!(key("FFFF9876543210E01FFE", KeyUsage::Pin), "C84D15B055471A925FBCE7833CFFD51A");
+    }
+
+    #[test]
+    fn test_dukpt_pin_block() {
+        let key = hex::decode(key("FFFF9876543210E00001", KeyUsage::Pin)).unwrap();
+        let pin_block = generate_pin_block(&PinFormat::ISO0, "1234", "4012345678909", &key).unwrap();
+        assert_eq!(hex::encode_upper(pin_block), "1B9C1845EB993A7A");
+    }
+
+    #[test]
+    fn test_aes_dukpt() {
+        let bdk = hex::decode("FEDCBA9876543210F1F1F1F1F1F1F1F1").unwrap();
+        let ik = derive_aes_initial_key(&bdk, &hex::decode("1234567890123456").unwrap()).unwrap();
+        assert_eq!(hex::encode_upper(ik), "1273671EA26AC29AFA4D1084127652A1");
+
+        let key = |ksn: &str, usage: KeyUsage, key_type: Cipher| {
+            hex::encode_upper(derive_aes_key(&bdk, &hex::decode(ksn).unwrap(), usage, key_type).unwrap())
+        };
+
+        // ANSI X9.24-3:2017 - AES-128 BDK
+        assert_eq!(key("123456789012345600000001", KeyUsage::Pin, Cipher::Aes128), "AF8CB133A78F8DC2D1359F18527593FB");
+        assert_eq!(key("123456789012345600000001", KeyUsage::MacRequest, Cipher::Aes128), "A2DC23DE6FDE0824A2BC321E08E4B8B7");
+        assert_eq!(key("123456789012345600000001", KeyUsage::MacResponse, Cipher::Aes128), "DBB463945B286C07CD3AD82EE96FD9C9");
+        assert_eq!(key("123456789012345600000001", KeyUsage::DataRequest, Cipher::Aes128), "A35C412EFD41FDB98B69797C02DCD08F");
+        assert_eq!(key("123456789012345600000002", KeyUsage::Pin, Cipher::Aes128), "D30BDC73EC9714B000BEC66BDB7B6D09");
+        assert_eq!(key("123456789012345600010000", KeyUsage::Pin, Cipher::Aes128), "851AB519776C0935DFF1FCFF708A8CBA");
+        assert_eq!(key("123456789012345600000001", KeyUsage::Pin, Cipher::TdesEde2), "630C706D9546E47D4449313F61C4D4AB");
+
+        assert!(derive_aes_key(&bdk, &hex::decode("1234567890123456000001").unwrap(), KeyUsage::Pin, Cipher::Aes128).is_err());
+        assert!(derive_aes_key(&bdk, &hex::decode("123456789012345600000001").unwrap(), KeyUsage::Pin, Cipher::Aes256).is_err());
+    }
+
+    #[test]
+    fn test_aes256_dukpt() {
+        let bdk = hex::decode("FEDCBA9876543210F1F1F1F1F1F1F1F1FEDCBA9876543210F1F1F1F1F1F1F1F1").unwrap();
+        let ik = derive_aes_initial_key(&bdk, &hex::decode("1234567890123456").unwrap()).unwrap();
+        assert_eq!(hex::encode_upper(ik), "CE9CE0C101D1138F97FB6CAD4DF045A7083D4EAE2D35A31789D01CCF0949550F");
+
+        let ksn = hex::decode("123456789012345600000001").unwrap();
+        assert_eq!(hex::encode_upper(derive_aes_key(&bdk, &ksn, KeyUsage::Pin, Cipher::Aes256).unwrap()),
+                   "8C1AB7BEE973829E30242E0BBBDD4946D540C98FC1B5BDCF94790001A23FD502");
+        assert_eq!(hex::encode_upper(derive_aes_key(&bdk, &ksn, KeyUsage::Pin, Cipher::Aes128).unwrap()),
+                   "09C9C432966811D6B2C3336BAC1B1202");
+    }
+}
@@ -0,0 +1,280 @@
+//! This module contains the issuer side of the EMV application cryptograms (EMV 4.x Book 2 Annex A1) - ICC master
+//! key derivation (option A and B), session key derivation, ARQC verification and ARPC generation (method 1 and 2)
+//! along with the (BER) TLV encoding of the ICC data (F55)
+
+use sha1::{Digest, Sha1};
+
+use crate::crypto::keys::set_odd_parity;
+use crate::crypto::mac::{generate_mac, truncate_mac, MacAlgo, PaddingType};
+use crate::crypto::{Cipher, CryptoError};
+
+/// The tags of the data the ARQC is computed on by default - amount authorised, amount other, terminal country
+/// code, TVR, transaction currency code, transaction date, transaction type, unpredictable number, AIP, ATC and
+/// the issuer application data
+pub const DEFAULT_ARQC_TAGS: [&str; 11] = ["9F02", "9F03", "9F1A", "95", "5F2A", "9A", "9C", "9F37", "82", "9F36", "9F10"];
+
+/// This enum represents how the session key of the application cryptogram is derived from the ICC master key
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SessionKeyDerivation {
+    /// No session key, the ICC master key is used as is (like Visa CVN 10)
+    None,
+    /// The EMV common session key derivation with the ATC
+    Common,
+}
+
+/// This enum represents the method the ARPC is generated with
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArpcMethod {
+    /// Method 1 - the ARQC XOR'ed with the (2 byte) authorisation response code and encrypted with the session key
+    Method1 { arc: Vec<u8> },
+    /// Method 2 - the (4 byte) MAC of the ARQC, the (4 byte) card status update and the (up to 8 bytes of)
+    /// proprietary authentication data
+    Method2 { csu: Vec<u8>, prop_auth_data: Vec<u8> },
+}
+
+/// Returns the ICC master key derived from imk (the issuer master key) with the final 16 digits of Y
+fn derive_master_key_from(imk: &[u8], y: &str) -> Result<Vec<u8>, CryptoError> {
+    let y = hex::decode(y).map_err(|e| CryptoError { msg: format!("invalid PAN or PAN sequence number: {}", e) })?;
+    let cipher = Cipher::tdes(imk)?;
+    let mut mk = cipher.encrypt_ecb(&y, imk)?;
+    mk.extend(cipher.encrypt_ecb(&y.iter().map(|b| b ^ 0xff).collect::<Vec<u8>>(), imk)?);
+    Ok(set_odd_parity(&mk))
+}
+
+fn check_pan(pan: &str, psn: &str) -> Result<(), CryptoError> {
+    if pan.is_empty() || pan.len() > 19 || !pan.chars().all(|c| c.is_ascii_digit()) {
+        return Err(CryptoError { msg: format!("invalid PAN: {}", pan) });
+    }
+    if psn.len() != 2 || !psn.chars().all(|c| c.is_ascii_digit()) {
+        return Err(CryptoError { msg: format!("invalid PAN sequence number: {}", psn) });
+    }
+    Ok(())
+}
+
+/// Returns the ICC master key derived with option A - from the rightmost 16 digits of the PAN and the PAN sequence
+/// number (2 digits, "00" if absent)
+pub fn derive_master_key_option_a(imk: &[u8], pan: &str, psn: &str) -> Result<Vec<u8>, CryptoError> {
+    check_pan(pan, psn)?;
+    let x = format!("{:0>16}", format!("{}{}", pan, psn));
+    derive_master_key_from(imk, &x[x.len() - 16..])
+}
+
+/// Returns the ICC master key derived with option B (for PANs of more than 16 digits) - from the first 16 decimal
+/// digits of the SHA-1 hash of the PAN and the PAN sequence number
+pub fn derive_master_key_option_b(imk: &[u8], pan: &str, psn: &str) -> Result<Vec<u8>, CryptoError> {
+    check_pan(pan, psn)?;
+    let mut x = format!("{}{}", pan, psn);
+    if x.len() % 2 == 1 {
+        x.insert(0, '0');
+    }
+
+    let hash = hex::encode_upper(Sha1::digest(&hex::decode(x).unwrap()));
+    let digits = hash.chars().filter(|c| c.is_ascii_digit());
+    let letters = hash.chars().filter(|c| !c.is_ascii_digit()).map(|c| std::char::from_digit(c.to_digit(16).unwrap() - 10, 10).unwrap());
+    derive_master_key_from(imk, &digits.chain(letters).take(16).collect::<String>())
+}
+
+/// Returns the ICC master key - derived with option A for PANs of up to 16 digits and option B otherwise
+pub fn derive_master_key(imk: &[u8], pan: &str, psn: &str) -> Result<Vec<u8>, CryptoError> {
+    if pan.len() > 16 {
+        derive_master_key_option_b(imk, pan, psn)
+    } else {
+        derive_master_key_option_a(imk, pan, psn)
+    }
+}
+
+/// Returns the session key derived from the ICC master key mk with the (2 byte) ATC
+pub fn derive_session_key(mk: &[u8], atc: &[u8], skd: SessionKeyDerivation) -> Result<Vec<u8>, CryptoError> {
+    if atc.len() != 2 {
+        return Err(CryptoError { msg: format!("invalid ATC length: {} (expected 2)", atc.len()) });
+    }
+
+    match skd {
+        SessionKeyDerivation::None => Ok(mk.to_vec()),
+        SessionKeyDerivation::Common => {
+            let cipher = Cipher::tdes(mk)?;
+            let mut sk = cipher.encrypt_ecb(&[atc[0], atc[1], 0xf0, 0, 0, 0, 0, 0], mk)?;
+            sk.extend(cipher.encrypt_ecb(&[atc[0], atc[1], 0x0f, 0, 0, 0, 0, 0], mk)?);
+            Ok(set_odd_parity(&sk))
+        }
+    }
+}
+
+/// Returns the ARQC (a ISO 9797-1 algorithm 3 MAC) on data with the session key sk
+pub fn generate_arqc(sk: &[u8], data: &[u8], padding: &PaddingType) -> Result<Vec<u8>, CryptoError> {
+    Ok(generate_mac(&MacAlgo::RetailMac, padding, data, sk)?)
+}
+
+/// Returns the ARPC for the ARQC with the session key sk
+pub fn generate_arpc(sk: &[u8], arqc: &[u8], method: &ArpcMethod) -> Result<Vec<u8>, CryptoError> {
+    if arqc.len() != 8 {
+        return Err(CryptoError { msg: format!("invalid ARQC length: {} (expected 8)", arqc.len()) });
+    }
+
+    match method {
+        ArpcMethod::Method1 { arc } => {
+            if arc.len() != 2 {
+                return Err(CryptoError { msg: format!("invalid ARC length: {} (expected 2)", arc.len()) });
+            }
+            let data: Vec<u8> = arqc.iter().zip(arc.iter().chain([0u8; 6].iter())).map(|(a, b)| a ^ b).collect();
+            Cipher::tdes(sk)?.encrypt_ecb(&data, sk)
+        }
+        ArpcMethod::Method2 { csu, prop_auth_data } => {
+            if csu.len() != 4 || prop_auth_data.len() > 8 {
+                return Err(CryptoError { msg: format!("invalid CSU length: {} or proprietary authentication data length: {}", csu.len(), prop_auth_data.len()) });
+            }
+            let data = [arqc, csu, prop_auth_data].concat();
+            Ok(truncate_mac(generate_mac(&MacAlgo::RetailMac, &PaddingType::Type2, &data, sk)?, 4)?)
+        }
+    }
+}
+
+/// Returns the issuer authentication data (tag 91) for the ARPC - the ARPC followed by the ARC (method 1) or by the
+/// CSU and the proprietary authentication data (method 2)
+pub fn issuer_authentication_data(arpc: &[u8], method: &ArpcMethod) -> Vec<u8> {
+    match method {
+        ArpcMethod::Method1 { arc } => [arpc, arc].concat(),
+        ArpcMethod::Method2 { csu, prop_auth_data } => [arpc, csu, prop_auth_data].concat()
+    }
+}
+
+/// Parses (BER) TLV data into a list of tags (hex, like "9F26") and values, constructed tags are not parsed further
+pub fn parse_tlv(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>, CryptoError> {
+    let err = |msg: &str| CryptoError { msg: format!("invalid TLV data: {}: {}", msg, hex::encode_upper(data)) };
+    let mut tlvs = vec![];
+    let mut pos = 0;
+
+    while pos < data.len() {
+        // padding between TLVs
+        if data[pos] == 0x00 || data[pos] == 0xff {
+            pos += 1;
+            continue;
+        }
+
+        let start = pos;
+        if data[pos] & 0x1f == 0x1f {
+            pos += 1;
+            while pos < data.len() && data[pos] & 0x80 == 0x80 {
+                pos += 1;
+            }
+        }
+        pos += 1;
+        if pos >= data.len() {
+            return Err(err("truncated tag"));
+        }
+        let tag = hex::encode_upper(&data[start..pos]);
+
+        let len = match data[pos] {
+            n if n < 0x80 => {
+                pos += 1;
+                n as usize
+            }
+            0x81 if pos + 1 < data.len() => {
+                pos += 2;
+                data[pos - 1] as usize
+            }
+            0x82 if pos + 2 < data.len() => {
+                pos += 3;
+                (data[pos - 2] as usize) << 8 | data[pos - 1] as usize
+            }
+            _ => return Err(err(format!("invalid length of tag {}", tag).as_str()))
+        };
+        if pos + len > data.len() {
+            return Err(err(format!("truncated value of tag {}", tag).as_str()));
+        }
+
+        tlvs.push((tag, data[pos..pos + len].to_vec()));
+        pos += len;
+    }
+    Ok(tlvs)
+}
+
+/// Returns the (BER) TLV encoding of tag (hex, like "91") and value
+pub fn encode_tlv(tag: &str, value: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let mut res = hex::decode(tag).map_err(|e| CryptoError { msg: format!("invalid tag {}: {}", tag, e) })?;
+    if res.is_empty() {
+        return Err(CryptoError { msg: "empty tag".to_string() });
+    }
+
+    match value.len() {
+        n if n < 0x80 => res.push(n as u8),
+        n if n <= 0xff => res.extend(&[0x81, n as u8]),
+        n if n <= 0xffff => res.extend(&[0x82, (n >> 8) as u8, n as u8]),
+        n => return Err(CryptoError { msg: format!("value of tag {} is too long: {}", tag, n) })
+    }
+    res.extend_from_slice(value);
+    Ok(res)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::emv::{derive_master_key, derive_master_key_option_a, derive_master_key_option_b, derive_session_key, encode_tlv,
+                             generate_arpc, generate_arqc, issuer_authentication_data, parse_tlv, ArpcMethod, SessionKeyDerivation};
+    use crate::crypto::mac::PaddingType;
+
+    const IMK: &str = "0123456789ABCDEFFEDCBA9876543210";
+    const DATA: &str = "000000001000000000000000082600000000000826251018001234567818000001\
+                        0110A00003220000000000000000000000FF";
+
+    #[test]
+    fn test_master_key() {
+        let imk = hex::decode(IMK).unwrap();
+        assert_eq!(hex::encode_upper(derive_master_key_option_a(&imk, "5413330089010434", "00").unwrap()), "E64AF707D9F85B61E6F1F8805BCDF298");
+        assert_eq!(hex::encode_upper(derive_master_key_option_b(&imk, "6799998900000000019", "01").unwrap()), "4C9DCD370EB004F4D007C1372A346DC4");
+        assert_eq!(hex::encode_upper(derive_master_key(&imk, "679999890000000001", "01").unwrap()), "D3D07C13CDEFE04F10FE044F46DF6D26");
+        assert_eq!(derive_master_key(&imk, "5413330089010434", "00").unwrap(), derive_master_key_option_a(&imk, "5413330089010434", "00").unwrap());
+
+        assert!(derive_master_key(&imk, "5413330089010434", "0").is_err());
+        assert!(derive_master_key(&imk, "54133300890104AB", "00").is_err());
+    }
+
+    #[test]
+    fn test_arqc_arpc() {
+        let mk = derive_master_key(&hex::decode(IMK).unwrap(), "5413330089010434", "00").unwrap();
+        let sk = derive_session_key(&mk, &[0x00, 0x01], SessionKeyDerivation::Common).unwrap();
+        assert_eq!(hex::encode_upper(&sk), "02345BFBEA4C791C0BA7DAE9A4BFC723");
+        assert_eq!(derive_session_key(&mk, &[0x00, 0x01], SessionKeyDerivation::None).unwrap(), mk);
+        assert!(derive_session_key(&mk, &[0x01], SessionKeyDerivation::Common).is_err());
+
+        let data = hex::decode(DATA).unwrap();
+        let arqc = generate_arqc(&sk, &data, &PaddingType::Type2).unwrap();
+        assert_eq!(hex::encode_upper(&arqc), "D9F38036608A59E6");
+        assert_eq!(hex::encode_upper(generate_arqc(&mk, &data, &PaddingType::Type1).unwrap()), "78BC4A52941D2C23");
+
+        let method = ArpcMethod::Method1 { arc: b"00".to_vec() };
+        let arpc = generate_arpc(&sk, &arqc, &method).unwrap();
+        assert_eq!(hex::encode_upper(&arpc), "74C99AEED052B523");
+        assert_eq!(hex::encode_upper(issuer_authentication_data(&arpc, &method)), "74C99AEED052B5233030");
+
+        let method = ArpcMethod::Method2 { csu: vec![0x00, 0x82, 0x00, 0x00], prop_auth_data: vec![] };
+        assert_eq!(hex::encode_upper(generate_arpc(&sk, &arqc, &method).unwrap()), "15E8E32D");
+        let method = ArpcMethod::Method2 { csu: vec![0x00, 0x82, 0x00, 0x00], prop_auth_data: vec![0x11, 0x22] };
+        let arpc = generate_arpc(&sk, &arqc, &method).unwrap();
+        assert_eq!(hex::encode_upper(&arpc), "C4F6537F");
+        assert_eq!(hex::encode_upper(issuer_authentication_data(&arpc, &method)), "C4F6537F008200001122");
+
+        assert!(generate_arpc(&sk, &arqc[0..4], &method).is_err());
+        assert!(generate_arpc(&sk, &arqc, &ArpcMethod::Method1 { arc: b"000".to_vec() }).is_err());
+    }
+
+    #[test]
+    fn test_tlv() {
+        let data = hex::decode("9F2608D9F38036608A59E69F360200019F10120110A00003220000000000000000000000FF0000950500000000008A023030").unwrap();
+        let tlvs = parse_tlv(&data).unwrap();
+        assert_eq!(tlvs.len(), 5);
+        assert_eq!(tlvs[0], ("9F26".to_string(), hex::decode("D9F38036608A59E6").unwrap()));
+        assert_eq!(tlvs[1], ("9F36".to_string(), vec![0x00, 0x01]));
+        assert_eq!(tlvs[2].1.len(), 18);
+        assert_eq!(tlvs[4], ("8A".to_string(), b"00".to_vec()));
+
+        assert_eq!(hex::encode_upper(encode_tlv("91", &[0x01, 0x02]).unwrap()), "91020102");
+        let long = encode_tlv("9F10", &[0x01; 200]).unwrap();
+        assert_eq!(hex::encode_upper(&long[0..4]), "9F1081C8");
+        assert_eq!(parse_tlv(&long).unwrap()[0].1, vec![0x01; 200]);
+
+        assert!(parse_tlv(&hex::decode("9F2608D9F3").unwrap()).is_err());
+        assert!(parse_tlv(&hex::decode("9F").unwrap()).is_err());
+        assert!(encode_tlv("9", &[]).is_err());
+    }
+}
@@ -0,0 +1,305 @@
+//! This module contains a client for a HSM with a Thales payShield like command set (see `crypto::hsm`), which is
+//! a SecurityModule whose keys are encrypted under the LMK of the HSM
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::crypto::dukpt::KeyUsage;
+use crate::crypto::hsm::{account_number, pin_format_code, response_code, ERR_NONE, ERR_VERIFICATION_FAILURE};
+use crate::crypto::mac::{MacAlgo, PaddingType};
+use crate::crypto::pin::PinFormat;
+use crate::crypto::security_module::SecurityModule;
+use crate::crypto::{Cipher, CryptoError};
+use crate::iso8583::mli::{MLI, MLI2E};
+
+/// This struct represents the response to a HSM command
+#[derive(Debug)]
+pub struct HsmResponse {
+    /// The error code (like "00")
+    pub error_code: String,
+    /// The data that follows the error code
+    pub data: Vec<u8>,
+}
+
+/// This struct represents a HSM client with a single (lazily opened) connection to the HSM
+pub struct HsmClient {
+    server_addr: String,
+    timeout: Duration,
+    stream: Mutex<Option<TcpStream>>,
+    header: AtomicU32,
+}
+
+impl HsmClient {
+    /// Creates a new HsmClient for the HSM at server_addr, with a response timeout of 10 seconds
+    pub fn new(server_addr: &str) -> HsmClient {
+        HsmClient {
+            server_addr: server_addr.to_string(),
+            timeout: Duration::from_secs(10),
+            stream: Mutex::new(None),
+            header: AtomicU32::new(0),
+        }
+    }
+
+    /// Use the client with a builder pattern - sets the response timeout
+    pub fn with_timeout(&mut self, timeout: Duration) -> &mut HsmClient {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Executes the command cmd (like "CA") with data (the fields of the command) and returns the response, the
+    /// connection is closed on errors and reopened with the next command
+    pub fn execute(&self, cmd: &str, data: &[u8]) -> Result<HsmResponse, CryptoError> {
+        if cmd.len() != 2 {
+            return Err(CryptoError { msg: format!("invalid HSM command: {}", cmd) });
+        }
+
+        let header = format!("{:04}", self.header.fetch_add(1, Ordering::Relaxed) % 10000);
+        let mut req = format!("{}{}", header, cmd).into_bytes();
+        req.extend_from_slice(data);
+
+        let mut stream = self.stream.lock().unwrap();
+        let res = self.send_recv(&mut stream, &req);
+        if res.is_err() {
+            *stream = None;
+        }
+        let resp = res?;
+
+        let mut expected = header.into_bytes();
+        expected.extend(response_code(cmd.as_bytes()));
+        if resp.len() < 8 || !resp.starts_with(&expected) {
+            *stream = None;
+            return Err(CryptoError { msg: format!("unexpected HSM response: {} (expected {})", String::from_utf8_lossy(&resp), String::from_utf8_lossy(&expected)) });
+        }
+
+        Ok(HsmResponse { error_code: String::from_utf8_lossy(&resp[6..8]).to_string(), data: resp[8..].to_vec() })
+    }
+
+    fn send_recv(&self, stream: &mut Option<TcpStream>, req: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let io_err = |e: std::io::Error| CryptoError { msg: format!("HSM {}: {}", self.server_addr, e) };
+
+        if stream.is_none() {
+            let s = TcpStream::connect(&self.server_addr).map_err(io_err)?;
+            s.set_read_timeout(Some(self.timeout)).map_err(io_err)?;
+            *stream = Some(s);
+        }
+        let s = stream.as_mut().unwrap();
+
+        let mli = MLI2E {};
+        let mut buf = mli.create(&req.len()).map_err(|e| CryptoError { msg: e.msg })?;
+        buf.extend_from_slice(req);
+        s.write_all(&buf).map_err(io_err)?;
+
+        let len = mli.parse(s).map_err(|e| CryptoError { msg: format!("HSM {}: {}", self.server_addr, e.msg) })?;
+        let mut resp = vec![0; len as usize];
+        s.read_exact(&mut resp).map_err(io_err)?;
+        Ok(resp)
+    }
+
+    /// Executes the command and returns the response data, the error code should be 00
+    fn execute_ok(&self, cmd: &str, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let resp = self.execute(cmd, data)?;
+        match resp.error_code.as_str() {
+            ERR_NONE => Ok(resp.data),
+            code => Err(CryptoError { msg: format!("HSM command {} failed with error code {}", cmd, code) })
+        }
+    }
+
+    /// Executes the (verification) command and returns true for error code 00 and false for 01
+    fn execute_verify(&self, cmd: &str, data: &[u8]) -> Result<bool, CryptoError> {
+        let resp = self.execute(cmd, data)?;
+        match resp.error_code.as_str() {
+            ERR_NONE => Ok(true),
+            ERR_VERIFICATION_FAILURE => Ok(false),
+            code => Err(CryptoError { msg: format!("HSM command {} failed with error code {}", cmd, code) })
+        }
+    }
+
+    /// Returns the PIN encrypted under the LMK (BA)
+    fn encrypt_pin(&self, pin: &str, account: &str) -> Result<String, CryptoError> {
+        if pin.is_empty() || pin.len() > 12 {
+            return Err(CryptoError { msg: format!("invalid PIN length: {}", pin.len()) });
+        }
+        let data = self.execute_ok("BA", format!("{:F<12}{}", pin, account).as_bytes())?;
+        Ok(String::from_utf8_lossy(&data).to_string())
+    }
+
+    /// Returns the M6/M8 fields up to (and including) the message
+    fn mac_fields(algo: &MacAlgo, padding: &PaddingType, data: &[u8], key: &str, mac_size: &str) -> Result<Vec<u8>, CryptoError> {
+        let algo = match algo {
+            MacAlgo::CbcMac => "1",
+            MacAlgo::RetailMac => "3",
+            MacAlgo::TdesCmac => "5",
+            _ => return Err(CryptoError { msg: "AES-CMAC and HMAC-SHA256 are not supported by the HSM".to_string() })
+        };
+        let padding = match padding {
+            PaddingType::Type1 => "1",
+            PaddingType::Type2 => "2",
+            PaddingType::Type3 => "3",
+        };
+        if data.len() > 0xffff {
+            return Err(CryptoError { msg: format!("data too long to MAC: {}", data.len()) });
+        }
+
+        let mut fields = format!("00{}{}{}008{}{:04X}", mac_size, algo, padding, key, data.len()).into_bytes();
+        fields.extend_from_slice(data);
+        Ok(fields)
+    }
+}
+
+fn pin_block_hex(data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    hex::decode(data).map_err(|e| CryptoError { msg: format!("invalid PIN block in HSM response: {}", e) })
+}
+
+impl SecurityModule for HsmClient {
+    fn generate_pin_block(&self, fmt: &PinFormat, pin: &str, pan: &str, key: &str) -> Result<Vec<u8>, CryptoError> {
+        let account = account_number(pan);
+        let lmk_pin = self.encrypt_pin(pin, &account)?;
+        let data = self.execute_ok("JG", format!("{}{}{}{}", key, pin_format_code(fmt)?, account, lmk_pin).as_bytes())?;
+        pin_block_hex(&data)
+    }
+
+    fn verify_pin(&self, fmt: &PinFormat, expected_pin: &str, pin_block: &[u8], pan: &str, key: &str) -> Result<bool, CryptoError> {
+        let account = account_number(pan);
+        let lmk_pin = self.encrypt_pin(expected_pin, &account)?;
+        self.execute_verify("BE", format!("{}{}{}{}{}", key, hex::encode_upper(pin_block), pin_format_code(fmt)?, account, lmk_pin).as_bytes())
+    }
+
+    fn translate_pin_block(&self, in_fmt: &PinFormat, in_key: &str, out_fmt: &PinFormat, out_key: &str, pin_block: &[u8], pan: &str) -> Result<Vec<u8>, CryptoError> {
+        let data = self.execute_ok("CC", format!("{}{}12{}{}{}{}", in_key, out_key, hex::encode_upper(pin_block), pin_format_code(in_fmt)?,
+                                                 pin_format_code(out_fmt)?, account_number(pan)).as_bytes())?;
+        if data.len() != 20 {
+            return Err(CryptoError { msg: format!("unexpected CD response: {}", String::from_utf8_lossy(&data)) });
+        }
+        pin_block_hex(&data[2..18])
+    }
+
+    fn generate_mac(&self, algo: &MacAlgo, padding: &PaddingType, data: &[u8], key: &str) -> Result<Vec<u8>, CryptoError> {
+        let mac = self.execute_ok("M6", &HsmClient::mac_fields(algo, padding, data, key, "1")?)?;
+        hex::decode(&mac).map_err(|e| CryptoError { msg: format!("invalid MAC in HSM response: {}", e) })
+    }
+
+    fn verify_mac(&self, algo: &MacAlgo, padding: &PaddingType, data: &[u8], key: &str, mac: &[u8]) -> Result<bool, CryptoError> {
+        let mac_size = match mac.len() {
+            4 => "0",
+            8 => "1",
+            n => return Err(CryptoError { msg: format!("MAC length {} is not supported by the HSM (expected 4 or 8)", n) })
+        };
+        let mut fields = HsmClient::mac_fields(algo, padding, data, key, mac_size)?;
+        fields.extend(hex::encode_upper(mac).into_bytes());
+        self.execute_verify("M8", &fields)
+    }
+
+    fn derive_key(&self, _bdk: &str, _ksn: &[u8], _usage: KeyUsage, _key_type: Option<Cipher>) -> Result<String, CryptoError> {
+        Err(CryptoError { msg: "DUKPT key derivation is not supported by the HSM client".to_string() })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::crypto::hsm::client::HsmClient;
+    use crate::crypto::hsm::simulator::HsmSimulator;
+    use crate::crypto::hsm::{account_number, ERR_INVALID_PIN_BLOCK_FORMAT, ERR_UNSUPPORTED_COMMAND};
+    use crate::crypto::mac::{generate_mac, MacAlgo, PaddingType};
+    use crate::crypto::pin::{generate_pin_block, verify_pin, PinFormat};
+    use crate::crypto::security_module::SecurityModule;
+    use crate::iso8583::config::Config;
+    use crate::iso8583::iso_spec::{builtin_spec, new_msg};
+
+    const LMK: &str = "0123456789abcdeffedcba9876543210";
+    const ZPK: &str = "e0f4543f3e2a2c5ffc7e5e5a222e3e4d";
+    const ZPK2: &str = "00112233445566778899aabbccddeeff";
+    const TAK: &str = "0123456789abcdeffedcba9876543210";
+
+    /// Starts a simulator and returns a client for it and the keys under the LMK
+    fn start() -> (HsmClient, String, String, String) {
+        let simulator = HsmSimulator::new(&hex::decode(LMK).unwrap()).unwrap();
+        let keys: Vec<String> = [ZPK, ZPK2, TAK].iter().map(|k| simulator.key_under_lmk(&hex::decode(k).unwrap()).unwrap()).collect();
+        let addr = simulator.start("127.0.0.1:0").unwrap();
+        (HsmClient::new(&addr.to_string()), keys[0].clone(), keys[1].clone(), keys[2].clone())
+    }
+
+    #[test]
+    fn test_pin_commands() {
+        let (client, zpk, zpk2, _) = start();
+        let pan = "4111111111111111";
+        assert!(zpk.starts_with('U') && zpk.len() == 33);
+
+        let pin_block = client.generate_pin_block(&PinFormat::ISO0, "1234", pan, &zpk).unwrap();
+        assert_eq!(pin_block, generate_pin_block(&PinFormat::ISO0, "1234", pan, &hex::decode(ZPK).unwrap()).unwrap());
+        assert!(client.verify_pin(&PinFormat::ISO0, "1234", &pin_block, pan, &zpk).unwrap());
+        assert!(!client.verify_pin(&PinFormat::ISO0, "12345", &pin_block, pan, &zpk).unwrap());
+
+        let res = client.translate_pin_block(&PinFormat::ISO0, &zpk, &PinFormat::ISO3, &zpk2, &pin_block, pan).unwrap();
+        assert!(verify_pin(&PinFormat::ISO3, "1234", &res, pan, &hex::decode(ZPK2).unwrap()).unwrap());
+        assert!(client.translate_pin_block(&PinFormat::ISO0, &zpk, &PinFormat::ISO4, &zpk2, &pin_block, pan).is_err());
+
+        // a PIN block from a terminal (under a TPK)
+        let resp = client.execute("CA", format!("{}{}12{}0101{}", zpk, zpk2, hex::encode_upper(&pin_block), account_number(pan)).as_bytes()).unwrap();
+        assert_eq!(resp.error_code, "00");
+        assert_eq!(&resp.data[0..2], b"04");
+
+        let resp = client.execute("CA", format!("{}{}12{}0102{}", zpk, zpk2, hex::encode_upper(&pin_block), account_number(pan)).as_bytes()).unwrap();
+        assert_eq!(resp.error_code, ERR_INVALID_PIN_BLOCK_FORMAT);
+        assert_eq!(client.execute("ZX", b"").unwrap().error_code, ERR_UNSUPPORTED_COMMAND);
+    }
+
+    #[test]
+    fn test_mac_commands() {
+        let (client, _, _, tak) = start();
+        let data = b"Now is the time for all ";
+
+        let mac = client.generate_mac(&MacAlgo::RetailMac, &PaddingType::Type1, data, &tak).unwrap();
+        assert_eq!(hex::encode_upper(&mac), "A1C72E74EA3FA9B6");
+        let mac = client.generate_mac(&MacAlgo::TdesCmac, &PaddingType::Type1, data, &tak).unwrap();
+        assert_eq!(mac, generate_mac(&MacAlgo::TdesCmac, &PaddingType::Type1, data, &hex::decode(TAK).unwrap()).ok().unwrap());
+
+        assert!(client.verify_mac(&MacAlgo::TdesCmac, &PaddingType::Type1, data, &tak, &mac).unwrap());
+        assert!(client.verify_mac(&MacAlgo::TdesCmac, &PaddingType::Type1, data, &tak, &mac[0..4]).unwrap());
+        assert!(!client.verify_mac(&MacAlgo::TdesCmac, &PaddingType::Type1, b"Now is the time for it", &tak, &mac).unwrap());
+        assert!(client.verify_mac(&MacAlgo::TdesCmac, &PaddingType::Type1, data, &tak, &mac[0..6]).is_err());
+        assert!(client.generate_mac(&MacAlgo::AesCmac, &PaddingType::Type1, data, &tak).is_err());
+
+        // a key that is not under the LMK
+        assert!(client.generate_mac(&MacAlgo::RetailMac, &PaddingType::Type1, data, "UXYZ").is_err());
+    }
+
+    #[test]
+    fn test_iso_msg_with_hsm() {
+        let (client, zpk, zpk2, tak) = start();
+        let client: Arc<dyn SecurityModule> = Arc::new(client);
+        let spec = builtin_spec("ISO8583_1987_ASCII").unwrap();
+        let pan = "4111111111111111";
+
+        let mut acq_cfg = Config::new();
+        acq_cfg.with_pin(PinFormat::ISO0, zpk)
+            .with_mac(MacAlgo::RetailMac, PaddingType::Type1, tak)
+            .with_security_module(client.clone());
+        let mut iss_cfg = Config::new();
+        iss_cfg.with_pin(PinFormat::ISO3, zpk2).with_security_module(client.clone());
+
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("0200").unwrap());
+        iso_msg.set("message_type", "0200").unwrap();
+        iso_msg.set_on(4, "000000001000").unwrap();
+        iso_msg.set_pin("1234", pan, &acq_cfg).unwrap();
+        iso_msg.translate_pin(pan, &acq_cfg, &iss_cfg).unwrap();
+        assert!(iso_msg.verify_pin("1234", pan, &iss_cfg).unwrap());
+        iso_msg.set_mac(&acq_cfg).unwrap();
+
+        // the same PIN block and MAC with the clear keys
+        let mut cfg = Config::new();
+        cfg.with_pin(PinFormat::ISO3, ZPK2.to_string())
+            .with_mac(MacAlgo::RetailMac, PaddingType::Type1, TAK.to_string());
+        let parsed = spec.parse(&mut iso_msg.assemble().unwrap()).unwrap();
+        assert!(parsed.verify_pin("1234", pan, &cfg).unwrap());
+        assert!(parsed.verify_mac(&cfg).unwrap());
+        assert!(parsed.verify_mac(&acq_cfg).unwrap());
+        iso_msg.set_on(4, "000000002000").unwrap();
+        assert!(!iso_msg.verify_mac(&acq_cfg).unwrap());
+    }
+}
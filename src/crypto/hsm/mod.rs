@@ -0,0 +1,76 @@
+//! This module contains a client (a SecurityModule) for a HSM with a Thales payShield like host command set and a
+//! simulator of that HSM built on the crypto module of this crate.
+//!
+//! Commands and responses are exchanged over TCP with a 2 byte (binary) length prefix and start with a 4 character
+//! header (echoed in the response) followed by the 2 character command code. The response code is the command code
+//! with the 2nd character incremented (like CA -> CB) and is followed by a 2 digit error code and the response data.
+//! Keys are hex strings encrypted under the LMK (local master key) of the HSM - prefixed with "U" for double and "T"
+//! for triple length keys.
+//!
+//! The supported commands (a subset of the fields of payShield) are -
+//! * BA - encrypt a clear PIN under the LMK
+//! * JG - translate a PIN from LMK to ZPK encryption (i.e generate a PIN block)
+//! * BE - verify a PIN block under a ZPK against a PIN encrypted under the LMK
+//! * CA - translate a PIN block from a TPK to a ZPK
+//! * CC - translate a PIN block from a ZPK to another ZPK
+//! * M6 - generate a MAC
+//! * M8 - verify a MAC
+//!
+//! PIN blocks are 16H with the format codes 01 (ISO 0), 05 (ISO 1), 34 (ISO 2) and 47 (ISO 3), and the account
+//! number is the rightmost 12 digits of the PAN excluding the check digit.
+
+pub mod client;
+pub mod simulator;
+
+use crate::crypto::pin::PinFormat;
+use crate::crypto::CryptoError;
+
+/// No error
+pub const ERR_NONE: &str = "00";
+/// The PIN or MAC verification failed
+pub const ERR_VERIFICATION_FAILURE: &str = "01";
+/// A key (under the LMK) is invalid
+pub const ERR_INVALID_KEY: &str = "10";
+/// The input data of the command is invalid
+pub const ERR_INVALID_INPUT: &str = "15";
+/// The PIN block does not contain valid values
+pub const ERR_INVALID_PIN_BLOCK: &str = "20";
+/// The PIN block format code is invalid
+pub const ERR_INVALID_PIN_BLOCK_FORMAT: &str = "23";
+/// The command is not supported
+pub const ERR_UNSUPPORTED_COMMAND: &str = "68";
+
+/// Returns the response code for the command code cmd (like CB for CA)
+pub(crate) fn response_code(cmd: &[u8]) -> Vec<u8> {
+    vec![cmd[0], cmd[1].wrapping_add(1)]
+}
+
+/// Returns the format code of the PIN block format
+pub(crate) fn pin_format_code(fmt: &PinFormat) -> Result<&'static str, CryptoError> {
+    match fmt {
+        PinFormat::ISO0 => Ok("01"),
+        PinFormat::ISO1 => Ok("05"),
+        PinFormat::ISO2 => Ok("34"),
+        PinFormat::ISO3 => Ok("47"),
+        PinFormat::ISO4 => Err(CryptoError { msg: "ISO 4 PIN blocks are not supported by the HSM".to_string() })
+    }
+}
+
+/// Returns the PIN block format of the format code
+pub(crate) fn pin_format(code: &str) -> Option<PinFormat> {
+    match code {
+        "01" => Some(PinFormat::ISO0),
+        "05" => Some(PinFormat::ISO1),
+        "34" => Some(PinFormat::ISO2),
+        "47" => Some(PinFormat::ISO3),
+        _ => None
+    }
+}
+
+/// Returns the account number (the rightmost 12 digits of pan excluding the check digit) or zeros if pan is too short
+pub(crate) fn account_number(pan: &str) -> String {
+    if pan.len() < 13 {
+        return "0".repeat(12);
+    }
+    pan[pan.len() - 13..pan.len() - 1].to_string()
+}
@@ -0,0 +1,284 @@
+//! This module contains a simulator of a HSM with a Thales payShield like command set (see `crypto::hsm`), to test
+//! the HSM client and the flows that use it without a real HSM
+
+use std::io::{BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+
+use crate::crypto::hsm::{pin_format, response_code, ERR_INVALID_INPUT, ERR_INVALID_KEY, ERR_INVALID_PIN_BLOCK,
+                         ERR_INVALID_PIN_BLOCK_FORMAT, ERR_NONE, ERR_UNSUPPORTED_COMMAND, ERR_VERIFICATION_FAILURE};
+use crate::crypto::mac::{generate_mac, truncate_mac, MacAlgo, PaddingType};
+use crate::crypto::pin::{decode_pin_block, generate_pin_block, PinFormat};
+use crate::crypto::{ct_eq, Cipher, CryptoError};
+use crate::iso8583::mli::{MLI, MLI2E};
+
+/// This struct represents a HSM simulator with a (clear) double or triple length TDES LMK
+pub struct HsmSimulator {
+    lmk: Vec<u8>,
+}
+
+/// The fields of a command, read in order - errors are the HSM error codes
+struct Fields<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], &'static str> {
+        if self.pos + n > self.data.len() {
+            return Err(ERR_INVALID_INPUT);
+        }
+        self.pos += n;
+        Ok(&self.data[self.pos - n..self.pos])
+    }
+
+    fn take_str(&mut self, n: usize) -> Result<&'a str, &'static str> {
+        std::str::from_utf8(self.take(n)?).map_err(|_| ERR_INVALID_INPUT)
+    }
+
+    fn take_hex(&mut self, n: usize) -> Result<Vec<u8>, &'static str> {
+        hex::decode(self.take(n)?).map_err(|_| ERR_INVALID_INPUT)
+    }
+
+    fn take_pin_format(&mut self) -> Result<PinFormat, &'static str> {
+        pin_format(self.take_str(2)?).ok_or(ERR_INVALID_PIN_BLOCK_FORMAT)
+    }
+
+    /// Returns the PAN for the (12 digit) account number, which the PIN block functions expect with a check digit
+    fn take_pan(&mut self) -> Result<String, &'static str> {
+        let account = self.take_str(12)?;
+        if !account.chars().all(|c| c.is_ascii_digit()) {
+            return Err(ERR_INVALID_INPUT);
+        }
+        Ok(format!("{}0", account))
+    }
+}
+
+impl HsmSimulator {
+    /// Creates a new HsmSimulator with lmk (16 or 24 bytes)
+    pub fn new(lmk: &[u8]) -> Result<HsmSimulator, CryptoError> {
+        match Cipher::tdes(lmk)? {
+            Cipher::Des => Err(CryptoError { msg: "the LMK should be a double or triple length key".to_string() }),
+            _ => Ok(HsmSimulator { lmk: lmk.to_vec() })
+        }
+    }
+
+    /// Returns key encrypted under the LMK (as used in commands), prefixed with "U" or "T" for double and triple
+    /// length keys
+    pub fn key_under_lmk(&self, key: &[u8]) -> Result<String, CryptoError> {
+        let prefix = match Cipher::tdes(key)? {
+            Cipher::Des => "",
+            Cipher::TdesEde2 => "U",
+            _ => "T"
+        };
+        Ok(format!("{}{}", prefix, hex::encode_upper(Cipher::tdes(&self.lmk)?.encrypt_ecb(key, &self.lmk)?)))
+    }
+
+    /// Starts the simulator on host_port in a separate thread and returns the address it listens on
+    pub fn start(self, host_port: &str) -> Result<SocketAddr, CryptoError> {
+        let listener = TcpListener::bind(host_port).map_err(|e| CryptoError { msg: format!("failed to bind {}: {}", host_port, e) })?;
+        let addr = listener.local_addr().map_err(|e| CryptoError { msg: e.to_string() })?;
+        let simulator = Arc::new(self);
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let simulator = simulator.clone();
+                        std::thread::spawn(move || simulator.serve(stream));
+                    }
+                    Err(e) => error!("HSM simulator failed to accept connection - {}", e)
+                }
+            }
+        });
+        Ok(addr)
+    }
+
+    /// Handles the commands of a connection until it is closed
+    fn serve(&self, stream: TcpStream) {
+        let mli = MLI2E {};
+        let mut reader = BufReader::new(&stream);
+        let mut writer = &stream;
+
+        loop {
+            let len = match mli.parse(&mut reader) {
+                Ok(n) => n,
+                Err(e) => {
+                    debug!("HSM simulator connection closed - {}", e.msg);
+                    break;
+                }
+            };
+
+            let mut req = vec![0; len as usize];
+            if let Err(e) = reader.read_exact(&mut req) {
+                error!("HSM simulator failed to read command - {}", e);
+                break;
+            }
+
+            let resp = self.execute(&req);
+            let mut data = mli.create(&resp.len()).unwrap();
+            data.extend(resp);
+            if let Err(e) = writer.write_all(&data) {
+                error!("HSM simulator failed to write response - {}", e);
+                break;
+            }
+        }
+    }
+
+    /// Executes the command in req (header, command code and fields) and returns the response
+    pub fn execute(&self, req: &[u8]) -> Vec<u8> {
+        // without a header and a command code there is nothing to respond to
+        if req.len() < 6 {
+            return b"0000ZZ15".to_vec();
+        }
+
+        let (header, cmd) = (&req[0..4], &req[4..6]);
+        let mut fields = Fields { data: &req[6..], pos: 0 };
+        let res = match cmd {
+            b"BA" => self.encrypt_pin(&mut fields),
+            b"JG" => self.generate_pin_block(&mut fields),
+            b"BE" => self.verify_pin(&mut fields),
+            b"CA" | b"CC" => self.translate_pin_block(&mut fields),
+            b"M6" => self.generate_mac(&mut fields, false),
+            b"M8" => self.generate_mac(&mut fields, true),
+            _ => Err(ERR_UNSUPPORTED_COMMAND)
+        };
+
+        debug!("HSM simulator command: {}, result: {:?}", String::from_utf8_lossy(cmd), res);
+        let mut resp = header.to_vec();
+        resp.extend(response_code(cmd));
+        match res {
+            Ok(data) => {
+                resp.extend(ERR_NONE.as_bytes());
+                resp.extend(data.as_bytes());
+            }
+            Err(code) => resp.extend(code.as_bytes())
+        }
+        resp
+    }
+
+    /// Returns the key (under the LMK) of the next field
+    fn key(&self, fields: &mut Fields) -> Result<Vec<u8>, &'static str> {
+        let len = match fields.data.get(fields.pos) {
+            Some(b'U') => 32,
+            Some(b'T') => 48,
+            _ => 16
+        };
+        if len != 16 {
+            fields.pos += 1;
+        }
+
+        let key = fields.take_hex(len)?;
+        Cipher::tdes(&self.lmk).and_then(|c| c.decrypt_ecb(&key, &self.lmk)).map_err(|_| ERR_INVALID_KEY)
+    }
+
+    /// Returns the clear PIN of a PIN encrypted under the LMK (a ISO 0 PIN block)
+    fn lmk_pin(&self, fields: &mut Fields, pan: &str) -> Result<String, &'static str> {
+        let pin = fields.take_hex(16)?;
+        decode_pin_block(&PinFormat::ISO0, &pin, pan, &self.lmk).map_err(|_| ERR_INVALID_PIN_BLOCK)
+    }
+
+    /// BA - PIN (12A, padded with F), account number (12N) => PIN under the LMK (16H)
+    fn encrypt_pin(&self, fields: &mut Fields) -> Result<String, &'static str> {
+        let pin = fields.take_str(12)?.trim_end_matches('F');
+        let pan = fields.take_pan()?;
+        match generate_pin_block(&PinFormat::ISO0, pin, &pan, &self.lmk) {
+            Ok(pin) => Ok(hex::encode_upper(pin)),
+            Err(_) => Err(ERR_INVALID_INPUT)
+        }
+    }
+
+    /// JG - ZPK, PIN block format code (2N), account number (12N), PIN under the LMK (16H) => PIN block (16H)
+    fn generate_pin_block(&self, fields: &mut Fields) -> Result<String, &'static str> {
+        let zpk = self.key(fields)?;
+        let fmt = fields.take_pin_format()?;
+        let pan = fields.take_pan()?;
+        let pin = self.lmk_pin(fields, &pan)?;
+        match generate_pin_block(&fmt, &pin, &pan, &zpk) {
+            Ok(pin_block) => Ok(hex::encode_upper(pin_block)),
+            Err(_) => Err(ERR_INVALID_INPUT)
+        }
+    }
+
+    /// BE - ZPK, PIN block (16H), PIN block format code (2N), account number (12N), PIN under the LMK (16H) =>
+    /// error code 00 or 01
+    fn verify_pin(&self, fields: &mut Fields) -> Result<String, &'static str> {
+        let zpk = self.key(fields)?;
+        let pin_block = fields.take_hex(16)?;
+        let fmt = fields.take_pin_format()?;
+        let pan = fields.take_pan()?;
+        let pin = self.lmk_pin(fields, &pan)?;
+        match decode_pin_block(&fmt, &pin_block, &pan, &zpk) {
+            Ok(actual) if actual == pin => Ok(String::new()),
+            Ok(_) => Err(ERR_VERIFICATION_FAILURE),
+            Err(_) => Err(ERR_INVALID_PIN_BLOCK)
+        }
+    }
+
+    /// CA/CC - source TPK/ZPK, destination ZPK, maximum PIN length (2N), source PIN block (16H), source and destination
+    /// PIN block format codes (2N each), account number (12N) => PIN length (2N), destination PIN block (16H),
+    /// destination PIN block format code (2N)
+    fn translate_pin_block(&self, fields: &mut Fields) -> Result<String, &'static str> {
+        let in_key = self.key(fields)?;
+        let out_key = self.key(fields)?;
+        let max_len: usize = fields.take_str(2)?.parse().map_err(|_| ERR_INVALID_INPUT)?;
+        let pin_block = fields.take_hex(16)?;
+        let in_fmt = fields.take_pin_format()?;
+        let out_code = fields.take_str(2)?;
+        let out_fmt = pin_format(out_code).ok_or(ERR_INVALID_PIN_BLOCK_FORMAT)?;
+        let pan = fields.take_pan()?;
+
+        let pin = decode_pin_block(&in_fmt, &pin_block, &pan, &in_key).map_err(|_| ERR_INVALID_PIN_BLOCK)?;
+        if pin.len() > max_len {
+            return Err(ERR_INVALID_PIN_BLOCK);
+        }
+        match generate_pin_block(&out_fmt, &pin, &pan, &out_key) {
+            Ok(pin_block) => Ok(format!("{:02}{}{}", pin.len(), hex::encode_upper(pin_block), out_code)),
+            Err(_) => Err(ERR_INVALID_INPUT)
+        }
+    }
+
+    /// M6/M8 - mode flag (1N, 0), input format flag (1N, 0 - binary), MAC size (1N, 0 - 8H, 1 - 16H), MAC algorithm
+    /// (1N, ISO 9797-1 algorithm 1, 3 or 5), padding method (1N, 1 to 3), key type (3H), key, message length (4H),
+    /// message and the MAC (for M8) => the MAC (for M6) or error code 00 or 01 (for M8)
+    fn generate_mac(&self, fields: &mut Fields, verify: bool) -> Result<String, &'static str> {
+        if fields.take_str(2)? != "00" {
+            return Err(ERR_INVALID_INPUT);
+        }
+        let mac_len = match fields.take_str(1)? {
+            "0" => 4,
+            "1" => 8,
+            _ => return Err(ERR_INVALID_INPUT)
+        };
+        let algo = match fields.take_str(1)? {
+            "1" => MacAlgo::CbcMac,
+            "3" => MacAlgo::RetailMac,
+            "5" => MacAlgo::TdesCmac,
+            _ => return Err(ERR_INVALID_INPUT)
+        };
+        let padding = match fields.take_str(1)? {
+            "1" => PaddingType::Type1,
+            "2" => PaddingType::Type2,
+            "3" => PaddingType::Type3,
+            _ => return Err(ERR_INVALID_INPUT)
+        };
+        fields.take(3)?;
+        let key = self.key(fields)?;
+        let len = usize::from_str_radix(fields.take_str(4)?, 16).map_err(|_| ERR_INVALID_INPUT)?;
+        let data = fields.take(len)?;
+
+        let mac = match generate_mac(&algo, &padding, data, &key).and_then(|v| truncate_mac(v, mac_len)) {
+            Ok(mac) => mac,
+            Err(_) => return Err(ERR_INVALID_KEY)
+        };
+        if !verify {
+            return Ok(hex::encode_upper(mac));
+        }
+
+        if ct_eq(&fields.take_hex(mac_len * 2)?, &mac) {
+            Ok(String::new())
+        } else {
+            Err(ERR_VERIFICATION_FAILURE)
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! This module provides key check values (KCV) and the handling of (clear) key components - combining them and
+//! enforcing odd parity for DES/TDES keys
+
+use crate::crypto::mac::{generate_mac, MacAlgo, PaddingType};
+use crate::crypto::{Cipher, CryptoError};
+
+/// Returns the key check value of key - the leftmost 3 bytes of a block of zeros encrypted with key for DES/TDES and
+/// the leftmost 5 bytes of the CMAC of a block of zeros for AES (ANSI X9.24-1:2017)
+pub fn kcv(cipher: Cipher, key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if key.len() != cipher.key_len() {
+        return Err(CryptoError { msg: format!("invalid {:?} key length: {}", cipher, key.len()) });
+    }
+
+    match cipher {
+        Cipher::Aes128 | Cipher::Aes192 | Cipher::Aes256 => {
+            let mac = generate_mac(&MacAlgo::AesCmac, &PaddingType::Type1, &[0u8; 16], key).map_err(|e| CryptoError { msg: e.msg })?;
+            Ok(mac[0..5].to_vec())
+        }
+        _ => Ok(cipher.encrypt_ecb(&[0u8; 8], key)?[0..3].to_vec())
+    }
+}
+
+/// Verifies key against the expected KCV (which can be shorter than the computed KCV, but at least 2 bytes)
+pub fn verify_kcv(cipher: Cipher, key: &[u8], expected_kcv: &[u8]) -> Result<(), CryptoError> {
+    let res = kcv(cipher, key)?;
+    if expected_kcv.len() < 2 || expected_kcv.len() > res.len() {
+        return Err(CryptoError { msg: format!("invalid KCV length: {}", expected_kcv.len()) });
+    }
+
+    if res[0..expected_kcv.len()] == *expected_kcv {
+        Ok(())
+    } else {
+        Err(CryptoError { msg: format!("KCV mismatch - computed: {}, expected: {}", hex::encode_upper(&res[0..expected_kcv.len()]), hex::encode_upper(expected_kcv)) })
+    }
+}
+
+/// Combines (XOR's) clear key components into a key, all components should be of the same length
+pub fn combine_components(components: &[&[u8]]) -> Result<Vec<u8>, CryptoError> {
+    if components.len() < 2 {
+        return Err(CryptoError { msg: format!("at least 2 key components are required, found: {}", components.len()) });
+    }
+
+    let len = components[0].len();
+    if components.iter().any(|c| c.len() != len) {
+        return Err(CryptoError { msg: "all key components should be of the same length".to_string() });
+    }
+
+    Ok((0..len).map(|i| components.iter().fold(0u8, |res, c| res ^ c[i])).collect())
+}
+
+/// Returns true if each byte of key has odd parity (as required for DES/TDES keys)
+pub fn has_odd_parity(key: &[u8]) -> bool {
+    key.iter().all(|b| b.count_ones() % 2 == 1)
+}
+
+/// Returns key with odd parity i.e with the least significant bit of each byte adjusted
+pub fn set_odd_parity(key: &[u8]) -> Vec<u8> {
+    key.iter().map(|b| if b.count_ones() % 2 == 1 { *b } else { b ^ 0x01 }).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::keys::{combine_components, has_odd_parity, kcv, set_odd_parity, verify_kcv};
+    use crate::crypto::Cipher;
+
+    #[test]
+    fn test_kcv() {
+        let key = hex::decode("0123456789ABCDEFFEDCBA9876543210").unwrap();
+        assert_eq!(hex::encode_upper(kcv(Cipher::TdesEde2, &key).unwrap()), "08D7B4");
+        assert_eq!(hex::encode_upper(kcv(Cipher::Des, &key[0..8]).unwrap()), "D5D44F");
+        assert!(verify_kcv(Cipher::TdesEde2, &key, &hex::decode("08D7B4").unwrap()).is_ok());
+        assert!(verify_kcv(Cipher::TdesEde2, &key, &hex::decode("08D7").unwrap()).is_ok());
+        assert!(verify_kcv(Cipher::TdesEde2, &key, &hex::decode("08D7B5").unwrap()).is_err());
+        assert!(verify_kcv(Cipher::TdesEde2, &key, &hex::decode("08D7B4FB").unwrap()).is_err());
+
+        let key = hex::decode("2b7e151628aed2a6abf7158809cf4f3c").unwrap();
+        assert_eq!(hex::encode_upper(kcv(Cipher::Aes128, &key).unwrap()), "7AD386C376");
+        assert!(verify_kcv(Cipher::Aes128, &key, &hex::decode("7AD386").unwrap()).is_ok());
+        assert!(kcv(Cipher::Aes256, &key).is_err());
+    }
+
+    #[test]
+    fn test_components() {
+        let c1 = hex::decode("0123456789ABCDEFFEDCBA9876543210").unwrap();
+        let c2 = hex::decode("01010101010101018080808080808080").unwrap();
+        let c3 = hex::decode("02020202020202020404040404040404").unwrap();
+
+        let key = combine_components(&[&c1, &c2, &c3]).unwrap();
+        assert_eq!(hex::encode_upper(&key), "022046648AA8CEEC7A583E1CF2D0B694");
+        assert!(has_odd_parity(&key));
+        assert_eq!(hex::encode_upper(kcv(Cipher::TdesEde2, &key).unwrap()), "88FF52");
+
+        // 2 odd parity components give a even parity key
+        let key = combine_components(&[&c1, &c2]).unwrap();
+        assert!(!has_odd_parity(&key));
+        assert_eq!(hex::encode_upper(set_odd_parity(&key)), "0123456789ABCDEF7F5D3B19F7D5B391");
+
+        assert!(combine_components(&[&c1]).is_err());
+        assert!(combine_components(&[&c1, &c2[0..8]]).is_err());
+    }
+}
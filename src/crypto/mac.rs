@@ -2,7 +2,13 @@
 
 //https://en.wikipedia.org/wiki/ISO/IEC_9797-1#Complete_specification_of_the_MAC_calculation
 
-use crate::crypto::{tdes_encrypt_cbc, des_encrypt_cbc};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use crate::crypto::{tdes_encrypt_cbc, Cipher, CryptoError};
+
+/// The minimum length (in bytes) a MAC can be truncated to
+pub const MIN_MAC_LEN: usize = 4;
 
 /// This enum defines various supported algorithms
 pub enum MacAlgo {
@@ -10,23 +16,44 @@ pub enum MacAlgo {
     CbcMac,
     // ISO9797 - algo 3
     RetailMac,
+    /// ISO 9797-1 algorithm 5 (CMAC) with a DES or TDES key, the MAC is 8 bytes
+    TdesCmac,
+    /// AES-CMAC (NIST SP 800-38B, ISO 9797-1 algorithm 5) with a 128, 192 or 256 bit key, the MAC is 16 bytes
+    AesCmac,
+    /// HMAC-SHA-256 (RFC 2104) with a key of at least 16 bytes, the MAC is 32 bytes
+    HmacSha256,
 }
 
-/// This enum defines all supported padding types
+/// This enum defines all supported (ISO 9797-1) padding types, CMAC and HMAC have their own padding
 pub enum PaddingType {
-    /// Adding 0 bits
+    /// Method 1 - adding 0 bits (a block of 0 bits for empty data)
     Type1,
-    /// Adding a single 1 bit followed by 0 bits
+    /// Method 2 - adding a single 1 bit followed by 0 bits
     Type2,
+    /// Method 3 - a block with the length of the data (in bits) followed by the data padded with 0 bits
+    Type3,
 }
 
 pub struct MacError {
     pub msg: String
 }
 
+impl From<CryptoError> for MacError {
+    fn from(e: CryptoError) -> Self {
+        MacError { msg: e.msg }
+    }
+}
+
+impl From<MacError> for CryptoError {
+    fn from(e: MacError) -> Self {
+        CryptoError { msg: e.msg }
+    }
+}
 
+
+/// Verifies expected_mac against the MAC computed on data, expected_mac can be a (leftmost) truncated MAC
 pub fn verify_mac(algo: &MacAlgo, padding_type: &PaddingType, data: &[u8], key: &Vec<u8>, expected_mac: &Vec<u8>) -> Result<(), MacError> {
-    let mac = generate_mac(algo, padding_type, &data.to_vec(), key)?;
+    let mac = truncate_mac(generate_mac(algo, padding_type, data, key)?, expected_mac.len())?;
     if mac.eq(expected_mac) {
         Ok(())
     } else {
@@ -34,51 +61,107 @@ pub fn verify_mac(algo: &MacAlgo, padding_type: &PaddingType, data: &[u8], key:
     }
 }
 
-pub fn generate_mac(algo: &MacAlgo, padding_type: &PaddingType, data: &Vec<u8>, key: &Vec<u8>) -> Result<Vec<u8>, MacError> {
-    let new_data = apply_padding(padding_type, data);
-    let mut iv = Vec::<u8>::new();
-    iv.extend_from_slice(hex::decode("0000000000000000").unwrap().as_slice());
-
-    println!("generating mac on {}", hex::encode(data));
+/// Truncates mac to its leftmost len bytes
+pub fn truncate_mac(mut mac: Vec<u8>, len: usize) -> Result<Vec<u8>, MacError> {
+    if len < MIN_MAC_LEN || len > mac.len() {
+        return Err(MacError { msg: format!("invalid MAC length: {} (should be between {} and {})", len, MIN_MAC_LEN, mac.len()) });
+    }
+    mac.truncate(len);
+    Ok(mac)
+}
 
+/// Generates the (untruncated) MAC on data, the padding type applies to CbcMac and RetailMac only
+pub fn generate_mac(algo: &MacAlgo, padding_type: &PaddingType, data: &[u8], key: &[u8]) -> Result<Vec<u8>, MacError> {
     match algo {
         MacAlgo::CbcMac => {
-            let res = tdes_encrypt_cbc(&new_data, key, &iv);
+            // the key should be a DES or TDES (2 or 3 key) key
+            Cipher::tdes(key)?;
+            let res = tdes_encrypt_cbc(&apply_padding(padding_type, data), key, &[0u8; 8])?;
             Ok(res[res.len() - 8..].to_vec())
         }
         MacAlgo::RetailMac => {
-            let k = key.as_slice()[0..8].to_vec();
+            if Cipher::tdes(key)? == Cipher::Des {
+                return Err(MacError { msg: "a retail MAC requires a double or triple length TDES key".to_string() });
+            }
 
-            //if there is a single block
-            if data.len() == 8 {
-                Ok(tdes_encrypt_cbc(&data, key, &iv))
+            // all but the last block DES (with the left key) and the last block TDES
+            let new_data = apply_padding(padding_type, data);
+            let (d1, d2) = new_data.split_at(new_data.len() - 8);
+            let iv = if d1.is_empty() {
+                vec![0u8; 8]
             } else {
+                let res = tdes_encrypt_cbc(d1, &key[0..8], &[0u8; 8])?;
+                res[res.len() - 8..].to_vec()
+            };
+            Ok(tdes_encrypt_cbc(d2, key, &iv)?)
+        }
+        MacAlgo::TdesCmac => Ok(cmac(Cipher::tdes(key)?, data, key)?),
+        MacAlgo::AesCmac => Ok(cmac(Cipher::aes(key)?, data, key)?),
+        MacAlgo::HmacSha256 => hmac_sha256(data, key),
+    }
+}
 
-                //else, all but the last block DES and the last block TDES
-                let d1 = &new_data[0..new_data.len() - 8].to_vec();
-                let d2 = &new_data[new_data.len() - 8..].to_vec();
+/// CMAC - a CBC MAC where the last block is padded (if incomplete) and XOR'ed with a subkey derived from key
+fn cmac(cipher: Cipher, data: &[u8], key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let n = cipher.block_size();
+    let k1 = cmac_subkey(&cipher.encrypt_ecb(&vec![0u8; n], key)?);
+    let k2 = cmac_subkey(&k1);
 
-                let res1 = des_encrypt_cbc(&d1, &k, &iv);
-                Ok(tdes_encrypt_cbc(&d2, key, &res1[(res1.len() - 8)..].to_vec()))
-            }
+    let mut new_data = data.to_vec();
+    let subkey = if !data.is_empty() && data.len().is_multiple_of(n) {
+        k1
+    } else {
+        new_data.push(0x80);
+        while !new_data.len().is_multiple_of(n) {
+            new_data.push(0x00);
         }
+        k2
+    };
+
+    let last = new_data.len() - n;
+    new_data[last..].iter_mut().zip(subkey.iter()).for_each(|(b, k)| *b ^= k);
+    let res = cipher.encrypt_cbc(&new_data, key, &vec![0u8; n])?;
+    Ok(res[res.len() - n..].to_vec())
+}
+
+/// Derives a CMAC subkey i.e. shifts the block left by 1 bit and XOR's the last byte with 0x87 (0x1B for 64
+/// bit blocks) if the shifted out bit is set
+fn cmac_subkey(block: &[u8]) -> Vec<u8> {
+    let mut res: Vec<u8> = block.iter().enumerate()
+        .map(|(i, b)| (b << 1) | block.get(i + 1).map_or(0, |n| n >> 7))
+        .collect();
+    if block[0] & 0x80 != 0 {
+        let last = res.len() - 1;
+        res[last] ^= if block.len() == 16 { 0x87 } else { 0x1B };
+    }
+    res
+}
+
+fn hmac_sha256(data: &[u8], key: &[u8]) -> Result<Vec<u8>, MacError> {
+    if key.len() < 16 {
+        return Err(MacError { msg: format!("invalid HMAC key length: {} (should be at least 16)", key.len()) });
     }
+    let mut mac = Hmac::<Sha256>::new_varkey(key).map_err(|e| MacError { msg: format!("invalid HMAC key: {}", e) })?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
 }
 
-fn apply_padding(padding_type: &PaddingType, data: &Vec<u8>) -> Vec<u8> {
-    let mut new_data = data.clone();
-    match padding_type {
-        PaddingType::Type1 => {}
+fn apply_padding(padding_type: &PaddingType, data: &[u8]) -> Vec<u8> {
+    let mut new_data = match padding_type {
+        PaddingType::Type1 => data.to_vec(),
         PaddingType::Type2 => {
-            new_data.push(0x80);
+            let mut res = data.to_vec();
+            res.push(0x80);
+            res
+        }
+        PaddingType::Type3 => {
+            let mut res = ((data.len() * 8) as u64).to_be_bytes().to_vec();
+            res.extend_from_slice(data);
+            res
         }
     };
 
-    while new_data.len() < 8 {
-        new_data.push(0x00);
-    }
-
-    while new_data.len() % 8 != 0 {
+    while new_data.is_empty() || !new_data.len().is_multiple_of(8) {
         new_data.push(0x00);
     }
 
@@ -88,7 +171,7 @@ fn apply_padding(padding_type: &PaddingType, data: &Vec<u8>) -> Vec<u8> {
 
 #[cfg(test)]
 mod tests {
-    use crate::crypto::mac::{apply_padding, PaddingType, generate_mac, MacAlgo};
+    use crate::crypto::mac::{apply_padding, PaddingType, generate_mac, verify_mac, truncate_mac, MacAlgo};
     use hex_literal::hex;
 
     #[test]
@@ -129,6 +212,55 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_padding3() {
+        let data = hex::decode("0102030405").unwrap();
+        assert_eq!(hex::encode(apply_padding(&PaddingType::Type3, &data)), "00000000000000280102030405000000");
+        assert_eq!(hex::encode(apply_padding(&PaddingType::Type3, &[])), "0000000000000000");
+    }
+
+    #[test]
+    fn test_padding_empty() {
+        assert_eq!(hex::encode(apply_padding(&PaddingType::Type1, &[])), "0000000000000000");
+        assert_eq!(hex::encode(apply_padding(&PaddingType::Type2, &[])), "8000000000000000");
+    }
+
+    #[test]
+    fn test_iso9797_vectors() {
+        // ISO/IEC 9797-1 Annex B - K = 0123456789ABCDEF, K' = FEDCBA9876543210
+        let k = Vec::from(hex!("0123456789ABCDEF"));
+        let kk = Vec::from(hex!("0123456789ABCDEFFEDCBA9876543210"));
+        let mac = |algo: MacAlgo, padding: PaddingType, data: &[u8], key: &Vec<u8>| {
+            hex::encode_upper(generate_mac(&algo, &padding, data, key).ok().unwrap())
+        };
+
+        let d1 = b"Now is the time for all ";
+        assert_eq!(mac(MacAlgo::CbcMac, PaddingType::Type1, d1, &k), "70A30640CC76DD8B");
+        assert_eq!(mac(MacAlgo::CbcMac, PaddingType::Type2, d1, &k), "10E1F0F108341B6D");
+        assert_eq!(mac(MacAlgo::CbcMac, PaddingType::Type3, d1, &k), "2C58FB8FF12AAEAC");
+        assert_eq!(mac(MacAlgo::RetailMac, PaddingType::Type1, d1, &kk), "A1C72E74EA3FA9B6");
+        assert_eq!(mac(MacAlgo::RetailMac, PaddingType::Type2, d1, &kk), "E9086230CA3BE796");
+        assert_eq!(mac(MacAlgo::RetailMac, PaddingType::Type3, d1, &kk), "AB059463D7A7D170");
+
+        let d2 = b"Now is the time for it";
+        assert_eq!(mac(MacAlgo::CbcMac, PaddingType::Type1, d2, &k), "E45B3AD2B7CC0856");
+        assert_eq!(mac(MacAlgo::CbcMac, PaddingType::Type2, d2, &k), "A924C72136149211");
+        assert_eq!(mac(MacAlgo::CbcMac, PaddingType::Type3, d2, &k), "B1ECD6FC8B37C392");
+        assert_eq!(mac(MacAlgo::RetailMac, PaddingType::Type1, d2, &kk), "2E2B1428CC78254F");
+        assert_eq!(mac(MacAlgo::RetailMac, PaddingType::Type2, d2, &kk), "5A692CE64F404145");
+        assert_eq!(mac(MacAlgo::RetailMac, PaddingType::Type3, d2, &kk), "C59F7EED328DDD69");
+
+        // algorithm 5 (CMAC)
+        assert_eq!(mac(MacAlgo::TdesCmac, PaddingType::Type1, d1, &kk), "305EF2A5FE4D58C8");
+        assert_eq!(mac(MacAlgo::TdesCmac, PaddingType::Type1, d2, &kk), "2CFE6F5D817244AC");
+        assert_eq!(mac(MacAlgo::TdesCmac, PaddingType::Type1, &[], &kk), "5B560372570D37CB");
+
+        // a single (padded) block and empty data
+        assert_eq!(mac(MacAlgo::RetailMac, PaddingType::Type1, &[], &kk), "08D7B4FB629D0885");
+        assert_eq!(mac(MacAlgo::RetailMac, PaddingType::Type2, &[], &kk), "F1FBCF2A56D19BA7");
+        assert!(generate_mac(&MacAlgo::RetailMac, &PaddingType::Type1, d1, &k).is_err());
+    }
+
     #[test]
     fn test_gen_mac_cbc_nopads() {
         let res = generate_mac(&MacAlgo::CbcMac, &PaddingType::Type1,
@@ -235,4 +367,58 @@ mod tests {
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_gen_mac_key_lengths() {
+        let data = Vec::from(hex!("0102030405060708010203040506070801020304050607080000"));
+        let k2 = Vec::from(hex!("e0f4543f3e2a2c5ffc7e5e5a222e3e4d"));
+        // a 3 key TDES key with K3 = K1 is the same as the 2 key TDES key
+        let k3 = Vec::from(hex!("e0f4543f3e2a2c5ffc7e5e5a222e3e4de0f4543f3e2a2c5f"));
+
+        for algo in [MacAlgo::CbcMac, MacAlgo::RetailMac].iter() {
+            let m2 = generate_mac(algo, &PaddingType::Type1, &data, &k2).ok().unwrap();
+            let m3 = generate_mac(algo, &PaddingType::Type1, &data, &k3).ok().unwrap();
+            assert_eq!(m2, m3);
+        }
+        assert!(generate_mac(&MacAlgo::CbcMac, &PaddingType::Type1, &data, &k2[0..10]).is_err());
+        assert!(generate_mac(&MacAlgo::RetailMac, &PaddingType::Type1, &data, &k2[0..4]).is_err());
+    }
+
+    #[test]
+    fn test_gen_mac_aes_cmac() {
+        // NIST SP 800-38B / RFC 4493 examples
+        let key = Vec::from(hex!("2b7e151628aed2a6abf7158809cf4f3c"));
+        let msg = Vec::from(hex!("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e5130c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710"));
+        let cmac = |data: &[u8], key: &Vec<u8>| hex::encode(generate_mac(&MacAlgo::AesCmac, &PaddingType::Type1, data, key).ok().unwrap());
+
+        assert_eq!(cmac(&[], &key), "bb1d6929e95937287fa37d129b756746");
+        assert_eq!(cmac(&msg[0..16], &key), "070a16b46b4d4144f79bdd9dd04a287c");
+        assert_eq!(cmac(&msg[0..40], &key), "dfa66747de9ae63030ca32611497c827");
+        assert_eq!(cmac(&msg, &key), "51f0bebf7e3b9d92fc49741779363cfe");
+        assert_eq!(cmac(&msg[0..16], &Vec::from(hex!("8e73b0f7da0e6452c810f32b809079e562f8ead2522c6b7b"))), "9e99a7bf31e710900662f65e617c5184");
+        assert_eq!(cmac(&[], &Vec::from(hex!("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4"))), "028962f61b7bf89efc6b551f4667d983");
+
+        assert!(generate_mac(&MacAlgo::AesCmac, &PaddingType::Type1, &msg, &key[0..8]).is_err());
+    }
+
+    #[test]
+    fn test_gen_mac_hmac_sha256() {
+        // RFC 4231 test cases 1 and 4
+        let mac = generate_mac(&MacAlgo::HmacSha256, &PaddingType::Type1, b"Hi There", &[0x0b; 20]).ok().unwrap();
+        assert_eq!(hex::encode(mac), "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+
+        let key = (1..=25).collect::<Vec<u8>>();
+        let mac = generate_mac(&MacAlgo::HmacSha256, &PaddingType::Type1, &[0xcd; 50], &key).ok().unwrap();
+        assert_eq!(hex::encode(&mac), "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b");
+
+        // truncated to 8 bytes
+        let truncated = truncate_mac(mac, 8).ok().unwrap();
+        assert_eq!(hex::encode(&truncated), "82558a389a443c0e");
+        assert!(verify_mac(&MacAlgo::HmacSha256, &PaddingType::Type1, &[0xcd; 50], &key, &truncated).is_ok());
+        assert!(verify_mac(&MacAlgo::HmacSha256, &PaddingType::Type1, &[0xcd; 49], &key, &truncated).is_err());
+
+        assert!(truncate_mac(vec![0; 32], 3).is_err());
+        assert!(truncate_mac(vec![0; 8], 16).is_err());
+        assert!(generate_mac(&MacAlgo::HmacSha256, &PaddingType::Type1, b"Hi There", b"Jefe").is_err());
+    }
+}
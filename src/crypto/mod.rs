@@ -1,69 +1,274 @@
 pub mod mac;
 pub mod pin;
+pub mod pin_verification;
+pub mod dukpt;
+pub mod keys;
+pub mod tr31;
+pub mod security_module;
+pub mod hsm;
+pub mod cvv;
+pub mod emv;
 
 extern crate rand;
 extern crate des;
 extern crate block_modes;
 extern crate hex_literal;
+extern crate aes;
 
 
 use generic_array::{GenericArray};
+use generic_array::typenum::{U8, U16};
 use des::block_cipher::NewBlockCipher;
 use des::block_cipher::BlockCipher;
+use aes::NewBlockCipher as _;
 
 
-use self::block_modes::{BlockMode};
+/// CryptoError is a generic error in processing within this crate
+#[derive(Debug)]
+pub struct CryptoError {
+    pub msg: String
+}
 
+/// This enum represents the block ciphers supported by the crate, the variant is selected by the
+/// length of the key (see `Cipher::tdes` and `Cipher::aes`)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Cipher {
+    /// Single DES - 8 byte key
+    Des,
+    /// Two key triple DES - 16 byte key
+    TdesEde2,
+    /// Three key triple DES - 24 byte key
+    TdesEde3,
+    Aes128,
+    Aes192,
+    Aes256,
+}
 
-/// CryptoError is a generic error in processing within this crate
-#[allow(unused)]
-pub(crate) struct CryptoError {
-    pub(crate) msg: String
+impl Cipher {
+    /// Returns the DES/TDES cipher for a key of 8, 16 or 24 bytes
+    pub fn tdes(key: &[u8]) -> Result<Cipher, CryptoError> {
+        match key.len() {
+            8 => Ok(Cipher::Des),
+            16 => Ok(Cipher::TdesEde2),
+            24 => Ok(Cipher::TdesEde3),
+            _ => Err(CryptoError { msg: format!("invalid DES/TDES key length: {} (expected 8, 16 or 24)", key.len()) })
+        }
+    }
+
+    /// Returns the AES cipher for a key of 16, 24 or 32 bytes
+    pub fn aes(key: &[u8]) -> Result<Cipher, CryptoError> {
+        match key.len() {
+            16 => Ok(Cipher::Aes128),
+            24 => Ok(Cipher::Aes192),
+            32 => Ok(Cipher::Aes256),
+            _ => Err(CryptoError { msg: format!("invalid AES key length: {} (expected 16, 24 or 32)", key.len()) })
+        }
+    }
+
+    /// Returns the block size of the cipher in bytes
+    pub fn block_size(&self) -> usize {
+        match self {
+            Cipher::Des | Cipher::TdesEde2 | Cipher::TdesEde3 => 8,
+            _ => 16
+        }
+    }
+
+    /// Returns the length of the key of the cipher in bytes
+    pub fn key_len(&self) -> usize {
+        match self {
+            Cipher::Des => 8,
+            Cipher::TdesEde2 | Cipher::Aes128 => 16,
+            Cipher::TdesEde3 | Cipher::Aes192 => 24,
+            Cipher::Aes256 => 32,
+        }
+    }
+
+    /// Encrypts data (a multiple of the block size) in ECB mode
+    pub fn encrypt_ecb(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.ecb(data, key, true)
+    }
+
+    /// Decrypts data (a multiple of the block size) in ECB mode
+    pub fn decrypt_ecb(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.ecb(data, key, false)
+    }
+
+    /// Encrypts data (a multiple of the block size) in CBC mode
+    pub fn encrypt_cbc(&self, data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.check(data, key)?;
+        let mut prev = self.check_iv(iv)?;
+        let mut res = Vec::with_capacity(data.len());
+        for block in data.chunks(self.block_size()) {
+            let input: Vec<u8> = block.iter().zip(prev.iter()).map(|(a, b)| a ^ b).collect();
+            prev = self.ecb(&input, key, true)?;
+            res.extend_from_slice(&prev);
+        }
+        Ok(res)
+    }
+
+    /// Decrypts data (a multiple of the block size) in CBC mode
+    pub fn decrypt_cbc(&self, data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.check(data, key)?;
+        let mut prev = self.check_iv(iv)?;
+        let mut res = Vec::with_capacity(data.len());
+        for block in data.chunks(self.block_size()) {
+            let output = self.ecb(block, key, false)?;
+            res.extend(output.iter().zip(prev.iter()).map(|(a, b)| a ^ b));
+            prev = block.to_vec();
+        }
+        Ok(res)
+    }
+
+    fn check(&self, data: &[u8], key: &[u8]) -> Result<(), CryptoError> {
+        if key.len() != self.key_len() {
+            return Err(CryptoError { msg: format!("invalid key length {} for {:?}", key.len(), self) });
+        }
+        if !data.len().is_multiple_of(self.block_size()) {
+            return Err(CryptoError { msg: format!("data length {} is not a multiple of the block size of {:?}", data.len(), self) });
+        }
+        Ok(())
+    }
+
+    fn check_iv(&self, iv: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if iv.len() != self.block_size() {
+            return Err(CryptoError { msg: format!("invalid IV length {} for {:?}", iv.len(), self) });
+        }
+        Ok(iv.to_vec())
+    }
+
+    fn ecb(&self, data: &[u8], key: &[u8], encrypt: bool) -> Result<Vec<u8>, CryptoError> {
+        self.check(data, key)?;
+        let mut res = data.to_vec();
+        match self {
+            Cipher::Des => des_ecb(&des::Des::new(GenericArray::from_slice(key)), &mut res, encrypt),
+            Cipher::TdesEde2 => des_ecb(&des::TdesEde2::new(GenericArray::from_slice(key)), &mut res, encrypt),
+            Cipher::TdesEde3 => des_ecb(&des::TdesEde3::new(GenericArray::from_slice(key)), &mut res, encrypt),
+            Cipher::Aes128 => aes_ecb(&aes::Aes128::new(GenericArray::from_slice(key)), &mut res, encrypt),
+            Cipher::Aes192 => aes_ecb(&aes::Aes192::new(GenericArray::from_slice(key)), &mut res, encrypt),
+            Cipher::Aes256 => aes_ecb(&aes::Aes256::new(GenericArray::from_slice(key)), &mut res, encrypt),
+        }
+        Ok(res)
+    }
 }
 
-pub(crate) fn tdes_ede2_encrypt(data: &Vec<u8>, key: &Vec<u8>) -> Vec<u8> {
-    let block_cipher = des::TdesEde2::new(GenericArray::from_slice(key.as_slice()));
+fn des_ecb<C: BlockCipher<BlockSize=U8>>(cipher: &C, data: &mut [u8], encrypt: bool) {
+    for block in data.chunks_mut(8) {
+        let block = GenericArray::from_mut_slice(block);
+        if encrypt { cipher.encrypt_block(block) } else { cipher.decrypt_block(block) }
+    }
+}
 
-    let mut cp_data = data.clone();
-    block_cipher.encrypt_block(GenericArray::from_mut_slice(&mut cp_data));
-    cp_data
+fn aes_ecb<C: aes::BlockCipher<BlockSize=U16>>(cipher: &C, data: &mut [u8], encrypt: bool) {
+    for block in data.chunks_mut(16) {
+        let block = GenericArray::from_mut_slice(block);
+        if encrypt { cipher.encrypt_block(block) } else { cipher.decrypt_block(block) }
+    }
 }
 
-pub(crate) fn tdes_ede2_decrypt(data: &Vec<u8>, key: &Vec<u8>) -> Vec<u8> {
-    let block_cipher = des::TdesEde2::new(GenericArray::from_slice(key.as_slice()));
+/// Encrypts data (in ECB mode) with DES, TDES (2 key) or TDES (3 key) based on the length of the key
+pub(crate) fn tdes_encrypt(data: &[u8], key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    Cipher::tdes(key)?.encrypt_ecb(data, key)
+}
 
-    let mut cp_data = data.clone();
-    block_cipher.decrypt_block(GenericArray::from_mut_slice(&mut cp_data));
-    cp_data
+/// Decrypts data (in ECB mode) with DES, TDES (2 key) or TDES (3 key) based on the length of the key
+pub(crate) fn tdes_decrypt(data: &[u8], key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    Cipher::tdes(key)?.decrypt_ecb(data, key)
 }
 
-type TripleDESCBC = block_modes::Cbc::<des::TdesEde2, block_modes::block_padding::NoPadding>;
+/// Encrypts data (in CBC mode) with DES, TDES (2 key) or TDES (3 key) based on the length of the key
+pub(crate) fn tdes_encrypt_cbc(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    Cipher::tdes(key)?.encrypt_cbc(data, key, iv)
+}
 
-pub(crate) fn tdes_encrypt_cbc(data: &Vec<u8>, key: &Vec<u8>, iv: &Vec<u8>) -> Vec<u8> {
-    let block_cipher = TripleDESCBC::new_var(key.as_slice(), &iv.as_slice()).unwrap();
+/// Decrypts data (in CBC mode) with DES, TDES (2 key) or TDES (3 key) based on the length of the key
+#[allow(unused)]
+pub(crate) fn tdes_decrypt_cbc(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    Cipher::tdes(key)?.decrypt_cbc(data, key, iv)
+}
 
-    let encrypted_data = block_cipher.encrypt_vec(data.as_slice());
-    encrypted_data
+/// Encrypts data (in ECB mode) with AES-128, AES-192 or AES-256 based on the length of the key
+pub(crate) fn aes_encrypt(data: &[u8], key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    Cipher::aes(key)?.encrypt_ecb(data, key)
 }
 
+/// Decrypts data (in ECB mode) with AES-128, AES-192 or AES-256 based on the length of the key
+pub(crate) fn aes_decrypt(data: &[u8], key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    Cipher::aes(key)?.decrypt_ecb(data, key)
+}
 
-pub(crate) fn des_encrypt_cbc(data: &Vec<u8>, key: &Vec<u8>, iv: &Vec<u8>) -> Vec<u8> {
-    let block_cipher = block_modes::Cbc::<des::Des, block_modes::block_padding::NoPadding>::new_var(key.as_slice(), iv.as_slice()).unwrap();
-    block_cipher.encrypt_vec(data)
+/// Compares a and b (like two MACs) in a time that does not depend on where they differ, slices of a different
+/// length are not equal
+pub(crate) fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
-type DesCbc = block_modes::Cbc::<des::Des, block_modes::block_padding::NoPadding>;
 
-#[allow(unused)]
-pub(crate) fn des_decrypt_cbc(data: &Vec<u8>, key: &Vec<u8>, iv: &Vec<u8>) -> Result<Vec<u8>, CryptoError> {
-    let block_cipher = DesCbc::new_var(key.as_slice(), iv.as_slice()).unwrap();
+#[cfg(test)]
+mod tests {
+    use crate::crypto::{Cipher, ct_eq, tdes_encrypt, tdes_decrypt, tdes_encrypt_cbc, tdes_decrypt_cbc, aes_encrypt, aes_decrypt};
 
-    match block_cipher.decrypt_vec(data) {
-        Ok(d) => {
-            Ok(d)
-        }
-        Err(e) => {
-            Err(CryptoError { msg: e.to_string() })
+    #[test]
+    fn test_cipher_by_key_length() {
+        assert_eq!(Cipher::tdes(&[0; 8]).unwrap(), Cipher::Des);
+        assert_eq!(Cipher::tdes(&[0; 16]).unwrap(), Cipher::TdesEde2);
+        assert_eq!(Cipher::tdes(&[0; 24]).unwrap(), Cipher::TdesEde3);
+        assert!(Cipher::tdes(&[0; 32]).is_err());
+        assert_eq!(Cipher::aes(&[0; 24]).unwrap(), Cipher::Aes192);
+        assert!(Cipher::aes(&[0; 8]).is_err());
+    }
+
+    #[test]
+    fn test_tdes() {
+        let data = hex::decode("0123456789abcdef0123456789abcdef").unwrap();
+        let k1 = hex::decode("0123456789abcdef").unwrap();
+        let k2 = hex::decode("0123456789abcdeffedcba9876543210").unwrap();
+        let k3 = hex::decode("0123456789abcdeffedcba987654321089abcdef01234567").unwrap();
+
+        // NIST SP 800-67 / FIPS 46 style known answers
+        assert_eq!(hex::encode(tdes_encrypt(&data[0..8], &hex::decode("133457799bbcdff1").unwrap()).unwrap()), "85e813540f0ab405");
+        // a 2 key TDES key is the same as a 3 key TDES key with K3 = K1
+        let k2_as_k3 = [&k2[..], &k2[0..8]].concat();
+        assert_eq!(tdes_encrypt(&data, &k2).unwrap(), tdes_encrypt(&data, &k2_as_k3).unwrap());
+        // TDES with K1 = K2 = K3 is single DES
+        let k1_as_k3 = [&k1[..], &k1[..], &k1[..]].concat();
+        assert_eq!(tdes_encrypt(&data, &k1).unwrap(), tdes_encrypt(&data, &k1_as_k3).unwrap());
+
+        for key in [&k1, &k2, &k3].iter() {
+            assert_eq!(tdes_decrypt(&tdes_encrypt(&data, key).unwrap(), key).unwrap(), data);
+            let iv = [1u8; 8];
+            let enc = tdes_encrypt_cbc(&data, key, &iv).unwrap();
+            assert_eq!(tdes_decrypt_cbc(&enc, key, &iv).unwrap(), data);
         }
+
+        assert!(tdes_encrypt(&data, &data[0..12]).is_err());
+        assert!(tdes_encrypt(&data[0..7], &k2).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_aes() {
+        // FIPS-197 appendix C
+        let data = hex::decode("00112233445566778899aabbccddeeff").unwrap();
+        let k128 = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let k192 = hex::decode("000102030405060708090a0b0c0d0e0f1011121314151617").unwrap();
+        let k256 = hex::decode("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f").unwrap();
+
+        assert_eq!(hex::encode(aes_encrypt(&data, &k128).unwrap()), "69c4e0d86a7b0430d8cdb78070b4c55a");
+        assert_eq!(hex::encode(aes_encrypt(&data, &k192).unwrap()), "dda97ca4864cdfe06eaf70a0ec0d7191");
+        assert_eq!(hex::encode(aes_encrypt(&data, &k256).unwrap()), "8ea2b7ca516745bfeafc49904b496089");
+        assert_eq!(aes_decrypt(&aes_encrypt(&data, &k256).unwrap(), &k256).unwrap(), data);
+
+        let iv = [7u8; 16];
+        let enc = Cipher::Aes128.encrypt_cbc(&[data.clone(), data.clone()].concat(), &k128, &iv).unwrap();
+        assert_eq!(Cipher::Aes128.decrypt_cbc(&enc, &k128, &iv).unwrap(), [data.clone(), data.clone()].concat());
+        assert!(Cipher::Aes128.encrypt_cbc(&data, &k128, &iv[0..8]).is_err());
+    }
+
+    #[test]
+    fn test_ct_eq() {
+        assert!(ct_eq(&[0x01, 0x02, 0x03], &[0x01, 0x02, 0x03]));
+        assert!(ct_eq(&[], &[]));
+        assert!(!ct_eq(&[0x01, 0x02, 0x03], &[0x01, 0x02, 0x02]));
+        assert!(!ct_eq(&[0x81, 0x02], &[0x01, 0x02]));
+        assert!(!ct_eq(&[0x01, 0x02], &[0x01, 0x02, 0x03]));
+    }
+}
@@ -4,7 +4,7 @@
 
 use rand;
 use super::rand::Rng;
-use crate::crypto::{tdes_ede2_decrypt, tdes_ede2_encrypt};
+use crate::crypto::{tdes_decrypt, tdes_encrypt, aes_encrypt, aes_decrypt, CryptoError};
 
 
 #[derive(Debug)]
@@ -17,22 +17,66 @@ pub enum PinFormat {
     ISO4,
 }
 
+/// This enum represents the kind of a PinError
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PinErrorKind {
+    /// The format (control) nibble of the PIN block or the PIN digits are invalid
+    InvalidFormat,
+    /// The padding of the PIN block is invalid
+    InvalidPadding,
+    /// The PIN length is not within 4 and 12
+    InvalidLength,
+    /// The PIN block was built for a different PAN
+    PanMismatch,
+    Other,
+}
+
+#[derive(Debug)]
 pub struct PinError {
-    pub msg: String
+    pub msg: String,
+    pub kind: PinErrorKind,
+}
+
+impl PinError {
+    pub(crate) fn new(kind: PinErrorKind, msg: String) -> PinError {
+        PinError { msg, kind }
+    }
 }
 
-pub fn generate_pin_block(fmt: &PinFormat, c_pin: &str, pan: &str, key: &Vec<u8>) -> Result<Vec<u8>, PinError> {
+impl From<CryptoError> for PinError {
+    fn from(e: CryptoError) -> Self {
+        PinError::new(PinErrorKind::Other, e.msg)
+    }
+}
+
+impl From<PinError> for CryptoError {
+    fn from(e: PinError) -> Self {
+        CryptoError { msg: e.msg }
+    }
+}
+
+/// Checks that c_pin is 4 to 12 digits
+fn check_pin(c_pin: &str) -> Result<(), PinError> {
+    if c_pin.len() < 4 || c_pin.len() > 12 {
+        return Err(PinError::new(PinErrorKind::InvalidLength, format!("invalid PIN - must be 4 to 12 digits, length: {}", c_pin.len())));
+    }
+    if !c_pin.chars().all(|c| c.is_ascii_digit()) {
+        return Err(PinError::new(PinErrorKind::InvalidFormat, "invalid PIN - must be 4 to 12 digits".to_string()));
+    }
+    Ok(())
+}
+
+pub fn generate_pin_block(fmt: &PinFormat, c_pin: &str, pan: &str, key: &[u8]) -> Result<Vec<u8>, PinError> {
+    check_pin(c_pin)?;
     match fmt {
         PinFormat::ISO0 => {
-            let mut b1 = format!("0{:X}{}", c_pin.len(), c_pin);
-            pad_8(&mut b1);
+            let b1 = format!("{:F<16}", format!("0{:X}{}", c_pin.len(), c_pin));
 
             //rightmost 12 not including check digit
-            let mut b2 = String::from("0000");
-            b2.push_str(&pan[pan.len() - 13..pan.len() - 1]);
+            let b2 = pan_field_12(pan)?;
 
             let res = xor_hexstr(b1.as_str(), b2.as_str());
-            let res = tdes_ede2_encrypt(&res, &key);
+            let res = tdes_encrypt(&res, key)?;
 
             Ok(res.to_vec())
         }
@@ -41,27 +85,24 @@ pub fn generate_pin_block(fmt: &PinFormat, c_pin: &str, pan: &str, key: &Vec<u8>
             pad_8(&mut b1);
             match hex::decode(b1) {
                 Ok(res) => {
-                    let res = tdes_ede2_encrypt(&res, &key);
+                    let res = tdes_encrypt(&res, key)?;
                     Ok(res)
                 }
                 Err(e) => {
-                    Err(PinError { msg: e.to_string() })
+                    Err(PinError::new(PinErrorKind::Other, e.to_string()))
                 }
             }
         }
 
         PinFormat::ISO2 => {
-            let mut b1 = format!("2{:X}{}", c_pin.len(), c_pin);
-            while b1.len() != 16 {
-                b1.push('F');
-            }
+            let b1 = format!("{:F<16}", format!("2{:X}{}", c_pin.len(), c_pin));
             match hex::decode(b1) {
                 Ok(res) => {
-                    let res = tdes_ede2_encrypt(&res, &key);
+                    let res = tdes_encrypt(&res, key)?;
                     Ok(res)
                 }
                 Err(e) => {
-                    Err(PinError { msg: e.to_string() })
+                    Err(PinError::new(PinErrorKind::Other, e.to_string()))
                 }
             }
         }
@@ -71,85 +112,148 @@ pub fn generate_pin_block(fmt: &PinFormat, c_pin: &str, pan: &str, key: &Vec<u8>
             pad_8_a2f(&mut b1);
 
             //rightmost 12 not including check digit
-            let mut b2 = String::from("0000");
-            b2.push_str(&pan[pan.len() - 13..pan.len() - 1]);
+            let b2 = pan_field_12(pan)?;
 
             let res = xor_hexstr(b1.as_str(), b2.as_str());
-            let res = tdes_ede2_encrypt(&res, &key);
+            let res = tdes_encrypt(&res, key)?;
 
             Ok(res.to_vec())
         }
 
-        _ => {
-            Err(PinError { msg: format!("{:?} is not supported yet.", fmt) })
+        PinFormat::ISO4 => {
+            let fill: [u8; 8] = rand::thread_rng().gen();
+            iso4_pin_block(c_pin, pan, key, &fill)
         }
     }
 }
 
+/// Translates pin_block from in_fmt (under in_key) to out_fmt (under out_key), as a switch does when forwarding F52
+/// from the acquirer's zone to the issuer's zone. The clear PIN is never returned to the caller
+pub fn translate_pin_block(in_fmt: &PinFormat, in_key: &[u8], out_fmt: &PinFormat, out_key: &[u8], pin_block: &[u8], pan: &str) -> Result<Vec<u8>, PinError> {
+    let c_pin = decode_pin_block(in_fmt, pin_block, pan, in_key)?;
+    generate_pin_block(out_fmt, c_pin.as_str(), pan, out_key)
+}
+
+/// Builds a ISO-4 (AES) PIN block - the plain text PIN field is enciphered, XOR'ed with the PAN field and
+/// enciphered again with a 128, 192 or 256 bit AES key. fill is the random fill of the PIN field
+fn iso4_pin_block(c_pin: &str, pan: &str, key: &[u8], fill: &[u8; 8]) -> Result<Vec<u8>, PinError> {
+    check_pin(c_pin)?;
+
+    let mut pin_field = format!("{:A<16}", format!("4{:X}{}", c_pin.len(), c_pin));
+    pin_field.push_str(hex::encode_upper(fill).as_str());
+
+    let pan_field = iso4_pan_field(pan)?;
+    let intermediate = aes_encrypt(hex::decode(pin_field).unwrap().as_slice(), key)?;
+    let res: Vec<u8> = intermediate.iter().zip(pan_field.iter()).map(|(a, b)| a ^ b).collect();
+    Ok(aes_encrypt(res.as_slice(), key)?)
+}
+
+/// Returns the 16 byte ISO-4 PAN field - the PAN length minus 12 followed by the PAN (left padded with zeros to
+/// 12 digits) and right padded with zeros
+fn iso4_pan_field(pan: &str) -> Result<Vec<u8>, PinError> {
+    if pan.is_empty() || pan.len() > 19 || !pan.chars().all(|c| c.is_ascii_digit()) {
+        return Err(PinError::new(PinErrorKind::Other, format!("invalid PAN - must be 1 to 19 digits, length: {}", pan.len())));
+    }
+
+    let mut pan_field = format!("{}{:0>12}", pan.len().saturating_sub(12), pan);
+    while pan_field.len() != 32 {
+        pan_field.push('0');
+    }
+    Ok(hex::decode(pan_field).unwrap())
+}
+
+/// Decrypts a ISO-4 (AES) PIN block and returns the plain text PIN field
+fn iso4_pin_field(pin_block: &[u8], pan: &str, key: &[u8]) -> Result<Vec<u8>, PinError> {
+    let pan_field = iso4_pan_field(pan)?;
+    let res = aes_decrypt(pin_block, key)?;
+    let intermediate: Vec<u8> = res.iter().zip(pan_field.iter()).map(|(a, b)| a ^ b).collect();
+    Ok(aes_decrypt(intermediate.as_slice(), key)?)
+}
+
 /// Verifies the pin in the 'pin_block' against expected_pin and returns a boolean to indicate if there was
 /// was a successful match
 pub fn verify_pin(fmt: &PinFormat, expected_pin: &str, pin_block: &Vec<u8>, pan: &str, key: &Vec<u8>) -> Result<bool, PinError> {
-    debug!("verifying pin - expected_pin: {},  block: {}, pan:{}, key:{:?}", expected_pin, hex::encode(pin_block), pan, key);
-    match fmt {
-        PinFormat::ISO0 => {
-            let mut b2 = String::from("0000");
-            b2.push_str(&pan[pan.len() - 13..pan.len() - 1]);
-
-            let res = tdes_ede2_decrypt(&pin_block, &key);
-            let res = xor_hexstr(hex::encode(res.as_slice()).as_str(), b2.as_str());
-            let pin_len = res.get(0).unwrap();
-            let b1 = hex::encode(&res);
-            let actual_pin = b1.get(2 as usize..(2 + pin_len) as usize).unwrap().clone();
-            if expected_pin == actual_pin {
-                Ok(true)
-            } else {
-                Ok(false)
-            }
-        }
+    debug!("verifying pin - block: {}, pan:{}", hex::encode(pin_block), pan);
+    let actual_pin = decode_pin_block(fmt, pin_block, pan, key)?;
+    Ok(expected_pin == actual_pin)
+}
 
-        PinFormat::ISO1 => {
-            let res = tdes_ede2_decrypt(&pin_block, &key);
-
-            let pin_len = res.get(0).unwrap();
-            let b1 = hex::encode(&res);
-            let actual_pin = b1.get(2 as usize..(2 + (pin_len - 16)) as usize).unwrap().clone();
-            if expected_pin == actual_pin {
-                Ok(true)
-            } else {
-                Ok(false)
-            }
-        }
-        PinFormat::ISO2 => {
-            let res = tdes_ede2_decrypt(&pin_block, &key);
-
-            let pin_len = res.get(0).unwrap();
-            let b1 = hex::encode(&res);
-            let actual_pin = b1.get(2 as usize..(2 + (pin_len - 32)) as usize).unwrap().clone();
-            if expected_pin == actual_pin {
-                Ok(true)
-            } else {
-                Ok(false)
-            }
+/// Decrypts pin_block and returns the clear PIN after validating the structure of the PIN block - the format
+/// (control) nibble, the PIN length (4 to 12), the PIN digits and the padding. For ISO0, a PIN block built for
+/// a different PAN is reported as PinErrorKind::PanMismatch
+pub fn decode_pin_block(fmt: &PinFormat, pin_block: &[u8], pan: &str, key: &[u8]) -> Result<String, PinError> {
+    let block_len = match fmt {
+        PinFormat::ISO4 => 16,
+        _ => 8
+    };
+    if pin_block.len() != block_len {
+        return Err(PinError::new(PinErrorKind::Other, format!("invalid {:?} PIN block length: {}", fmt, pin_block.len())));
+    }
+
+    let (pin_field, pan_field) = match fmt {
+        PinFormat::ISO0 | PinFormat::ISO3 => {
+            let pan_field = pan_field_12(pan)?;
+            let res = tdes_decrypt(pin_block, key)?;
+            (hex::encode_upper(xor_hexstr(hex::encode(res).as_str(), pan_field.as_str())), Some(pan_field))
         }
-        PinFormat::ISO3 => {
-            let mut b2 = String::from("0000");
-            b2.push_str(&pan[pan.len() - 13..pan.len() - 1]);
-
-            let res = tdes_ede2_decrypt(&pin_block, &key);
-            let res = xor_hexstr(hex::encode(res.as_slice()).as_str(), b2.as_str());
-            let pin_len = res.get(0).unwrap();
-            let b1 = hex::encode(&res);
-            let actual_pin = b1.get(2 as usize..(2 + (pin_len - 48)) as usize).unwrap().clone();
-            if expected_pin == actual_pin {
-                Ok(true)
-            } else {
-                Ok(false)
+        PinFormat::ISO1 | PinFormat::ISO2 => (hex::encode_upper(tdes_decrypt(pin_block, key)?), None),
+        PinFormat::ISO4 => (hex::encode_upper(iso4_pin_field(pin_block, pan, key)?), None),
+    };
+    let nibbles: Vec<char> = pin_field.chars().collect();
+
+    let control = match fmt {
+        PinFormat::ISO0 => '0',
+        PinFormat::ISO1 => '1',
+        PinFormat::ISO2 => '2',
+        PinFormat::ISO3 => '3',
+        PinFormat::ISO4 => '4',
+    };
+    if nibbles[0] != control {
+        return Err(PinError::new(PinErrorKind::InvalidFormat, format!("invalid format nibble {} in {:?} PIN block", nibbles[0], fmt)));
+    }
+
+    let pin_len = nibbles[1].to_digit(16).unwrap() as usize;
+    if !(4..=12).contains(&pin_len) {
+        return Err(PinError::new(PinErrorKind::InvalidLength, format!("invalid PIN length {} in {:?} PIN block", pin_len, fmt)));
+    }
+
+    // the padding upto the 16th nibble, ISO1 has a random padding and ISO4 a random fill after that
+    let padding = &nibbles[2 + pin_len..16];
+    let valid_padding = match fmt {
+        PinFormat::ISO0 | PinFormat::ISO2 => padding.iter().all(|c| *c == 'F'),
+        PinFormat::ISO3 => padding.iter().all(|c| ('A'..='F').contains(c)),
+        PinFormat::ISO4 => padding.iter().all(|c| *c == 'A'),
+        PinFormat::ISO1 => true
+    };
+    if !valid_padding {
+        if let (PinFormat::ISO0, Some(pan_field)) = (fmt, pan_field) {
+            // the padding (XOR'ed back with F and the PAN field) is the part of the PAN that the PIN block
+            // was built for, if they're all digits this is most likely a PIN block for a different PAN
+            let built_for: Vec<u32> = pan_field.chars().zip(nibbles.iter()).skip(2 + pin_len)
+                .map(|(p, c)| p.to_digit(16).unwrap() ^ c.to_digit(16).unwrap() ^ 0xF).collect();
+            if built_for.iter().all(|d| *d < 10) {
+                return Err(PinError::new(PinErrorKind::PanMismatch, format!("{:?} PIN block was built for a different PAN", fmt)));
             }
         }
-        _ => {
-            Err(PinError { msg: format!("{:?} is not supported yet.", fmt) })
-        }
+        return Err(PinError::new(PinErrorKind::InvalidPadding, format!("invalid padding in {:?} PIN block", fmt)));
+    }
+
+    let pin: String = nibbles[2..2 + pin_len].iter().collect();
+    if !pin.chars().all(|c| c.is_ascii_digit()) {
+        return Err(PinError::new(PinErrorKind::InvalidFormat, format!("invalid PIN digits in {:?} PIN block", fmt)));
     }
+    Ok(pin)
+}
+
+/// Returns the PAN field of a ISO0/ISO3 PIN block - 0000 followed by the rightmost 12 digits of the PAN
+/// excluding the check digit
+fn pan_field_12(pan: &str) -> Result<String, PinError> {
+    if pan.len() < 13 || !pan.chars().all(|c| c.is_ascii_digit()) {
+        return Err(PinError::new(PinErrorKind::Other, format!("invalid PAN - must be atleast 13 digits, length: {}", pan.len())));
+    }
+    let mut b2 = String::from("0000");
+    b2.push_str(&pan[pan.len() - 13..pan.len() - 1]);
+    Ok(b2)
 }
 
 
@@ -185,8 +289,9 @@ fn pad_8_a2f(data: &mut String) {
 
 #[cfg(test)]
 mod tests {
-    use crate::crypto::pin::{generate_pin_block, verify_pin};
-    use crate::crypto::pin::PinFormat::{ISO0, ISO1, ISO2, ISO3};
+    use crate::crypto::pin::{generate_pin_block, verify_pin, iso4_pin_block, iso4_pan_field, iso4_pin_field, translate_pin_block, decode_pin_block, PinErrorKind};
+    use crate::crypto::tdes_encrypt;
+    use crate::crypto::pin::PinFormat::{ISO0, ISO1, ISO2, ISO3, ISO4};
 
     const KEY_STR: &str = "e0f4543f3e2a2c5ffc7e5e5a222e3e4d";
 
@@ -305,4 +410,123 @@ mod tests {
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_iso4_known_answer() {
+        // (key, PIN, PAN, random fill, plain text PIN field, PAN field, PIN block) - the inputs of the first vector are
+        // those of the EFTlab "ISO 9564 PIN block format 4" example. The PIN and PAN fields are as laid out in
+        // ISO 9564-1:2017 9.4.2 (a PAN shorter than 12 digits is left padded with zeros, a longer one has its length
+        // minus 12 in the first nibble) and the block is AES(K, AES(K, PIN field) ^ PAN field), with AES itself
+        // checked against the FIPS-197 vectors in crypto::tests
+        let vectors = [
+            ("C1D0F8FB4958670DBA40AB1F3752EF0D", "1234", "432198765432109870", "2F69ADDE2E9E7ACE",
+             "441234AAAAAAAAAA2F69ADDE2E9E7ACE", "64321987654321098700000000000000", "7919AF472DC746FEBD159F1105FC1DA4"),
+            ("00112233445566778899AABBCCDDEEFF0011223344556677", "98765", "123456", "FEDCBA9876543210",
+             "4598765AAAAAAAAAFEDCBA9876543210", "00000001234560000000000000000000", "0FD7FDC49E74D9B1654B8D76E4E36EA3"),
+            ("00112233445566778899AABBCCDDEEFF", "0000", "123456789012", "1111111111111111",
+             "440000AAAAAAAAAA1111111111111111", "01234567890120000000000000000000", "55929A5F839654A4255BA8EB52F9245D"),
+            ("00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF", "123456789012", "1234567890123456789", "0123456789ABCDEF",
+             "4C123456789012AA0123456789ABCDEF", "71234567890123456789000000000000", "0E673D208A7134849AD44C8FF8C0FC84"),
+        ];
+
+        for (key, pin, pan, fill, pin_field, pan_field, expected) in vectors.iter() {
+            let key = hex::decode(key).unwrap();
+            let mut f = [0u8; 8];
+            f.copy_from_slice(hex::decode(fill).unwrap().as_slice());
+
+            assert_eq!(hex::encode_upper(iso4_pan_field(pan).unwrap()), *pan_field);
+            let block = iso4_pin_block(pin, pan, &key, &f).unwrap();
+            assert_eq!(hex::encode_upper(&block), *expected);
+            assert_eq!(hex::encode_upper(iso4_pin_field(&block, pan, &key).unwrap()), *pin_field);
+            assert!(verify_pin(&ISO4, pin, &block, pan, &key).unwrap());
+            assert!(!verify_pin(&ISO4, "9999", &block, pan, &key).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_invalid_pin() {
+        let key = hex::decode(KEY_STR).unwrap();
+        for fmt in [ISO0, ISO1, ISO2, ISO3].iter() {
+            assert_eq!(generate_pin_block(fmt, "1234567890123", "4111111111111111", &key).unwrap_err().kind, PinErrorKind::InvalidLength);
+            assert_eq!(generate_pin_block(fmt, "123456789012345", "4111111111111111", &key).unwrap_err().kind, PinErrorKind::InvalidLength);
+            assert_eq!(generate_pin_block(fmt, "12a4", "4111111111111111", &key).unwrap_err().kind, PinErrorKind::InvalidFormat);
+        }
+    }
+
+    #[test]
+    fn test_iso4() {
+        let key = hex::decode("00112233445566778899AABBCCDDEEFF").unwrap();
+        let p = generate_pin_block(&ISO4, "1234", "4111111111111111", &key).unwrap();
+        assert_eq!(p.len(), 16);
+        assert!(verify_pin(&ISO4, "1234", &p, "4111111111111111", &key).unwrap());
+
+        // the PIN field has a random fill
+        assert_ne!(p, generate_pin_block(&ISO4, "1234", "4111111111111111", &key).unwrap());
+
+        assert!(generate_pin_block(&ISO4, "123", "4111111111111111", &key).is_err());
+        assert!(generate_pin_block(&ISO4, "1234", "4111111111111111", &key[0..8]).is_err());
+    }
+
+    #[test]
+    fn test_translate_pin_block() {
+        let acq_key = hex::decode(KEY_STR).unwrap();
+        let iss_key = hex::decode("00112233445566778899AABBCCDDEEFF").unwrap();
+        let pan = "4111111111111111";
+
+        let in_block = generate_pin_block(&ISO1, "4321", pan, &acq_key).unwrap();
+        let out_block = translate_pin_block(&ISO1, &acq_key, &ISO0, &iss_key, &in_block, pan).unwrap();
+        assert!(verify_pin(&ISO0, "4321", &out_block, pan, &iss_key).unwrap());
+
+        let out_block = translate_pin_block(&ISO0, &iss_key, &ISO4, &iss_key, &out_block, pan).unwrap();
+        assert_eq!(out_block.len(), 16);
+        assert!(verify_pin(&ISO4, "4321", &out_block, pan, &iss_key).unwrap());
+
+        let out_block = translate_pin_block(&ISO4, &iss_key, &ISO3, &acq_key, &out_block, pan).unwrap();
+        assert!(verify_pin(&ISO3, "4321", &out_block, pan, &acq_key).unwrap());
+
+        // a block under a different key doesn't decode
+        let in_block = generate_pin_block(&ISO2, "4321", pan, &acq_key).unwrap();
+        assert!(translate_pin_block(&ISO2, &iss_key, &ISO0, &acq_key, &in_block, pan).is_err());
+    }
+
+    #[test]
+    fn test_decode_pin_block() {
+        let key = hex::decode(KEY_STR).unwrap();
+        let pan = "4111111111111111";
+
+        let p = generate_pin_block(&ISO0, "123456", pan, &key).unwrap();
+        assert_eq!(decode_pin_block(&ISO0, &p, pan, &key).unwrap(), "123456");
+        assert_eq!(decode_pin_block(&ISO0, &p, "5500000000000004", &key).unwrap_err().kind, PinErrorKind::PanMismatch);
+        assert_eq!(decode_pin_block(&ISO3, &p, pan, &key).unwrap_err().kind, PinErrorKind::InvalidFormat);
+        assert_eq!(decode_pin_block(&ISO0, &p, "4111", &key).unwrap_err().kind, PinErrorKind::Other);
+        assert_eq!(decode_pin_block(&ISO0, &p[0..4], pan, &key).unwrap_err().kind, PinErrorKind::Other);
+
+        let p = generate_pin_block(&ISO3, "1234", pan, &key).unwrap();
+        assert_eq!(decode_pin_block(&ISO3, &p, pan, &key).unwrap(), "1234");
+
+        // ISO2 PIN blocks with an invalid structure
+        let block = |pin_field: &str| tdes_encrypt(&hex::decode(pin_field).unwrap(), &key).unwrap();
+        assert_eq!(decode_pin_block(&ISO2, &block("241234FFFFFFFFFF"), pan, &key).unwrap(), "1234");
+        assert_eq!(decode_pin_block(&ISO2, &block("541234FFFFFFFFFF"), pan, &key).unwrap_err().kind, PinErrorKind::InvalidFormat);
+        assert_eq!(decode_pin_block(&ISO2, &block("23123FFFFFFFFFFF"), pan, &key).unwrap_err().kind, PinErrorKind::InvalidLength);
+        assert_eq!(decode_pin_block(&ISO2, &block("2D1234567890123F"), pan, &key).unwrap_err().kind, PinErrorKind::InvalidLength);
+        assert_eq!(decode_pin_block(&ISO2, &block("241234FFFFFFFFF0"), pan, &key).unwrap_err().kind, PinErrorKind::InvalidPadding);
+        assert_eq!(decode_pin_block(&ISO2, &block("2412A4FFFFFFFFFF"), pan, &key).unwrap_err().kind, PinErrorKind::InvalidFormat);
+        assert!(verify_pin(&ISO2, "1234", &block("2312A4FFFFFFFFFF"), pan, &key).is_err());
+    }
+
+    #[test]
+    fn test_key_lengths() {
+        let pan = "4111111111111111";
+        let k3 = hex::decode("0123456789abcdeffedcba987654321089abcdef01234567").unwrap();
+        for fmt in [ISO0, ISO1, ISO2, ISO3].iter() {
+            let p = generate_pin_block(fmt, "1234", pan, &k3).unwrap();
+            assert!(verify_pin(fmt, "1234", &p, pan, &k3).unwrap());
+        }
+
+        // a invalid key length is an error (and not a panic)
+        let p = generate_pin_block(&ISO0, "1234", pan, &k3[0..12]);
+        assert_eq!(p.unwrap_err().kind, PinErrorKind::Other);
+        assert!(decode_pin_block(&ISO4, &[0; 16], pan, &k3[0..20]).is_err());
+    }
+}
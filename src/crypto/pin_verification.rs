@@ -0,0 +1,139 @@
+//! This module contains the PIN verification methods used by issuers to verify a (clear) PIN against the PIN
+//! verification data of a card - IBM 3624 PIN offsets and Visa PVV's
+
+use crate::crypto::pin::{PinError, PinErrorKind};
+use crate::crypto::Cipher;
+
+/// This enum represents the PIN verification data of a card (as stored by the issuer)
+#[derive(Debug, Clone, PartialEq)]
+pub enum PinVerificationData {
+    /// IBM 3624 - the validation data (16H, usually from the PAN), the decimalization table (16N) and the PIN offset,
+    /// whose length is the number of PIN digits that are checked
+    Ibm3624 { validation_data: String, dec_table: String, offset: String },
+    /// Visa PVV - the PVK index (0 to 9) and the PVV (4N)
+    VisaPvv { pvki: u8, pvv: String },
+}
+
+fn check_digits(name: &str, val: &str, min: usize, max: usize) -> Result<(), PinError> {
+    if val.len() < min || val.len() > max || !val.chars().all(|c| c.is_ascii_digit()) {
+        return Err(PinError::new(PinErrorKind::InvalidFormat, format!("invalid {} - must be {} to {} digits: {}", name, min, max, val)));
+    }
+    Ok(())
+}
+
+/// Returns val encrypted under pvk (a DES or TDES key) as a hex string
+fn encrypt(pvk: &[u8], val: &str) -> Result<String, PinError> {
+    let data = hex::decode(val).map_err(|e| PinError::new(PinErrorKind::Other, format!("invalid data {}: {}", val, e)))?;
+    Ok(hex::encode_upper(Cipher::tdes(pvk)?.encrypt_ecb(&data, pvk)?))
+}
+
+/// Returns the IBM 3624 natural PIN of pin_len digits i.e the validation data encrypted under pvk and decimalized with
+/// dec_table
+pub fn ibm3624_natural_pin(pvk: &[u8], validation_data: &str, dec_table: &str, pin_len: usize) -> Result<String, PinError> {
+    check_digits("decimalization table", dec_table, 16, 16)?;
+    if validation_data.len() != 16 {
+        return Err(PinError::new(PinErrorKind::Other, format!("invalid validation data - must be 16 hex digits: {}", validation_data)));
+    }
+    if !(4..=16).contains(&pin_len) {
+        return Err(PinError::new(PinErrorKind::InvalidLength, format!("invalid PIN length: {}", pin_len)));
+    }
+
+    let dec_table = dec_table.as_bytes();
+    Ok(encrypt(pvk, validation_data)?.chars().take(pin_len).map(|c| dec_table[c.to_digit(16).unwrap() as usize] as char).collect())
+}
+
+/// Returns the IBM 3624 offset of pin i.e the digits of pin minus the digits of the natural PIN (modulo 10)
+pub fn ibm3624_offset(pvk: &[u8], validation_data: &str, dec_table: &str, pin: &str) -> Result<String, PinError> {
+    check_digits("PIN", pin, 4, 12)?;
+    let natural_pin = ibm3624_natural_pin(pvk, validation_data, dec_table, pin.len())?;
+    Ok(pin.bytes().zip(natural_pin.bytes()).map(|(p, n)| (b'0' + (p + 10 - n) % 10) as char).collect())
+}
+
+/// Verifies pin against the IBM 3624 offset, the leftmost digits of pin (as many as the offset) are checked
+pub fn ibm3624_verify(pvk: &[u8], validation_data: &str, dec_table: &str, pin: &str, offset: &str) -> Result<bool, PinError> {
+    check_digits("PIN", pin, 4, 12)?;
+    check_digits("offset", offset, 4, pin.len().max(4))?;
+    let expected = ibm3624_offset(pvk, validation_data, dec_table, pin)?;
+    Ok(expected[0..offset.len()] == *offset)
+}
+
+/// Returns the Visa PVV - the decimal digits (and then the hex digits minus 10) of the transformed security parameter
+/// (the rightmost 11 digits of the PAN excluding the check digit, the PVK index and the leftmost 4 PIN digits) encrypted
+/// under the (double length) pvk
+pub fn visa_pvv(pvk: &[u8], pan: &str, pvki: u8, pin: &str) -> Result<String, PinError> {
+    check_digits("PIN", pin, 4, 12)?;
+    check_digits("PAN", pan, 12, 19)?;
+    if pvki > 9 {
+        return Err(PinError::new(PinErrorKind::Other, format!("invalid PVK index: {}", pvki)));
+    }
+
+    let tsp = format!("{}{}{}", &pan[pan.len() - 12..pan.len() - 1], pvki, &pin[0..4]);
+    let res = encrypt(pvk, &tsp)?;
+    let digits = res.chars().filter(|c| c.is_ascii_digit());
+    let letters = res.chars().filter(|c| !c.is_ascii_digit()).map(|c| std::char::from_digit(c.to_digit(16).unwrap() - 10, 10).unwrap());
+    Ok(digits.chain(letters).take(4).collect())
+}
+
+/// Verifies pin against the Visa PVV
+pub fn visa_pvv_verify(pvk: &[u8], pan: &str, pvki: u8, pin: &str, pvv: &str) -> Result<bool, PinError> {
+    check_digits("PVV", pvv, 4, 4)?;
+    Ok(visa_pvv(pvk, pan, pvki, pin)? == pvv)
+}
+
+/// Verifies pin (of the card with pan) against the PIN verification data with pvk
+pub fn verify(pvk: &[u8], data: &PinVerificationData, pin: &str, pan: &str) -> Result<bool, PinError> {
+    match data {
+        PinVerificationData::Ibm3624 { validation_data, dec_table, offset } => ibm3624_verify(pvk, validation_data, dec_table, pin, offset),
+        PinVerificationData::VisaPvv { pvki, pvv } => visa_pvv_verify(pvk, pan, *pvki, pin, pvv)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::pin::PinErrorKind;
+    use crate::crypto::pin_verification::{ibm3624_natural_pin, ibm3624_offset, ibm3624_verify, verify, visa_pvv, visa_pvv_verify, PinVerificationData};
+
+    #[test]
+    fn test_ibm3624() {
+        let pvk = hex::decode("0123456789ABCDEFFEDCBA9876543210").unwrap();
+        let dec_table = "0123456789012345";
+
+        assert_eq!(ibm3624_natural_pin(&pvk, "4111111111111111", dec_table, 4).unwrap(), "6939");
+        assert_eq!(ibm3624_offset(&pvk, "4111111111111111", dec_table, "1234").unwrap(), "5305");
+        assert!(ibm3624_verify(&pvk, "4111111111111111", dec_table, "1234", "5305").unwrap());
+        assert!(!ibm3624_verify(&pvk, "4111111111111111", dec_table, "1235", "5305").unwrap());
+        // only the leftmost 4 digits are checked
+        assert!(ibm3624_verify(&pvk, "4111111111111111", dec_table, "12349", "5305").unwrap());
+
+        let pvk = hex::decode("0123456789ABCDEF").unwrap();
+        assert_eq!(ibm3624_natural_pin(&pvk, "4111111111111111", dec_table, 6).unwrap(), "501572");
+        assert_eq!(ibm3624_offset(&pvk, "4111111111111111", dec_table, "123456").unwrap(), "622984");
+
+        assert!(ibm3624_natural_pin(&pvk, "41111111", dec_table, 4).is_err());
+        assert!(ibm3624_natural_pin(&pvk, "4111111111111111", "012345678901234A", 4).is_err());
+        assert_eq!(ibm3624_offset(&pvk, "4111111111111111", dec_table, "123").err().unwrap().kind, PinErrorKind::InvalidFormat);
+        assert!(ibm3624_verify(&pvk, "4111111111111111", dec_table, "1234", "62298").is_err());
+    }
+
+    #[test]
+    fn test_visa_pvv() {
+        let pvk = hex::decode("0123456789ABCDEFFEDCBA9876543210").unwrap();
+
+        assert_eq!(visa_pvv(&pvk, "4111111111111111", 1, "1234").unwrap(), "9464");
+        assert_eq!(visa_pvv(&pvk, "4761739001010010", 1, "1234").unwrap(), "8034");
+        // less than 4 decimal digits in the result
+        assert_eq!(visa_pvv(&pvk, "4111111111111111", 1, "0198").unwrap(), "1225");
+
+        assert!(visa_pvv_verify(&pvk, "4111111111111111", 1, "1234", "9464").unwrap());
+        assert!(!visa_pvv_verify(&pvk, "4111111111111111", 2, "1234", "9464").unwrap());
+        assert!(visa_pvv(&pvk, "4111111111111111", 10, "1234").is_err());
+        assert!(visa_pvv_verify(&pvk, "4111111111111111", 1, "1234", "946").is_err());
+
+        let data = PinVerificationData::VisaPvv { pvki: 1, pvv: "8034".to_string() };
+        assert!(verify(&pvk, &data, "1234", "4761739001010010").unwrap());
+        let data = PinVerificationData::Ibm3624 { validation_data: "4111111111111111".to_string(), dec_table: "0123456789012345".to_string(), offset: "5305".to_string() };
+        assert!(verify(&pvk, &data, "1234", "4111111111111111").unwrap());
+        assert!(!verify(&pvk, &data, "4321", "4111111111111111").unwrap());
+    }
+}
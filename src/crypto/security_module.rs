@@ -0,0 +1,158 @@
+//! This module defines the security module abstraction used for the PIN and MAC operations on a IsoMsg, so that
+//! keys can be kept in a (hardware) security module. Keys are referred to by strings - clear keys (hex) for the
+//! SoftwareSecurityModule and typically keys encrypted under a local master key for a HSM
+
+use crate::crypto::cvv;
+use crate::crypto::dukpt::{self, KeyUsage};
+use crate::crypto::emv::{self, ArpcMethod, SessionKeyDerivation};
+use crate::crypto::mac::{self, MacAlgo, PaddingType};
+use crate::crypto::pin::{self, PinFormat};
+use crate::crypto::pin_verification::{self, PinVerificationData};
+use crate::crypto::{ct_eq, Cipher, CryptoError};
+
+/// This trait represents a security module that performs PIN and MAC operations with the keys it manages
+pub trait SecurityModule: Send + Sync {
+    /// Returns the PIN block of pin (and pan) in fmt encrypted under key
+    fn generate_pin_block(&self, fmt: &PinFormat, pin: &str, pan: &str, key: &str) -> Result<Vec<u8>, CryptoError>;
+
+    /// Returns true if the PIN block (in fmt encrypted under key) contains expected_pin
+    fn verify_pin(&self, fmt: &PinFormat, expected_pin: &str, pin_block: &[u8], pan: &str, key: &str) -> Result<bool, CryptoError>;
+
+    /// Translates the PIN block from in_fmt under in_key to out_fmt under out_key
+    fn translate_pin_block(&self, in_fmt: &PinFormat, in_key: &str, out_fmt: &PinFormat, out_key: &str, pin_block: &[u8], pan: &str) -> Result<Vec<u8>, CryptoError>;
+
+    /// Returns the (untruncated) MAC on data
+    fn generate_mac(&self, algo: &MacAlgo, padding: &PaddingType, data: &[u8], key: &str) -> Result<Vec<u8>, CryptoError>;
+
+    /// Returns true if the (possibly truncated) MAC matches the MAC on data
+    fn verify_mac(&self, algo: &MacAlgo, padding: &PaddingType, data: &[u8], key: &str, mac: &[u8]) -> Result<bool, CryptoError>;
+
+    /// Derives the DUKPT working key for usage from the BDK and the KSN - TDES DUKPT for a 10 byte KSN and AES DUKPT
+    /// for a 12 byte KSN, with a working key of key_type (or of the type of the BDK if None)
+    fn derive_key(&self, bdk: &str, ksn: &[u8], usage: KeyUsage, key_type: Option<Cipher>) -> Result<String, CryptoError>;
+
+    /// Returns true if the PIN in the PIN block (in fmt encrypted under key) matches the PIN verification data (like
+    /// a IBM 3624 offset or a Visa PVV) of the card with pvk
+    fn verify_pin_data(&self, _fmt: &PinFormat, _pin_block: &[u8], _pan: &str, _key: &str, _pvk: &str, _data: &PinVerificationData) -> Result<bool, CryptoError> {
+        Err(CryptoError { msg: "PIN verification data is not supported by the security module".to_string() })
+    }
+
+    /// Returns true if cvv is the CVV of the card (pan, expiry as YYMM and service code) with cvk
+    fn verify_cvv(&self, _pan: &str, _expiry: &str, _service_code: &str, _cvk: &str, _cvv: &str) -> Result<bool, CryptoError> {
+        Err(CryptoError { msg: "CVV verification is not supported by the security module".to_string() })
+    }
+
+    /// Derives the EMV (application cryptogram) session key for the card (pan and PAN sequence number) and the ATC
+    /// from the issuer master key imk
+    fn derive_emv_session_key(&self, _imk: &str, _pan: &str, _psn: &str, _atc: &[u8], _skd: SessionKeyDerivation) -> Result<String, CryptoError> {
+        Err(CryptoError { msg: "EMV key derivation is not supported by the security module".to_string() })
+    }
+
+    /// Returns the ARPC for the ARQC with the session key sk
+    fn generate_arpc(&self, _sk: &str, _arqc: &[u8], _method: &ArpcMethod) -> Result<Vec<u8>, CryptoError> {
+        Err(CryptoError { msg: "ARPC generation is not supported by the security module".to_string() })
+    }
+}
+
+/// This struct represents a security module with clear (hex) keys, implemented with the functions of the
+/// crypto module
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SoftwareSecurityModule {}
+
+fn clear_key(key: &str) -> Result<Vec<u8>, CryptoError> {
+    hex::decode(key).map_err(|e| CryptoError { msg: format!("invalid key: {}", e) })
+}
+
+impl SecurityModule for SoftwareSecurityModule {
+    fn generate_pin_block(&self, fmt: &PinFormat, pin: &str, pan: &str, key: &str) -> Result<Vec<u8>, CryptoError> {
+        Ok(pin::generate_pin_block(fmt, pin, pan, &clear_key(key)?)?)
+    }
+
+    fn verify_pin(&self, fmt: &PinFormat, expected_pin: &str, pin_block: &[u8], pan: &str, key: &str) -> Result<bool, CryptoError> {
+        Ok(pin::verify_pin(fmt, expected_pin, &pin_block.to_vec(), pan, &clear_key(key)?)?)
+    }
+
+    fn translate_pin_block(&self, in_fmt: &PinFormat, in_key: &str, out_fmt: &PinFormat, out_key: &str, pin_block: &[u8], pan: &str) -> Result<Vec<u8>, CryptoError> {
+        Ok(pin::translate_pin_block(in_fmt, &clear_key(in_key)?, out_fmt, &clear_key(out_key)?, pin_block, pan)?)
+    }
+
+    fn generate_mac(&self, algo: &MacAlgo, padding: &PaddingType, data: &[u8], key: &str) -> Result<Vec<u8>, CryptoError> {
+        Ok(mac::generate_mac(algo, padding, data, &clear_key(key)?)?)
+    }
+
+    fn verify_mac(&self, algo: &MacAlgo, padding: &PaddingType, data: &[u8], key: &str, mac: &[u8]) -> Result<bool, CryptoError> {
+        let res = mac::truncate_mac(mac::generate_mac(algo, padding, data, &clear_key(key)?)?, mac.len())?;
+        Ok(ct_eq(&res, mac))
+    }
+
+    fn derive_key(&self, bdk: &str, ksn: &[u8], usage: KeyUsage, key_type: Option<Cipher>) -> Result<String, CryptoError> {
+        let bdk = clear_key(bdk)?;
+        let key = match (ksn.len(), key_type) {
+            (10, None) | (10, Some(Cipher::TdesEde2)) => dukpt::derive_key(&bdk, ksn, usage)?,
+            (10, Some(c)) => return Err(CryptoError { msg: format!("{:?} working key can't be derived with TDES DUKPT", c) }),
+            (12, Some(c)) => dukpt::derive_aes_key(&bdk, ksn, usage, c)?,
+            (12, None) => dukpt::derive_aes_key(&bdk, ksn, usage, Cipher::aes(&bdk)?)?,
+            _ => return Err(CryptoError { msg: format!("invalid KSN length: {} (expected 10 or 12)", ksn.len()) })
+        };
+        Ok(hex::encode(key))
+    }
+
+    fn verify_pin_data(&self, fmt: &PinFormat, pin_block: &[u8], pan: &str, key: &str, pvk: &str, data: &PinVerificationData) -> Result<bool, CryptoError> {
+        let pin = pin::decode_pin_block(fmt, pin_block, pan, &clear_key(key)?)?;
+        Ok(pin_verification::verify(&clear_key(pvk)?, data, &pin, pan)?)
+    }
+
+    fn verify_cvv(&self, pan: &str, expiry: &str, service_code: &str, cvk: &str, cvv: &str) -> Result<bool, CryptoError> {
+        cvv::verify_cvv(&clear_key(cvk)?, pan, expiry, service_code, cvv)
+    }
+
+    fn derive_emv_session_key(&self, imk: &str, pan: &str, psn: &str, atc: &[u8], skd: SessionKeyDerivation) -> Result<String, CryptoError> {
+        let mk = emv::derive_master_key(&clear_key(imk)?, pan, psn)?;
+        Ok(hex::encode(emv::derive_session_key(&mk, atc, skd)?))
+    }
+
+    fn generate_arpc(&self, sk: &str, arqc: &[u8], method: &ArpcMethod) -> Result<Vec<u8>, CryptoError> {
+        emv::generate_arpc(&clear_key(sk)?, arqc, method)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::dukpt::KeyUsage;
+    use crate::crypto::mac::{MacAlgo, PaddingType};
+    use crate::crypto::pin::PinFormat;
+    use crate::crypto::security_module::{SecurityModule, SoftwareSecurityModule};
+    use crate::crypto::Cipher;
+
+    #[test]
+    fn test_software_security_module() {
+        let sm = SoftwareSecurityModule {};
+        let key = "e0f4543f3e2a2c5ffc7e5e5a222e3e4d";
+
+        let pin_block = sm.generate_pin_block(&PinFormat::ISO0, "1234", "4111111111111111", key).unwrap();
+        assert!(sm.verify_pin(&PinFormat::ISO0, "1234", &pin_block, "4111111111111111", key).unwrap());
+        assert!(!sm.verify_pin(&PinFormat::ISO0, "4321", &pin_block, "4111111111111111", key).unwrap());
+
+        let out_key = "0123456789abcdeffedcba9876543210";
+        let res = sm.translate_pin_block(&PinFormat::ISO0, key, &PinFormat::ISO3, out_key, &pin_block, "4111111111111111").unwrap();
+        assert!(sm.verify_pin(&PinFormat::ISO3, "1234", &res, "4111111111111111", out_key).unwrap());
+
+        let mac = sm.generate_mac(&MacAlgo::RetailMac, &PaddingType::Type1, b"Now is the time for all ", out_key).unwrap();
+        assert_eq!(hex::encode_upper(&mac), "A1C72E74EA3FA9B6");
+        assert!(sm.verify_mac(&MacAlgo::RetailMac, &PaddingType::Type1, b"Now is the time for all ", out_key, &mac[0..4]).unwrap());
+        assert!(!sm.verify_mac(&MacAlgo::RetailMac, &PaddingType::Type1, b"Now is the time for it", out_key, &mac).unwrap());
+        assert!(sm.generate_mac(&MacAlgo::RetailMac, &PaddingType::Type1, b"", "xyz").is_err());
+
+        let bdk = "0123456789ABCDEFFEDCBA9876543210";
+        let ksn = hex::decode("FFFF9876543210E00001").unwrap();
+        assert_eq!(sm.derive_key(bdk, &ksn, KeyUsage::Pin, None).unwrap(), "042666b49184cf5c68de9628d0397b36");
+        assert!(sm.derive_key(bdk, &ksn, KeyUsage::Pin, Some(Cipher::Aes128)).is_err());
+
+        let bdk = "FEDCBA9876543210F1F1F1F1F1F1F1F1";
+        let ksn = hex::decode("123456789012345600000001").unwrap();
+        assert_eq!(sm.derive_key(bdk, &ksn, KeyUsage::Pin, None).unwrap(), "af8cb133a78f8dc2d1359f18527593fb");
+        assert_eq!(sm.derive_key(bdk, &ksn, KeyUsage::Pin, Some(Cipher::TdesEde2)).unwrap(), "630c706d9546e47d4449313f61c4d4ab");
+        assert!(sm.derive_key(bdk, &ksn[0..11], KeyUsage::Pin, None).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+//! This module implements TR-31 (ANSI X9.143) key blocks of version B (TDES key block protection key) and D (AES
+//! key block protection key) - parsing of the header, wrapping and unwrapping (with verification of the MAC) of keys
+
+use std::fmt::{Display, Formatter};
+
+use rand;
+use super::rand::Rng;
+
+use crate::crypto::mac::{generate_mac, MacAlgo, PaddingType};
+use crate::crypto::{Cipher, CryptoError};
+
+/// This struct represents a optional block of a key block header (like the KSN "KS" of a BDK)
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionalBlock {
+    /// ID of the block (like "KS" or "PB")
+    pub id: String,
+    pub data: String,
+}
+
+/// This struct represents a TR-31 key block header
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyBlockHeader {
+    /// 'B' (TDES key block protection key) or 'D' (AES key block protection key)
+    pub version: char,
+    /// Key usage like "P0" (PIN encryption), "M3" (ISO 9797-1 MAC algorithm 3), "K0" (key encryption) or "B0" (BDK)
+    pub key_usage: String,
+    /// Algorithm of the key - 'T' (TDES), 'A' (AES), 'D' (DES) or 'H' (HMAC)
+    pub algorithm: char,
+    /// Mode of use like 'E' (encrypt only), 'D' (decrypt only), 'B' (both), 'G' (generate only), 'V' (verify only),
+    /// 'C' (generate and verify), 'X' (key derivation) or 'N' (no restrictions)
+    pub mode_of_use: char,
+    /// Key version number ("00" if unused)
+    pub key_version: String,
+    /// 'E' (exportable under a trusted key), 'N' (non-exportable) or 'S' (sensitive)
+    pub exportability: char,
+    pub optional_blocks: Vec<OptionalBlock>,
+}
+
+impl KeyBlockHeader {
+    /// Creates a new header without a key version and optional blocks for a non-exportable key
+    pub fn new(version: char, key_usage: &str, algorithm: char, mode_of_use: char) -> KeyBlockHeader {
+        KeyBlockHeader {
+            version,
+            key_usage: key_usage.to_string(),
+            algorithm,
+            mode_of_use,
+            key_version: "00".to_string(),
+            exportability: 'N',
+            optional_blocks: vec![],
+        }
+    }
+
+    /// Parses the header of key_block and returns it along with its length
+    pub fn parse(key_block: &str) -> Result<(KeyBlockHeader, usize), CryptoError> {
+        if !key_block.is_ascii() || key_block.len() < 16 {
+            return Err(CryptoError { msg: format!("invalid key block: {}", key_block) });
+        }
+
+        let version = key_block.chars().next().unwrap();
+        block_size(version)?;
+
+        match key_block[1..5].parse::<usize>() {
+            Ok(len) if len == key_block.len() => {}
+            _ => return Err(CryptoError { msg: format!("invalid key block length: {} (actual: {})", &key_block[1..5], key_block.len()) })
+        }
+        let num_blocks = key_block[12..14].parse::<usize>().map_err(|_| CryptoError { msg: format!("invalid number of optional blocks: {}", &key_block[12..14]) })?;
+
+        let mut header = KeyBlockHeader {
+            version,
+            key_usage: key_block[5..7].to_string(),
+            algorithm: key_block.chars().nth(7).unwrap(),
+            mode_of_use: key_block.chars().nth(8).unwrap(),
+            key_version: key_block[9..11].to_string(),
+            exportability: key_block.chars().nth(11).unwrap(),
+            optional_blocks: vec![],
+        };
+
+        let mut pos = 16;
+        for _ in 0..num_blocks {
+            let (block, len) = parse_optional_block(&key_block[pos..])?;
+            header.optional_blocks.push(block);
+            pos += len;
+        }
+        Ok((header, pos))
+    }
+
+    /// Returns the header for a key block of length key_block_len, a padding block ("PB") is added (if there
+    /// isn't one) when the optional blocks are not a multiple of the block size
+    fn format(&self, key_block_len: usize) -> String {
+        let mut blocks: String = self.optional_blocks.iter().map(format_optional_block).collect();
+        let mut num_blocks = self.optional_blocks.len();
+
+        let bs = if self.version == 'D' { 16 } else { 8 };
+        if !blocks.len().is_multiple_of(bs) && !self.optional_blocks.iter().any(|b| b.id == "PB") {
+            let mut pad_len = bs - blocks.len() % bs;
+            if pad_len < 4 {
+                pad_len += bs;
+            }
+            blocks.push_str(&format_optional_block(&OptionalBlock { id: "PB".to_string(), data: "0".repeat(pad_len - 4) }));
+            num_blocks += 1;
+        }
+
+        format!("{}{:04}{}{}{}{}{}{:02}00{}", self.version, key_block_len, self.key_usage, self.algorithm, self.mode_of_use,
+                self.key_version, self.exportability, num_blocks, blocks)
+    }
+}
+
+impl Display for KeyBlockHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "version: {}, usage: {}, algorithm: {}, mode of use: {}, key version: {}, exportability: {}", self.version,
+               self.key_usage, self.algorithm, self.mode_of_use, self.key_version, self.exportability)?;
+        self.optional_blocks.iter().try_for_each(|b| write!(f, ", {}: {}", b.id, b.data))
+    }
+}
+
+/// Parses a optional block (ID, length in hex and data) and returns it along with its length
+fn parse_optional_block(data: &str) -> Result<(OptionalBlock, usize), CryptoError> {
+    let invalid = || CryptoError { msg: format!("invalid optional block: {}", data) };
+    if data.len() < 4 {
+        return Err(invalid());
+    }
+
+    let mut len = usize::from_str_radix(&data[2..4], 16).map_err(|_| invalid())?;
+    let mut start = 4;
+    // a extended length - the length of the length followed by the length
+    if len == 0 {
+        let len_len = data.get(4..6).and_then(|l| usize::from_str_radix(l, 16).ok()).ok_or_else(invalid)?;
+        len = data.get(6..6 + len_len).and_then(|l| usize::from_str_radix(l, 16).ok()).ok_or_else(invalid)?;
+        start = 6 + len_len;
+    }
+
+    if len < start || len > data.len() {
+        return Err(invalid());
+    }
+    Ok((OptionalBlock { id: data[0..2].to_string(), data: data[start..len].to_string() }, len))
+}
+
+fn format_optional_block(block: &OptionalBlock) -> String {
+    let len = block.data.len() + 4;
+    if len <= 0xFF {
+        format!("{}{:02X}{}", block.id, len, block.data)
+    } else {
+        format!("{}0004{:04X}{}", block.id, len + 6, block.data)
+    }
+}
+
+/// Returns the block size of the cipher of the key block version
+fn block_size(version: char) -> Result<usize, CryptoError> {
+    match version {
+        'B' => Ok(8),
+        'D' => Ok(16),
+        _ => Err(CryptoError { msg: format!("unsupported key block version: {}", version) })
+    }
+}
+
+/// Returns the cipher of the key block protection key (and the derived keys) of a key block version
+fn kbpk_cipher(version: char, kbpk: &[u8]) -> Result<Cipher, CryptoError> {
+    match (version, Cipher::tdes(kbpk), Cipher::aes(kbpk)) {
+        ('B', Ok(c @ Cipher::TdesEde2), _) | ('B', Ok(c @ Cipher::TdesEde3), _) => Ok(c),
+        ('D', _, Ok(c)) => Ok(c),
+        _ => Err(CryptoError { msg: format!("invalid key block protection key length: {} for version {}", kbpk.len(), version) })
+    }
+}
+
+/// Derives the key block encryption key (KBEK) and the key block authentication key (KBAK) from the key block
+/// protection key with CMAC
+fn derive_keys(cipher: Cipher, kbpk: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+    let (algo, mac_algo) = match cipher {
+        Cipher::TdesEde2 => (0u8, MacAlgo::TdesCmac),
+        Cipher::TdesEde3 => (1, MacAlgo::TdesCmac),
+        Cipher::Aes128 => (2, MacAlgo::AesCmac),
+        Cipher::Aes192 => (3, MacAlgo::AesCmac),
+        _ => (4, MacAlgo::AesCmac),
+    };
+    let bits = (kbpk.len() * 8) as u16;
+
+    let mut keys = vec![];
+    for usage in 0..2u8 {
+        let mut key = vec![];
+        let mut counter = 1u8;
+        while key.len() < kbpk.len() {
+            let data = [counter, 0, usage, 0, 0, algo, (bits >> 8) as u8, bits as u8];
+            key.extend(generate_mac(&mac_algo, &PaddingType::Type1, &data, kbpk).map_err(|e| CryptoError { msg: e.msg })?);
+            counter += 1;
+        }
+        key.truncate(kbpk.len());
+        keys.push(key);
+    }
+    let kbak = keys.pop().unwrap();
+    Ok((keys.pop().unwrap(), kbak))
+}
+
+/// Computes the MAC of a key block - the CMAC (with the KBAK) of the header and the clear key data
+fn key_block_mac(cipher: Cipher, kbak: &[u8], header: &str, key_data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let mac_algo = if cipher.block_size() == 16 { MacAlgo::AesCmac } else { MacAlgo::TdesCmac };
+    let mut data = header.as_bytes().to_vec();
+    data.extend_from_slice(key_data);
+    generate_mac(&mac_algo, &PaddingType::Type1, &data, kbak).map_err(|e| CryptoError { msg: e.msg })
+}
+
+/// Wraps key in a key block with header under the key block protection key (kbpk)
+pub fn wrap(kbpk: &[u8], header: &KeyBlockHeader, key: &[u8]) -> Result<String, CryptoError> {
+    let padding: [u8; 32] = rand::thread_rng().gen();
+    wrap_with_padding(kbpk, header, key, &padding)
+}
+
+fn wrap_with_padding(kbpk: &[u8], header: &KeyBlockHeader, key: &[u8], padding: &[u8]) -> Result<String, CryptoError> {
+    let bs = block_size(header.version)?;
+    let cipher = kbpk_cipher(header.version, kbpk)?;
+    if key.is_empty() || key.len() > 64 {
+        return Err(CryptoError { msg: format!("invalid key length: {}", key.len()) });
+    }
+
+    // the key length (in bits), the key and a random padding upto the block size
+    let mut key_data = ((key.len() * 8) as u16).to_be_bytes().to_vec();
+    key_data.extend_from_slice(key);
+    let pad_len = (bs - key_data.len() % bs) % bs;
+    key_data.extend_from_slice(&padding[0..pad_len]);
+
+    let mac_len = bs;
+    let header_len = header.format(0).len();
+    let header = header.format(header_len + 2 * (key_data.len() + mac_len));
+
+    let (kbek, kbak) = derive_keys(cipher, kbpk)?;
+    let mac = key_block_mac(cipher, &kbak, &header, &key_data)?;
+    let enc_data = cipher.encrypt_cbc(&key_data, &kbek, &mac)?;
+
+    Ok(format!("{}{}{}", header, hex::encode_upper(enc_data), hex::encode_upper(mac)))
+}
+
+/// Unwraps key_block with the key block protection key (kbpk) after verifying its MAC and returns the header
+/// and the key
+pub fn unwrap(kbpk: &[u8], key_block: &str) -> Result<(KeyBlockHeader, Vec<u8>), CryptoError> {
+    let (header, header_len) = KeyBlockHeader::parse(key_block)?;
+    let bs = block_size(header.version)?;
+    let cipher = kbpk_cipher(header.version, kbpk)?;
+
+    let body = &key_block[header_len..];
+    let mac_len = 2 * bs;
+    if body.len() <= mac_len || !(body.len() - mac_len).is_multiple_of(2 * bs) {
+        return Err(CryptoError { msg: format!("invalid key block data length: {}", body.len()) });
+    }
+    let invalid_hex = |e: hex::FromHexError| CryptoError { msg: format!("invalid key block data: {}", e) };
+    let enc_data = hex::decode(&body[0..body.len() - mac_len]).map_err(invalid_hex)?;
+    let mac = hex::decode(&body[body.len() - mac_len..]).map_err(invalid_hex)?;
+
+    let (kbek, kbak) = derive_keys(cipher, kbpk)?;
+    let key_data = cipher.decrypt_cbc(&enc_data, &kbek, &mac)?;
+    if key_block_mac(cipher, &kbak, &key_block[0..header_len], &key_data)? != mac {
+        return Err(CryptoError { msg: "key block MAC verification failed".to_string() });
+    }
+
+    let key_len = u16::from_be_bytes([key_data[0], key_data[1]]) as usize;
+    if !key_len.is_multiple_of(8) || key_len / 8 > key_data.len() - 2 {
+        return Err(CryptoError { msg: format!("invalid key length in key block: {} bits", key_len) });
+    }
+    Ok((header, key_data[2..2 + key_len / 8].to_vec()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::tr31::{unwrap, wrap, wrap_with_padding, KeyBlockHeader, OptionalBlock};
+
+    // the padding of the examples in ANSI X9.143 (the TDES key block uses the first 6 bytes)
+    const PADDING: &str = "1C2965473CE206BB855B01533782";
+
+    #[test]
+    fn test_version_b() {
+        let kbpk = hex::decode("89E88CF7931444F334BD7547FC3F380C").unwrap();
+        let key = hex::decode("F039121BEC83D26B169BDCD5B22AAF8F").unwrap();
+        let header = KeyBlockHeader::new('B', "P0", 'T', 'E');
+
+        let kb = wrap_with_padding(&kbpk, &header, &key, &hex::decode(PADDING).unwrap()).unwrap();
+        assert_eq!(kb, "B0080P0TE00N00000C77B158622112A976F16671BA0AF8EC1415825FF26C8D81D634E3646AF77DAF");
+        assert_eq!(unwrap(&kbpk, &kb).unwrap(), (header, key.clone()));
+
+        // a 3 key TDES KBPK
+        let kbpk = hex::decode("0123456789ABCDEFFEDCBA98765432100123456789ABCDEF").unwrap();
+        let key = hex::decode("0123456789ABCDEFFEDCBA9876543210").unwrap();
+        let (header, res) = unwrap(&kbpk, "B0080M3TC00N00009182AF08B2DE8104D4EA05FA2B3A20E6FE19930288506C7CDAF3A4B89D0CD92B").unwrap();
+        assert_eq!(res, key);
+        assert_eq!((header.key_usage.as_str(), header.algorithm, header.mode_of_use), ("M3", 'T', 'C'));
+
+        // a tampered key block
+        assert!(unwrap(&kbpk, "B0080M3TC00N00009182AF08B2DE8104D4EA05FA2B3A20E6FE19930288506C7CDAF3A4B89D0CD92C").is_err());
+        assert!(unwrap(&kbpk, "B0080M3TC00E00009182AF08B2DE8104D4EA05FA2B3A20E6FE19930288506C7CDAF3A4B89D0CD92B").is_err());
+    }
+
+    #[test]
+    fn test_version_d() {
+        let kbpk = hex::decode("88E1AB2A2E3DD38C1FA039A536500CC8A87AB9D62DC92C01058FA79F44657DE6").unwrap();
+        let key = hex::decode("3F419E1CB7079442AA37474C2EFBF8B8").unwrap();
+        let mut header = KeyBlockHeader::new('D', "P0", 'A', 'E');
+        header.exportability = 'E';
+
+        let kb = wrap_with_padding(&kbpk, &header, &key, &hex::decode(PADDING).unwrap()).unwrap();
+        assert_eq!(kb, "D0112P0AE00E0000B82679114F470F540165EDFBF7E250FCEA43F810D215F8D207E2E417C07156A27E8E31DA05F7425509593D03A457DC34");
+        assert_eq!(unwrap(&kbpk, &kb).unwrap(), (header, key.clone()));
+
+        // with optional blocks - the KSN and a padding block
+        let mut header = KeyBlockHeader::new('D', "B0", 'A', 'N');
+        header.optional_blocks.push(OptionalBlock { id: "KS".to_string(), data: "00604B120F9292800000".to_string() });
+        let kb = wrap(&kbpk, &header, &key).unwrap();
+        assert!(kb.starts_with("D0144B0AN00N0200KS1800604B120F9292800000PB0800"));
+
+        let (res_header, res) = unwrap(&kbpk, &kb).unwrap();
+        assert_eq!(res, key);
+        assert_eq!(res_header.optional_blocks.len(), 2);
+        assert_eq!(res_header.optional_blocks[0], header.optional_blocks[0]);
+        assert_eq!(res_header.optional_blocks[1].id, "PB");
+
+        // a AES KBPK is required for version D and a TDES KBPK for version B
+        assert!(unwrap(&kbpk[0..24], &kb).is_err());
+        assert!(wrap(&kbpk, &KeyBlockHeader::new('B', "P0", 'T', 'E'), &key).is_err());
+    }
+
+    #[test]
+    fn test_header() {
+        assert!(KeyBlockHeader::parse("B0016P0TE00N0000").is_ok());
+        assert!(KeyBlockHeader::parse("B0017P0TE00N0000").is_err());
+        assert!(KeyBlockHeader::parse("A0016P0TE00N0000").is_err());
+        assert!(KeyBlockHeader::parse("B0016P0TE00N0100").is_err());
+
+        // a extended length optional block
+        let (header, len) = KeyBlockHeader::parse("B0046P0TE00N0100KS0004001E00604B120F9292800000").unwrap();
+        assert_eq!((header.optional_blocks[0].data.as_str(), len), ("00604B120F9292800000", 46));
+
+        let header = KeyBlockHeader::new('D', "M7", 'H', 'C');
+        let kb = wrap(&hex::decode("88E1AB2A2E3DD38C1FA039A536500CC8").unwrap(), &header, &[0x5A; 32]).unwrap();
+        assert_eq!(kb.len(), 16 + 2 * 48 + 32);
+        assert_eq!(header.to_string(), "version: D, usage: M7, algorithm: H, mode of use: C, key version: 00, exportability: N");
+    }
+}
@@ -0,0 +1,164 @@
+//! This module contains the amount type and the (signed) amount formats that can be declared for fields
+//! in the spec and read/written via `IsoMsg::get_amount` and `IsoMsg::set_amount`
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::iso8583::currency::currency;
+use crate::iso8583::IsoError;
+
+/// This enum represents the format of a field value
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum FieldFormat {
+    /// A amount in minor units of the currency (like F4)
+    Amount,
+    /// A amount in minor units of the currency preceded by 'C' (credit) or 'D' (debit) (like F28 or F97)
+    SignedAmount,
+}
+
+/// This struct represents a amount field in a segment
+#[derive(Clone, Debug)]
+pub struct AmountField {
+    /// Name of the field
+    pub name: String,
+    /// Position of the field in the bitmap
+    pub position: u32,
+    /// Length of the field (including the sign)
+    pub len: u32,
+    pub format: FieldFormat,
+    /// Position of the field that holds the ISO 4217 numeric currency code of the amount (like 49)
+    pub currency: Option<u32>,
+}
+
+/// This struct represents a amount as a decimal value i.e minor_units * 10^-exponent
+#[derive(Clone, Debug, PartialEq)]
+pub struct Amount {
+    /// Value in minor units of the currency, negative for a debit
+    pub minor_units: i64,
+    /// Number of digits after the decimal separator
+    pub exponent: u32,
+    /// ISO 4217 numeric currency code (if known)
+    pub currency: Option<String>,
+}
+
+impl Amount {
+    /// Creates a new amount without a currency
+    pub fn new(minor_units: i64, exponent: u32) -> Amount {
+        Amount { minor_units, exponent, currency: None }
+    }
+
+    /// Creates a new amount in the given currency (ISO 4217 numeric or alphabetic code), the exponent
+    /// is that of the currency
+    pub fn in_currency(minor_units: i64, code: &str) -> Result<Amount, IsoError> {
+        match currency(code) {
+            Some(c) => Ok(Amount { minor_units, exponent: c.exponent, currency: Some(c.numeric.to_string()) }),
+            None => Err(IsoError { msg: format!("unknown currency code: {}", code) })
+        }
+    }
+
+    /// Returns true if this is a debit (negative) amount
+    pub fn is_debit(&self) -> bool {
+        self.minor_units < 0
+    }
+
+    /// Returns the amount as a f64 (for display or reporting only)
+    pub fn to_f64(&self) -> f64 {
+        self.minor_units as f64 / 10f64.powi(self.exponent as i32)
+    }
+
+    /// Parses the raw value of a field in the given format
+    pub(in crate::iso8583) fn parse(format: FieldFormat, val: &str, exponent: u32, currency: Option<String>) -> Result<Amount, IsoError> {
+        let (negative, digits) = match format {
+            FieldFormat::Amount => (false, val),
+            FieldFormat::SignedAmount => {
+                match val.chars().next() {
+                    Some('C') => (false, &val[1..]),
+                    Some('D') => (true, &val[1..]),
+                    _ => return Err(IsoError { msg: format!("invalid sign in signed amount: {}", val) })
+                }
+            }
+        };
+
+        if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(IsoError { msg: format!("invalid amount: {}", val) });
+        }
+
+        match digits.parse::<i64>() {
+            Ok(v) => Ok(Amount { minor_units: if negative { -v } else { v }, exponent, currency }),
+            Err(e) => Err(IsoError { msg: format!("invalid amount: {} - {}", val, e) })
+        }
+    }
+
+    /// Formats the amount as the raw value of a field of length len in the given format
+    pub(in crate::iso8583) fn format(&self, format: FieldFormat, len: u32) -> Result<String, IsoError> {
+        let res = match format {
+            FieldFormat::Amount => {
+                if self.is_debit() {
+                    return Err(IsoError { msg: format!("negative amount {} is not allowed in a unsigned amount field", self) });
+                }
+                format!("{:0width$}", self.minor_units, width = len as usize)
+            }
+            FieldFormat::SignedAmount => {
+                let sign = if self.is_debit() { 'D' } else { 'C' };
+                format!("{}{:0width$}", sign, self.minor_units.unsigned_abs(), width = len.saturating_sub(1) as usize)
+            }
+        };
+
+        if res.len() > len as usize {
+            return Err(IsoError { msg: format!("amount {} does not fit in {} digits", self, len) });
+        }
+        Ok(res)
+    }
+}
+
+impl Display for Amount {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let sign = if self.is_debit() { "-" } else { "" };
+        let val = self.minor_units.unsigned_abs();
+        if self.exponent == 0 {
+            write!(f, "{}{}", sign, val)
+        } else {
+            match 10u64.checked_pow(self.exponent) {
+                Some(scale) => write!(f, "{}{}.{:0width$}", sign, val / scale, val % scale, width = self.exponent as usize),
+                // the scale is more than any value in minor units
+                None => write!(f, "{}0.{:0width$}", sign, val, width = self.exponent as usize)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::iso8583::amount::{Amount, FieldFormat};
+
+    #[test]
+    fn test_signed_amount() {
+        let amt = Amount::parse(FieldFormat::SignedAmount, "D00012345", 2, None).unwrap();
+        assert_eq!(amt.minor_units, -12345);
+        assert_eq!(amt.to_string(), "-123.45");
+        assert_eq!(amt.format(FieldFormat::SignedAmount, 9).unwrap(), "D00012345");
+
+        let amt = Amount::parse(FieldFormat::SignedAmount, "C00000500", 3, None).unwrap();
+        assert_eq!(amt.to_string(), "0.500");
+        assert!(Amount::parse(FieldFormat::SignedAmount, "X00000500", 2, None).is_err());
+        assert!(Amount::parse(FieldFormat::Amount, "00000A500", 2, None).is_err());
+    }
+
+    #[test]
+    fn test_amount_format() {
+        let amt = Amount::in_currency(1500, "JPY").unwrap();
+        assert_eq!(amt.currency.as_deref(), Some("392"));
+        assert_eq!(amt.to_string(), "1500");
+        assert_eq!(amt.format(FieldFormat::Amount, 12).unwrap(), "000000001500");
+
+        assert!(Amount::new(-1, 2).format(FieldFormat::Amount, 12).is_err());
+        assert!(Amount::new(123456789, 2).format(FieldFormat::SignedAmount, 9).is_err());
+        assert!(Amount::in_currency(1, "XYZ").is_err());
+        assert_eq!(Amount::new(i64::MIN, 2).format(FieldFormat::SignedAmount, 20).unwrap(), "D9223372036854775808");
+        assert!(Amount::new(i64::MIN, 2).format(FieldFormat::SignedAmount, 9).is_err());
+
+        assert_eq!(Amount::new(-5, 19).to_string(), "-0.0000000000000000005");
+        assert_eq!(Amount::new(5, 20).to_string(), "0.00000000000000000005");
+    }
+}
@@ -3,12 +3,13 @@ use std::collections::HashMap;
 use std::io::{BufRead};
 
 use byteorder::ByteOrder;
+use serde::{Deserialize, Serialize};
 
-use crate::iso8583::field::{Encoding, Field, ParseError};
+use crate::iso8583::field::{Encoding, Field, ParseError, string_to_vec, vec_to_string};
 use crate::iso8583::{iso_spec, IsoError};
 
 /// This struct represents a bitmap that can support 192 (64*3) fields
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Bitmap {
     p_bmp: u64,
     s_bmp: u64,
@@ -130,16 +131,51 @@ mod tests {
 }
 
 
+/// This enum represents how a bitmap is laid out on the wire
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Default)]
+pub enum BitmapFormat {
+    /// 8 binary bytes per 64 bits (the default)
+    #[default]
+    Binary,
+    /// 16 hex characters per 64 bits in the encoding of the field (like ASCII or EBCDIC)
+    Hex,
+}
+
 /// This struct represents a bitmapped field in the ISO message
 pub struct BmpField {
     pub name: String,
     pub id: u32,
     pub encoding: Encoding,
+    pub format: BitmapFormat,
     pub children: Vec<Box<dyn Field>>,
 }
 
 /// Operarions on BmpField
 impl BmpField {
+    /// Reads 64 bits of the bitmap from in_buf, a Hex bitmap is 16 characters (in the encoding of the field)
+    /// on the wire while a Binary bitmap is 8 bytes
+    fn read_bmp_word(&self, in_buf: &mut dyn BufRead) -> Result<Vec<u8>, ParseError> {
+        match self.format {
+            BitmapFormat::Binary => {
+                let mut f_data = vec![0; 8];
+                match in_buf.read_exact(&mut f_data[..]) {
+                    Ok(_) => Ok(f_data),
+                    Err(_) => Err(ParseError { msg: format!("not enough data to parse bitmap - {}", self.name) })
+                }
+            }
+            BitmapFormat::Hex => {
+                let mut f_data = vec![0; 16];
+                if in_buf.read_exact(&mut f_data[..]).is_err() {
+                    return Err(ParseError { msg: format!("not enough data to parse bitmap - {}", self.name) });
+                }
+                match hex::decode(vec_to_string(&self.encoding, &f_data)) {
+                    Ok(v) => Ok(v),
+                    Err(e) => Err(ParseError { msg: format!("invalid bitmap - {}: {}", self.name, e) })
+                }
+            }
+        }
+    }
+
     /// Returns a field at the position (if defined or a IsoError if not)
     pub fn by_position(&self, pos: u32) -> Result<&Box<dyn Field>, IsoError> {
         let opt = &(self.children).iter().filter(|f| -> bool{
@@ -164,24 +200,20 @@ impl Field for BmpField {
     }
 
     fn parse(&self, in_buf: &mut dyn BufRead, f2d_map: &mut HashMap<String, Vec<u8>>) -> Result<(), ParseError> {
-        let mut f_data = vec![0; 8];
-
-        match in_buf.read_exact(&mut f_data[..]) {
-            Ok(_) => {
+        match self.read_bmp_word(in_buf) {
+            Ok(f_data) => {
                 let b1 = byteorder::BigEndian::read_u64(f_data.as_slice());
                 let mut b2: u64 = 0;
                 let mut b3: u64 = 0;
 
                 if f_data[0] & 0x80 == 0x80 {
-                    let mut s_bmp_data = vec![0; 8];
-                    match in_buf.read_exact(&mut s_bmp_data[..]) {
-                        Ok(_) => {
+                    match self.read_bmp_word(in_buf) {
+                        Ok(s_bmp_data) => {
                             trace!("parsed sec...");
                             b2 = byteorder::BigEndian::read_u64(s_bmp_data.as_slice());
                             if s_bmp_data[0] & 0x80 == 0x80 {
-                                let mut t_bmp_data = vec![0; 8];
-                                match in_buf.read_exact(&mut t_bmp_data[..]) {
-                                    Ok(_) => {
+                                match self.read_bmp_word(in_buf) {
+                                    Ok(t_bmp_data) => {
                                         trace!("parsed tertiary...");
                                         b3 = byteorder::BigEndian::read_u64(t_bmp_data.as_slice());
                                     }
@@ -243,7 +275,10 @@ impl Field for BmpField {
 
     fn assemble(&self, out_buf: &mut Vec<u8>, iso_msg: &iso_spec::IsoMsg) -> Result<u32, ParseError> {
         let bmp_data = iso_msg.bmp.as_vec();
-        out_buf.extend(bmp_data);
+        match self.format {
+            BitmapFormat::Binary => out_buf.extend(bmp_data),
+            BitmapFormat::Hex => out_buf.extend(string_to_vec(&self.encoding, hex::encode_upper(bmp_data).as_str()))
+        }
 
         for pos in 2..193 {
             if iso_msg.bmp.is_on(pos) {
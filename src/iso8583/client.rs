@@ -6,6 +6,7 @@ use std::net::{TcpStream, Shutdown};
 use crate::iso8583::mli::{MLI, MLIType, MLI2E, MLI2I, MLI4E, MLI4I};
 use std::io::{Write, Read};
 use crate::iso8583::server::get_hexdump;
+use crate::iso8583::defaults::Stan;
 
 
 /// This struct represents a ISO8583 TCP client
@@ -14,6 +15,7 @@ pub struct ISOTcpClient {
     mli: Box<dyn MLI>,
     spec: &'static Spec,
     _tcp_stream: Option<TcpStream>,
+    stan: Stan,
 }
 
 
@@ -34,9 +36,15 @@ impl ISOTcpClient {
             spec,
             mli,
             _tcp_stream: None,
+            stan: Stan::new(),
         }
     }
 
+    /// Returns the STAN sequence of this client (connection), to be used with `IsoMsg::apply_defaults`
+    pub fn stan(&self) -> &Stan {
+        &self.stan
+    }
+
     /// Sends a ISO message to the server and returns the response from server on success
     /// or a IsoError on failure
     pub fn send(&mut self, iso_msg: &IsoMsg) -> Result<IsoMsg, IsoError> {
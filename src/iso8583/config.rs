@@ -3,13 +3,109 @@
 
 use crate::crypto::pin::PinFormat;
 use crate::crypto::mac::{MacAlgo, PaddingType};
+use crate::crypto::keys::verify_kcv;
+use crate::crypto::tr31::{self, KeyBlockHeader};
+use crate::crypto::Cipher;
+use crate::crypto::emv::{SessionKeyDerivation, DEFAULT_ARQC_TAGS};
+use crate::crypto::security_module::{SecurityModule, SoftwareSecurityModule};
+use crate::iso8583::IsoError;
+use std::sync::Arc;
+
+lazy_static! {
+    /// The security module (with clear keys) shared by all the configs without a security module of their own
+    static ref SOFTWARE_SECURITY_MODULE: Arc<dyn SecurityModule> = Arc::new(SoftwareSecurityModule {});
+}
+
+/// This enum represents how the PIN or MAC key of a Config is used
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum KeyScheme {
+    /// The key is used as is (like a ZPK or a TAK)
+    Static,
+    /// The key is a DUKPT BDK, the transaction key is derived with the KSN in the message
+    Dukpt,
+    /// The key is a AES DUKPT BDK, the working key is derived with the (12 byte) KSN in the message
+    AesDukpt,
+}
+
+/// This enum represents the data a MAC is computed on
+#[derive(Debug, Clone, PartialEq)]
+pub enum MacInput {
+    /// The whole message (as assembled or received) up to the MAC field
+    FullMessage,
+    /// The values of a list of fields
+    Fields(MacFields),
+}
+
+/// This struct represents the list of fields (like MTI, F2, F3, F4, F11, F38 and F39) a MAC is computed on and
+/// how their values are formatted
+#[derive(Debug, Clone, PartialEq)]
+pub struct MacFields {
+    /// The fields - by name (like "message_type") or bitmap position (like "F2") - in the order they are MAC'd
+    pub fields: Vec<String>,
+    /// The separator between the field values (like " " or ",")
+    pub separator: String,
+    /// If true, absent fields are skipped (else they are MAC'd as empty values)
+    pub skip_absent: bool,
+    /// If true, leading and trailing spaces are removed from the values and runs of spaces are reduced to one
+    pub compress_spaces: bool,
+    /// If true, the values are converted to upper case
+    pub upper_case: bool,
+}
+
+impl MacFields {
+    /// Returns a MacFields for the given fields without a separator or formatting, absent fields are skipped
+    pub fn new(fields: &[&str]) -> MacFields {
+        MacFields {
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+            separator: String::new(),
+            skip_absent: true,
+            compress_spaces: false,
+            upper_case: false,
+        }
+    }
+
+    /// Returns the MAC input i.e the formatted values (as returned by value_of) joined with the separator
+    pub(in crate::iso8583) fn mac_data(&self, value_of: impl Fn(&str) -> Option<String>) -> Vec<u8> {
+        let mut values = vec![];
+        for f in &self.fields {
+            let mut val = match value_of(f) {
+                Some(v) => v,
+                None if self.skip_absent => continue,
+                None => String::new()
+            };
+            if self.compress_spaces {
+                val = val.split(' ').filter(|s| !s.is_empty()).collect::<Vec<&str>>().join(" ");
+            }
+            if self.upper_case {
+                val = val.to_uppercase();
+            }
+            values.push(val);
+        }
+        values.join(self.separator.as_str()).into_bytes()
+    }
+}
 
 pub struct Config {
     pin_format: Option<PinFormat>,
     pin_key: Option<String>,
+    pin_key_scheme: KeyScheme,
+    pvk: Option<String>,
+    cvk: Option<String>,
+    cvv_position: usize,
+    cvv2_position: usize,
+    imk: Option<String>,
+    session_key_derivation: SessionKeyDerivation,
+    arqc_padding: PaddingType,
+    arqc_tags: Vec<String>,
     mac_algo: Option<MacAlgo>,
     mac_padding: Option<PaddingType>,
     mac_key: Option<String>,
+    mac_key_scheme: KeyScheme,
+    mac_length: usize,
+    mac_input: MacInput,
+    security_control_info: Option<String>,
+    ksn_position: u32,
+    security_module: Arc<dyn SecurityModule>,
 }
 
 
@@ -19,9 +115,24 @@ impl Config {
         Config {
             pin_format: None,
             pin_key: None,
+            pin_key_scheme: KeyScheme::Static,
+            pvk: None,
+            cvk: None,
+            cvv_position: 0,
+            cvv2_position: 0,
+            imk: None,
+            session_key_derivation: SessionKeyDerivation::Common,
+            arqc_padding: PaddingType::Type2,
+            arqc_tags: DEFAULT_ARQC_TAGS.iter().map(|t| t.to_string()).collect(),
             mac_algo: None,
             mac_key: None,
             mac_padding: None,
+            mac_key_scheme: KeyScheme::Static,
+            mac_length: 8,
+            mac_input: MacInput::FullMessage,
+            security_control_info: None,
+            ksn_position: 53,
+            security_module: SOFTWARE_SECURITY_MODULE.clone(),
         }
     }
 
@@ -35,6 +146,46 @@ impl Config {
         &self.pin_key
     }
 
+    /// Returns the PIN verification key associated with this config
+    pub fn get_pvk(&self) -> &Option<String> {
+        &self.pvk
+    }
+
+    /// Returns the card verification key (CVK A and CVK B) associated with this config
+    pub fn get_cvk(&self) -> &Option<String> {
+        &self.cvk
+    }
+
+    /// Returns the position of the CVV in the discretionary data of the track (0 by default)
+    pub fn get_cvv_position(&self) -> usize {
+        self.cvv_position
+    }
+
+    /// Returns the position of the CVV2 in F48 (0 by default)
+    pub fn get_cvv2_position(&self) -> usize {
+        self.cvv2_position
+    }
+
+    /// Returns the EMV issuer master key (for application cryptograms) associated with this config
+    pub fn get_imk(&self) -> &Option<String> {
+        &self.imk
+    }
+
+    /// Returns how the EMV session key is derived (the common session key derivation by default)
+    pub fn get_session_key_derivation(&self) -> SessionKeyDerivation {
+        self.session_key_derivation
+    }
+
+    /// Returns the padding of the ARQC data (ISO 9797-1 method 2 by default)
+    pub fn get_arqc_padding(&self) -> &PaddingType {
+        &self.arqc_padding
+    }
+
+    /// Returns the tags (in F55) of the data the ARQC is computed on (`emv::DEFAULT_ARQC_TAGS` by default)
+    pub fn get_arqc_tags(&self) -> &Vec<String> {
+        &self.arqc_tags
+    }
+
     /// Returns the MAC key associated with this config
     pub fn get_mac_key(&self) -> &Option<String> {
         &self.mac_key
@@ -50,11 +201,105 @@ impl Config {
         &self.mac_padding
     }
 
+    /// Returns the scheme (static or DUKPT) of the PIN key
+    pub fn get_pin_key_scheme(&self) -> KeyScheme {
+        self.pin_key_scheme
+    }
+
+    /// Returns the scheme (static or DUKPT) of the MAC key
+    pub fn get_mac_key_scheme(&self) -> KeyScheme {
+        self.mac_key_scheme
+    }
+
+    /// Returns the length (in bytes) the MAC is truncated to (8 by default)
+    pub fn get_mac_length(&self) -> usize {
+        self.mac_length
+    }
+
+    /// Returns the data the MAC is computed on (the full message by default)
+    pub fn get_mac_input(&self) -> &MacInput {
+        &self.mac_input
+    }
+
+    /// Returns the position of the field that holds the DUKPT KSN (53 by default)
+    pub fn get_ksn_position(&self) -> u32 {
+        self.ksn_position
+    }
+
+    /// Returns the security related control information (F53) associated with this config
+    pub fn get_security_control_info(&self) -> &Option<String> {
+        &self.security_control_info
+    }
+
+    /// Returns the security module the PIN and MAC operations of this config are performed with
+    pub fn get_security_module(&self) -> &dyn SecurityModule {
+        self.security_module.as_ref()
+    }
+
 
     /// Use the Config with a builder pattern
     pub fn with_pin(&mut self, fmt: PinFormat, key: String) -> &mut Config {
         self.pin_format = Some(fmt);
         self.pin_key = Some(key);
+        self.pin_key_scheme = KeyScheme::Static;
+        self
+    }
+
+    /// Use the Config with a builder pattern - the PIN key is derived (DUKPT) from bdk and the KSN in the message
+    pub fn with_dukpt_pin(&mut self, fmt: PinFormat, bdk: String) -> &mut Config {
+        self.pin_format = Some(fmt);
+        self.pin_key = Some(bdk);
+        self.pin_key_scheme = KeyScheme::Dukpt;
+        self
+    }
+
+    /// Use the Config with a builder pattern - the PIN key is derived (AES DUKPT) from bdk and the KSN in the message,
+    /// a AES key (of the length of bdk) for ISO4 PIN blocks and a double length TDES key otherwise
+    pub fn with_aes_dukpt_pin(&mut self, fmt: PinFormat, bdk: String) -> &mut Config {
+        self.pin_format = Some(fmt);
+        self.pin_key = Some(bdk);
+        self.pin_key_scheme = KeyScheme::AesDukpt;
+        self
+    }
+
+    /// Use the Config with a builder pattern - sets the PIN verification key (for IBM 3624 offsets and Visa PVV's)
+    pub fn with_pvk(&mut self, pvk: String) -> &mut Config {
+        self.pvk = Some(pvk);
+        self
+    }
+
+    /// Use the Config with a builder pattern - sets the card verification key (the double length key of CVK A and
+    /// CVK B) for CVV, iCVV and CVV2 verification
+    pub fn with_cvk(&mut self, cvk: String) -> &mut Config {
+        self.cvk = Some(cvk);
+        self
+    }
+
+    /// Use the Config with a builder pattern - sets the position (0 based) of the CVV in the discretionary data of the
+    /// track (like 5 after a PVKI and PVV)
+    pub fn with_cvv_position(&mut self, pos: usize) -> &mut Config {
+        self.cvv_position = pos;
+        self
+    }
+
+    /// Use the Config with a builder pattern - sets the position (0 based) of the CVV2 in F48
+    pub fn with_cvv2_position(&mut self, pos: usize) -> &mut Config {
+        self.cvv2_position = pos;
+        self
+    }
+
+    /// Use the Config with a builder pattern - sets the EMV issuer master key, the session key derivation and the
+    /// padding of the ARQC data for ARQC verification and ARPC generation
+    pub fn with_emv(&mut self, imk: String, skd: SessionKeyDerivation, arqc_padding: PaddingType) -> &mut Config {
+        self.imk = Some(imk);
+        self.session_key_derivation = skd;
+        self.arqc_padding = arqc_padding;
+        self
+    }
+
+    /// Use the Config with a builder pattern - sets the tags (in F55) of the data the ARQC is computed on, in order
+    pub fn with_arqc_tags(&mut self, tags: &[&str]) -> &mut Config {
+        self.arqc_tags = tags.iter().map(|t| t.to_string()).collect();
         self
     }
 
@@ -63,6 +308,286 @@ impl Config {
         self.mac_algo = Some(algo);
         self.mac_key = Some(key);
         self.mac_padding = Some(mac_padding);
+        self.mac_key_scheme = KeyScheme::Static;
+        self
+    }
+
+    /// Use the Config with a builder pattern - the MAC key is derived (DUKPT) from bdk and the KSN in the message
+    pub fn with_dukpt_mac(&mut self, algo: MacAlgo, mac_padding: PaddingType, bdk: String) -> &mut Config {
+        self.mac_algo = Some(algo);
+        self.mac_key = Some(bdk);
+        self.mac_padding = Some(mac_padding);
+        self.mac_key_scheme = KeyScheme::Dukpt;
+        self
+    }
+
+    /// Use the Config with a builder pattern - the MAC key is derived (AES DUKPT) from bdk and the KSN in the message
+    pub fn with_aes_dukpt_mac(&mut self, algo: MacAlgo, mac_padding: PaddingType, bdk: String) -> &mut Config {
+        self.mac_algo = Some(algo);
+        self.mac_key = Some(bdk);
+        self.mac_padding = Some(mac_padding);
+        self.mac_key_scheme = KeyScheme::AesDukpt;
+        self
+    }
+
+    /// Use the Config with a builder pattern - sets the length (in bytes) the MAC is truncated to, the MAC field
+    /// (F64/F128) should be defined with this length in the spec
+    pub fn with_mac_length(&mut self, len: usize) -> &mut Config {
+        self.mac_length = len;
         self
     }
-}
\ No newline at end of file
+
+    /// Use the Config with a builder pattern - sets the data (the full message or a list of fields) the MAC is
+    /// computed on, for both generation and verification
+    pub fn with_mac_input(&mut self, input: MacInput) -> &mut Config {
+        self.mac_input = input;
+        self
+    }
+
+    /// Use the Config with a builder pattern - the PIN key is unwrapped from a TR-31 key block with the key block
+    /// protection key kbpk (hex), the key usage of the key block should be "P0" (or "B0" for a TDES or AES DUKPT BDK)
+    /// and a AES PIN key is only for ISO4 PIN blocks. The key is unwrapped in the clear, so this is only for the
+    /// (default) software security module
+    pub fn with_pin_key_block(&mut self, fmt: PinFormat, kbpk: &str, key_block: &str) -> Result<&mut Config, IsoError> {
+        let (header, key) = unwrap_key_block(kbpk, key_block)?;
+        match (header.key_usage.as_str(), header.algorithm) {
+            ("P0", 'A') if matches!(fmt, PinFormat::ISO4) => {
+                check_mode_of_use(&header, &['E', 'D', 'B', 'N'])?;
+                Ok(self.with_pin(fmt, key))
+            }
+            ("P0", 'T') | ("P0", 'D') if !matches!(fmt, PinFormat::ISO4) => {
+                check_mode_of_use(&header, &['E', 'D', 'B', 'N'])?;
+                Ok(self.with_pin(fmt, key))
+            }
+            ("B0", 'T') => {
+                check_mode_of_use(&header, &['X', 'N'])?;
+                Ok(self.with_dukpt_pin(fmt, key))
+            }
+            ("B0", 'A') => {
+                check_mode_of_use(&header, &['X', 'N'])?;
+                Ok(self.with_aes_dukpt_pin(fmt, key))
+            }
+            ("P0", _) | ("B0", _) => Err(IsoError { msg: format!("invalid algorithm {} of a {} key for {:?} PIN blocks", header.algorithm, header.key_usage, fmt) }),
+            (usage, _) => Err(IsoError { msg: format!("invalid key usage for a PIN key: {}", usage) })
+        }
+    }
+
+    /// Use the Config with a builder pattern - the MAC key is unwrapped from a TR-31 key block with the key block
+    /// protection key kbpk (hex), the key usage of the key block should be the MAC key usage of algo - M1 (CbcMac),
+    /// M3 (RetailMac), M5/M6 (TdesCmac), M6 with algorithm A (AesCmac) or M7 (HmacSha256) - with a mode of use of
+    /// C or N (or "B0" for a TDES or AES DUKPT BDK). The key is unwrapped in the clear, so this is only for the
+    /// (default) software security module
+    pub fn with_mac_key_block(&mut self, algo: MacAlgo, mac_padding: PaddingType, kbpk: &str, key_block: &str) -> Result<&mut Config, IsoError> {
+        let (header, key) = unwrap_key_block(kbpk, key_block)?;
+        match (header.key_usage.as_str(), header.algorithm) {
+            ("B0", 'T') => {
+                check_mode_of_use(&header, &['X', 'N'])?;
+                Ok(self.with_dukpt_mac(algo, mac_padding, key))
+            }
+            ("B0", 'A') => {
+                check_mode_of_use(&header, &['X', 'N'])?;
+                Ok(self.with_aes_dukpt_mac(algo, mac_padding, key))
+            }
+            ("B0", _) => Err(IsoError { msg: format!("invalid algorithm {} of a B0 key", header.algorithm) }),
+            (u, alg) if u.starts_with('M') => {
+                let (usages, algorithms): (&[&str], &[char]) = match algo {
+                    MacAlgo::CbcMac => (&["M1"], &['T', 'D']),
+                    MacAlgo::RetailMac => (&["M3"], &['T']),
+                    MacAlgo::TdesCmac => (&["M5", "M6"], &['T']),
+                    MacAlgo::AesCmac => (&["M6"], &['A']),
+                    MacAlgo::HmacSha256 => (&["M7"], &['H'])
+                };
+                if !usages.contains(&u) {
+                    return Err(IsoError { msg: format!("invalid key usage {} of a key for the MAC algorithm (expected {})", u, usages.join(" or ")) });
+                }
+                if !algorithms.contains(&alg) {
+                    return Err(IsoError { msg: format!("invalid algorithm {} of a {} key for the MAC algorithm", alg, u) });
+                }
+                // the key both generates (set_mac) and verifies (verify_mac) MACs
+                check_mode_of_use(&header, &['C', 'N'])?;
+                Ok(self.with_mac(algo, mac_padding, key))
+            }
+            (usage, _) => Err(IsoError { msg: format!("invalid key usage for a MAC key: {}", usage) })
+        }
+    }
+
+    /// Verifies the PIN key (or BDK) of this config against the expected KCV (hex) - a AES KCV for ISO4 PIN blocks
+    /// and AES DUKPT, a TDES KCV otherwise. The key should be a clear key (of the software security module)
+    pub fn with_pin_kcv(&mut self, kcv: &str) -> Result<&mut Config, IsoError> {
+        let aes = self.pin_key_scheme == KeyScheme::AesDukpt || matches!(self.pin_format, Some(PinFormat::ISO4));
+        check_kcv("PIN", &self.pin_key, aes, kcv)?;
+        Ok(self)
+    }
+
+    /// Verifies the MAC key (or BDK) of this config against the expected KCV (hex) - a AES KCV for AES-CMAC and
+    /// AES DUKPT, a TDES KCV otherwise (there is no KCV for HMAC keys). The key should be a clear key (of the
+    /// software security module)
+    pub fn with_mac_kcv(&mut self, kcv: &str) -> Result<&mut Config, IsoError> {
+        let aes = match (self.mac_key_scheme, &self.mac_algo) {
+            (_, Some(MacAlgo::HmacSha256)) => return Err(IsoError { msg: "KCV is not supported for HMAC keys".to_string() }),
+            (KeyScheme::AesDukpt, _) | (_, Some(MacAlgo::AesCmac)) => true,
+            _ => false
+        };
+        check_kcv("MAC", &self.mac_key, aes, kcv)?;
+        Ok(self)
+    }
+
+    /// Use the Config with a builder pattern - sets the position of the field that holds the DUKPT KSN
+    pub fn with_ksn_position(&mut self, pos: u32) -> &mut Config {
+        self.ksn_position = pos;
+        self
+    }
+
+    /// Use the Config with a builder pattern - sets the security related control information (F53) that
+    /// identifies the PIN key and format of this config
+    pub fn with_security_control_info(&mut self, val: String) -> &mut Config {
+        self.security_control_info = Some(val);
+        self
+    }
+
+    /// Use the Config with a builder pattern - sets the security module (a SoftwareSecurityModule by default) that
+    /// performs the PIN and MAC operations, the PIN and MAC keys of this config are then references to keys of sm
+    pub fn with_security_module(&mut self, sm: Arc<dyn SecurityModule>) -> &mut Config {
+        self.security_module = sm;
+        self
+    }
+}
+
+/// Unwraps a TR-31 key block with kbpk (hex) and returns the header and the key (hex)
+fn unwrap_key_block(kbpk: &str, key_block: &str) -> Result<(KeyBlockHeader, String), IsoError> {
+    let kbpk = hex::decode(kbpk).map_err(|e| IsoError { msg: format!("invalid key block protection key: {}", e) })?;
+    match tr31::unwrap(&kbpk, key_block) {
+        Ok((header, key)) => Ok((header, hex::encode(key))),
+        Err(e) => Err(IsoError { msg: e.msg })
+    }
+}
+
+/// Checks that the mode of use of a key block is one of modes
+fn check_mode_of_use(header: &KeyBlockHeader, modes: &[char]) -> Result<(), IsoError> {
+    if modes.contains(&header.mode_of_use) {
+        Ok(())
+    } else {
+        Err(IsoError { msg: format!("invalid mode of use {} of a {} key", header.mode_of_use, header.key_usage) })
+    }
+}
+
+/// Verifies the (AES or TDES) key (hex) against the expected KCV (hex)
+fn check_kcv(name: &str, key: &Option<String>, aes: bool, kcv: &str) -> Result<(), IsoError> {
+    let key = match key {
+        Some(k) => hex::decode(k).map_err(|e| IsoError { msg: format!("invalid {} key: {}", name, e) })?,
+        None => return Err(IsoError { msg: format!("missing {} key to verify KCV", name) })
+    };
+    let kcv = hex::decode(kcv).map_err(|e| IsoError { msg: format!("invalid {} KCV: {}", name, e) })?;
+
+    let cipher = if aes { Cipher::aes(&key) } else { Cipher::tdes(&key) }.map_err(|e| IsoError { msg: format!("invalid {} key: {}", name, e.msg) })?;
+
+    verify_kcv(cipher, &key, &kcv).map_err(|e| IsoError { msg: format!("{} key: {}", name, e.msg) })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::iso8583::config::{Config, KeyScheme, MacFields};
+    use crate::crypto::tr31::{self, KeyBlockHeader};
+    use crate::crypto::pin::PinFormat;
+    use crate::crypto::mac::{MacAlgo, PaddingType};
+
+    #[test]
+    fn test_mac_fields() {
+        let value_of = |f: &str| match f {
+            "message_type" => Some("0200".to_string()),
+            "F2" => Some("4111111111111111".to_string()),
+            "F43" => Some("  Acme  Store   London ".to_string()),
+            _ => None
+        };
+
+        let fields = MacFields::new(&["message_type", "F2", "F38", "F43"]);
+        assert_eq!(String::from_utf8(fields.mac_data(value_of)).unwrap(), "02004111111111111111  Acme  Store   London ");
+
+        let fields = MacFields { separator: ",".to_string(), skip_absent: false, ..fields };
+        assert_eq!(String::from_utf8(fields.mac_data(value_of)).unwrap(), "0200,4111111111111111,,  Acme  Store   London ");
+
+        let fields = MacFields { separator: " ".to_string(), compress_spaces: true, upper_case: true, skip_absent: true, ..fields };
+        assert_eq!(String::from_utf8(fields.mac_data(value_of)).unwrap(), "0200 4111111111111111 ACME STORE LONDON");
+    }
+
+    #[test]
+    fn test_kcv() {
+        let mut cfg = Config::new();
+        assert!(cfg.with_pin_kcv("08D7B4").is_err());
+
+        cfg.with_pin(PinFormat::ISO0, "0123456789ABCDEFFEDCBA9876543210".to_string())
+            .with_mac(MacAlgo::AesCmac, PaddingType::Type1, "2b7e151628aed2a6abf7158809cf4f3c".to_string());
+        assert!(cfg.with_pin_kcv("08D7B4").is_ok());
+        assert!(cfg.with_pin_kcv("08D7B5").is_err());
+        assert!(cfg.with_mac_kcv("7AD386C376").and_then(|c| c.with_pin_kcv("08D7B4")).is_ok());
+        assert!(cfg.with_mac_kcv("08D7B4").is_err());
+
+        // the same key as a AES key for ISO4 PIN blocks
+        cfg.with_pin(PinFormat::ISO4, "0123456789ABCDEFFEDCBA9876543210".to_string());
+        assert!(cfg.with_pin_kcv("08D7B4").is_err());
+    }
+
+    #[test]
+    fn test_key_blocks() {
+        let kbpk = "89E88CF7931444F334BD7547FC3F380C";
+        let mut cfg = Config::new();
+        cfg.with_pin_key_block(PinFormat::ISO0, kbpk, "B0080P0TE00N00000C77B158622112A976F16671BA0AF8EC1415825FF26C8D81D634E3646AF77DAF").unwrap();
+        assert_eq!(cfg.get_pin_key().as_deref(), Some("f039121bec83d26b169bdcd5b22aaf8f"));
+
+        // the key usage should match
+        assert!(cfg.with_mac_key_block(MacAlgo::RetailMac, PaddingType::Type1, kbpk,
+                                       "B0080P0TE00N00000C77B158622112A976F16671BA0AF8EC1415825FF26C8D81D634E3646AF77DAF").is_err());
+        // and the MAC of the key block should verify
+        assert!(cfg.with_pin_key_block(PinFormat::ISO0, kbpk, "B0080P0TE00N00000C77B158622112A976F16671BA0AF8EC1415825FF26C8D81D634E3646AF77DAE").is_err());
+
+        cfg.with_mac_key_block(MacAlgo::RetailMac, PaddingType::Type1, "0123456789ABCDEFFEDCBA98765432100123456789ABCDEF",
+                               "B0080M3TC00N00009182AF08B2DE8104D4EA05FA2B3A20E6FE19930288506C7CDAF3A4B89D0CD92B").unwrap();
+        assert!(cfg.with_mac_kcv("08D7B4").is_ok());
+        // a TDES key for AES-CMAC
+        assert!(cfg.with_mac_key_block(MacAlgo::AesCmac, PaddingType::Type1, "0123456789ABCDEFFEDCBA98765432100123456789ABCDEF",
+                                       "B0080M3TC00N00009182AF08B2DE8104D4EA05FA2B3A20E6FE19930288506C7CDAF3A4B89D0CD92B").is_err());
+    }
+
+    #[test]
+    fn test_key_block_algorithms() {
+        let kbpk = "88E1AB2A2E3DD38C1FA039A536500CC8A87AB9D62DC92C01058FA79F44657DE6";
+        let key = hex::decode("3F419E1CB7079442AA37474C2EFBF8B8").unwrap();
+        let key_block = |usage: &str, algorithm: char, mode_of_use: char| {
+            tr31::wrap(&hex::decode(kbpk).unwrap(), &KeyBlockHeader::new('D', usage, algorithm, mode_of_use), &key).unwrap()
+        };
+
+        // a AES BDK is used for AES DUKPT
+        let mut cfg = Config::new();
+        cfg.with_pin_key_block(PinFormat::ISO4, kbpk, &key_block("B0", 'A', 'X')).unwrap();
+        assert_eq!(cfg.get_pin_key_scheme(), KeyScheme::AesDukpt);
+        cfg.with_mac_key_block(MacAlgo::AesCmac, PaddingType::Type1, kbpk, &key_block("B0", 'A', 'X')).unwrap();
+        assert_eq!(cfg.get_mac_key_scheme(), KeyScheme::AesDukpt);
+        cfg.with_pin_key_block(PinFormat::ISO0, kbpk, &key_block("B0", 'T', 'X')).unwrap();
+        assert_eq!(cfg.get_pin_key_scheme(), KeyScheme::Dukpt);
+
+        // a AES PIN key is only for ISO4 and a TDES PIN key is not for ISO4
+        cfg.with_pin_key_block(PinFormat::ISO4, kbpk, &key_block("P0", 'A', 'E')).unwrap();
+        assert_eq!(cfg.get_pin_key_scheme(), KeyScheme::Static);
+        assert!(cfg.with_pin_key_block(PinFormat::ISO0, kbpk, &key_block("P0", 'A', 'E')).is_err());
+        assert!(cfg.with_pin_key_block(PinFormat::ISO4, kbpk, &key_block("P0", 'T', 'E')).is_err());
+
+        // the mode of use should allow the use of the key
+        assert!(cfg.with_pin_key_block(PinFormat::ISO4, kbpk, &key_block("P0", 'A', 'G')).is_err());
+        assert!(cfg.with_pin_key_block(PinFormat::ISO4, kbpk, &key_block("B0", 'A', 'E')).is_err());
+        cfg.with_mac_key_block(MacAlgo::AesCmac, PaddingType::Type1, kbpk, &key_block("M6", 'A', 'C')).unwrap();
+        assert!(cfg.with_mac_key_block(MacAlgo::AesCmac, PaddingType::Type1, kbpk, &key_block("M6", 'A', 'E')).is_err());
+        assert!(cfg.with_mac_key_block(MacAlgo::HmacSha256, PaddingType::Type1, kbpk, &key_block("M6", 'A', 'C')).is_err());
+
+        // the key usage should be that of the MAC algorithm
+        cfg.with_mac_key_block(MacAlgo::HmacSha256, PaddingType::Type1, kbpk, &key_block("M7", 'H', 'C')).unwrap();
+        cfg.with_mac_key_block(MacAlgo::CbcMac, PaddingType::Type1, kbpk, &key_block("M1", 'T', 'N')).unwrap();
+        cfg.with_mac_key_block(MacAlgo::TdesCmac, PaddingType::Type1, kbpk, &key_block("M6", 'T', 'C')).unwrap();
+        assert!(cfg.with_mac_key_block(MacAlgo::RetailMac, PaddingType::Type1, kbpk, &key_block("M1", 'T', 'C')).is_err());
+        assert!(cfg.with_mac_key_block(MacAlgo::CbcMac, PaddingType::Type1, kbpk, &key_block("M3", 'T', 'C')).is_err());
+        assert!(cfg.with_mac_key_block(MacAlgo::AesCmac, PaddingType::Type1, kbpk, &key_block("M7", 'A', 'C')).is_err());
+        // a generate only or verify only key can't be used for both set_mac and verify_mac
+        assert!(cfg.with_mac_key_block(MacAlgo::AesCmac, PaddingType::Type1, kbpk, &key_block("M6", 'A', 'G')).is_err());
+        assert!(cfg.with_mac_key_block(MacAlgo::AesCmac, PaddingType::Type1, kbpk, &key_block("M6", 'A', 'V')).is_err());
+    }
+}
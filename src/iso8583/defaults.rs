@@ -0,0 +1,131 @@
+//! This module contains the default values and generators (STAN, RRN, date/time etc) that can be declared
+//! for fields in the spec and applied to a message via `IsoMsg::apply_defaults`
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use chrono::{DateTime, Datelike, Local, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+
+/// This enum represents the generators of field values
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Generator {
+    /// The next value from the (per-connection) STAN sequence - 6 digits
+    Stan,
+    /// Transmission date and time in UTC - MMDDhhmmss
+    TransmissionDateTime,
+    /// Local time - hhmmss
+    LocalTime,
+    /// Local date - MMDD
+    LocalDate,
+    /// Local date and time - YYMMDDhhmmss for a field of length 12 and YYYYMMDDhhmmss for length 14
+    LocalDateTime,
+    /// Retrieval reference number - YDDDhh followed by the STAN
+    Rrn,
+}
+
+/// This enum represents the default value of a field
+#[derive(Clone, Debug)]
+pub enum DefaultValue {
+    Constant(String),
+    Generated(Generator),
+}
+
+/// This struct represents the default value of a field in a segment
+#[derive(Clone, Debug)]
+pub struct FieldDefault {
+    /// Name of the field
+    pub name: String,
+    /// Position of the field in the bitmap (or 0 for a top level field)
+    pub position: u32,
+    /// Length of the field (used to format date/time values)
+    pub len: u32,
+    pub value: DefaultValue,
+}
+
+/// This struct represents a sequence of STAN's (1 to 999999), typically one per connection
+pub struct Stan {
+    next: AtomicU32,
+}
+
+impl Stan {
+    /// Creates a new sequence starting at 1
+    pub fn new() -> Stan {
+        Stan::starting_at(1)
+    }
+
+    /// Creates a new sequence starting at the given value
+    pub fn starting_at(start: u32) -> Stan {
+        Stan { next: AtomicU32::new(if start == 0 || start > 999999 { 1 } else { start }) }
+    }
+
+    /// Returns the next STAN in the sequence as a 6 digit string, the sequence wraps around to 1 after 999999
+    pub fn next_stan(&self) -> String {
+        let stan = self.next.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+            Some(if n >= 999999 { 1 } else { n + 1 })
+        }).unwrap();
+        format!("{:06}", stan)
+    }
+}
+
+impl Default for Stan {
+    fn default() -> Self {
+        Stan::new()
+    }
+}
+
+/// Returns a value for the field based on the generator, now is the time of generation and stan is the
+/// STAN of the message (required by a RRN)
+pub(in crate::iso8583) fn generate(generator: &Generator, len: u32, stan_seq: &Stan, stan: Option<&str>, now: &DateTime<Local>) -> String {
+    match generator {
+        Generator::Stan => stan_seq.next_stan(),
+        Generator::TransmissionDateTime => now.with_timezone(&Utc).format("%m%d%H%M%S").to_string(),
+        Generator::LocalTime => now.format("%H%M%S").to_string(),
+        Generator::LocalDate => now.format("%m%d").to_string(),
+        Generator::LocalDateTime => {
+            if len == 14 {
+                now.format("%Y%m%d%H%M%S").to_string()
+            } else {
+                now.format("%y%m%d%H%M%S").to_string()
+            }
+        }
+        Generator::Rrn => {
+            let stan = match stan {
+                Some(s) => s.to_string(),
+                None => stan_seq.next_stan()
+            };
+            format!("{}{:03}{:02}{:0>6}", now.year() % 10, now.ordinal(), now.hour(), stan)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::iso8583::defaults::{generate, Generator, Stan};
+    use chrono::{Local, TimeZone};
+
+    #[test]
+    fn test_stan() {
+        let stan = Stan::new();
+        assert_eq!(stan.next_stan(), "000001");
+        assert_eq!(stan.next_stan(), "000002");
+
+        let stan = Stan::starting_at(999999);
+        assert_eq!(stan.next_stan(), "999999");
+        assert_eq!(stan.next_stan(), "000001");
+        assert_eq!(stan.next_stan(), "000002");
+    }
+
+    #[test]
+    fn test_generators() {
+        let now = Local.with_ymd_and_hms(2020, 2, 3, 14, 5, 6).unwrap();
+        let stan = Stan::new();
+
+        assert_eq!(generate(&Generator::LocalTime, 6, &stan, None, &now), "140506");
+        assert_eq!(generate(&Generator::LocalDate, 4, &stan, None, &now), "0203");
+        assert_eq!(generate(&Generator::LocalDateTime, 12, &stan, None, &now), "200203140506");
+        assert_eq!(generate(&Generator::LocalDateTime, 14, &stan, None, &now), "20200203140506");
+        assert_eq!(generate(&Generator::TransmissionDateTime, 10, &stan, None, &now).len(), 10);
+        assert_eq!(generate(&Generator::Rrn, 12, &stan, Some("779581"), &now), "003414779581");
+        assert_eq!(generate(&Generator::Stan, 6, &stan, None, &now), "000001");
+    }
+}
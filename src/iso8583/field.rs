@@ -2,7 +2,8 @@
 //!
 use crate::iso8583::iso_spec::IsoMsg;
 use std::fmt;
-use crate::iso8583::field::Encoding::{ASCII, EBCDIC, BCD, BINARY};
+use crate::iso8583::field::Encoding::{ASCII, EBCDIC, BCD, BINARY, EBCDIC037, EBCDIC500, EBCDIC1047, EBCDIC273, ISO8859_1, UTF8};
+use crate::iso8583::code_page::{CodePage, latin1_decode, latin1_encode};
 use std::collections::HashMap;
 use std::io::{BufRead, Write};
 
@@ -17,6 +18,35 @@ pub enum Encoding {
     EBCDIC,
     BINARY,
     BCD,
+    /// EBCDIC - IBM code page 037 (USA/Canada)
+    EBCDIC037,
+    /// EBCDIC - IBM code page 500 (International Latin-1)
+    EBCDIC500,
+    /// EBCDIC - IBM code page 1047 (Open Systems Latin-1)
+    EBCDIC1047,
+    /// EBCDIC - IBM code page 273 (Germany/Austria)
+    EBCDIC273,
+    /// ISO-8859-1 (Latin-1), a single byte per character
+    ISO8859_1,
+    UTF8,
+}
+
+impl Encoding {
+    /// Returns the code page if this is a EBCDIC encoding with a specific code page
+    fn code_page(&self) -> Option<CodePage> {
+        match self {
+            EBCDIC037 => Some(CodePage::CP037),
+            EBCDIC500 => Some(CodePage::CP500),
+            EBCDIC1047 => Some(CodePage::CP1047),
+            EBCDIC273 => Some(CodePage::CP273),
+            _ => None
+        }
+    }
+
+    /// Returns true if this is a text encoding (i.e. not BINARY or BCD)
+    pub fn is_text(&self) -> bool {
+        !matches!(self, BINARY | BCD)
+    }
 }
 
 /// This struct represents a error in parsing a field/message
@@ -61,6 +91,11 @@ pub trait Field: Sync {
 
     /// Returns field value as binary (wire format)
     fn to_raw(&self, val: &str) -> Vec<u8>;
+
+    /// Returns the length (in bytes) of the field if it is a fixed length field
+    fn fixed_len(&self) -> Option<u32> {
+        None
+    }
 }
 
 /// This struct represents a Fixed field
@@ -97,6 +132,9 @@ impl Field for FixedField {
 
     fn assemble(self: &Self, out_buf: &mut Vec<u8>, iso_msg: &IsoMsg) -> Result<u32, ParseError> {
         match iso_msg.fd_map.get(&self.name) {
+            Some(fd) if fd.len() != self.len as usize => {
+                Err(ParseError { msg: format!("invalid length {} for fixed field {} (expected {})", fd.len(), self.name, self.len) })
+            }
             Some(fd) => {
                 out_buf.extend(fd);
                 Ok(fd.as_slice().len() as u32)
@@ -131,6 +169,10 @@ impl Field for FixedField {
     fn to_raw(&self, val: &str) -> Vec<u8> {
         string_to_vec(&self.encoding, val)
     }
+
+    fn fixed_len(&self) -> Option<u32> {
+        Some(self.len)
+    }
 }
 
 /// This struct represents a Variable field
@@ -154,10 +196,10 @@ impl VarField {
     fn data_len(&self, data: &Vec<u8>) -> usize
     {
         match self.len_encoding {
-            Encoding::ASCII => {
+            Encoding::ASCII | Encoding::ISO8859_1 | Encoding::UTF8 => {
                 String::from_utf8(data.clone()).expect("").parse::<usize>().unwrap()
             }
-            Encoding::EBCDIC => {
+            Encoding::EBCDIC | Encoding::EBCDIC037 | Encoding::EBCDIC500 | Encoding::EBCDIC1047 | Encoding::EBCDIC273 => {
                 ebcdic_to_ascii(data).parse::<usize>().unwrap()
             }
             Encoding::BINARY => {
@@ -180,7 +222,7 @@ impl VarField {
     /// Builds and returns the length indicator based on encoding of the field as a Vec<u8>
     fn build_len_ind(&self, len: usize) -> Vec<u8> {
         match self.len_encoding {
-            Encoding::ASCII => {
+            Encoding::ASCII | Encoding::ISO8859_1 | Encoding::UTF8 => {
                 match self.len {
                     1 => format!("{:01}", len).into_bytes(),
                     2 => format!("{:02}", len).into_bytes(),
@@ -188,7 +230,7 @@ impl VarField {
                     _ => unimplemented!("len-ind cannot exceed 3")
                 }
             }
-            Encoding::EBCDIC => {
+            Encoding::EBCDIC | Encoding::EBCDIC037 | Encoding::EBCDIC500 | Encoding::EBCDIC1047 | Encoding::EBCDIC273 => {
                 match self.len {
                     1 => ascii_to_ebcdic(&mut format!("{:01}", len).into_bytes()),
                     2 => ascii_to_ebcdic(&mut format!("{:02}", len).into_bytes()),
@@ -302,7 +344,11 @@ impl Field for VarField
 pub(in crate::iso8583) fn vec_to_string(encoding: &Encoding, data: &Vec<u8>) -> String {
     match encoding {
         ASCII => {
-            String::from_utf8(data.clone()).unwrap()
+            // data that isn't valid UTF-8 is read as ISO-8859-1 rather than failing
+            match String::from_utf8(data.clone()) {
+                Ok(s) => s,
+                Err(_) => latin1_decode(data)
+            }
         }
         EBCDIC => {
             ebcdic_to_ascii(data)
@@ -313,6 +359,15 @@ pub(in crate::iso8583) fn vec_to_string(encoding: &Encoding, data: &Vec<u8>) ->
         BCD => {
             hex::encode(data.as_slice())
         }
+        EBCDIC037 | EBCDIC500 | EBCDIC1047 | EBCDIC273 => {
+            encoding.code_page().unwrap().decode(data)
+        }
+        ISO8859_1 => {
+            latin1_decode(data)
+        }
+        UTF8 => {
+            String::from_utf8_lossy(data).to_string()
+        }
     }
 }
 
@@ -348,6 +403,15 @@ pub(in crate::iso8583) fn string_to_vec(encoding: &Encoding, data: &str) -> Vec<
         BCD => {
             hex::decode(data).unwrap()
         }
+        EBCDIC037 | EBCDIC500 | EBCDIC1047 | EBCDIC273 => {
+            encoding.code_page().unwrap().encode(data)
+        }
+        ISO8859_1 => {
+            latin1_encode(data)
+        }
+        UTF8 => {
+            data.to_string().into_bytes()
+        }
     }
 }
 
@@ -355,3 +419,36 @@ pub(in crate::iso8583) fn string_to_vec(encoding: &Encoding, data: &str) -> Vec<
 
 
 
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::iso8583::field::{Encoding, Field, VarField, string_to_vec, vec_to_string};
+
+    #[test]
+    fn test_text_encodings() {
+        let name = "Bäckerei Müller, Zürich";
+        for enc in [Encoding::EBCDIC037, Encoding::EBCDIC500, Encoding::EBCDIC1047, Encoding::EBCDIC273,
+            Encoding::ISO8859_1, Encoding::UTF8].iter() {
+            assert_eq!(vec_to_string(enc, &string_to_vec(enc, name)), name);
+        }
+
+        assert_eq!(string_to_vec(&Encoding::ISO8859_1, name).len(), 23);
+        assert_eq!(string_to_vec(&Encoding::UTF8, name).len(), 26);
+        // ASCII falls back to ISO-8859-1 for data that isn't valid UTF-8
+        assert_eq!(vec_to_string(&Encoding::ASCII, &b"Caf\xe9".to_vec()), "Café");
+    }
+
+    #[test]
+    fn test_var_field_code_page() {
+        let f43 = VarField { name: "card_acceptor_name_location".to_string(), id: 43, len: 2, len_encoding: Encoding::EBCDIC273, encoding: Encoding::EBCDIC273, position: 43 };
+        let mut data = vec![0xf0, 0xf8];
+        data.extend(string_to_vec(&Encoding::EBCDIC273, "Müller ß"));
+
+        let mut f2d_map = HashMap::new();
+        f43.parse(&mut std::io::Cursor::new(data), &mut f2d_map).unwrap();
+        assert_eq!(vec_to_string(&Encoding::EBCDIC273, f2d_map.get("card_acceptor_name_location").unwrap()), "Müller ß");
+        assert_eq!(f43.build_len_ind(8), vec![0xf0, 0xf8]);
+    }
+}
@@ -8,9 +8,20 @@ use crate::iso8583::{bitmap, IsoError};
 use crate::iso8583::field::{Field, ParseError};
 use crate::iso8583::yaml_de::YMessageSegment;
 use crate::iso8583::bitmap::Bitmap;
-use crate::iso8583::config::Config;
-use crate::crypto::pin::generate_pin_block;
-use crate::crypto::mac::generate_mac;
+use crate::iso8583::config::{Config, MacInput};
+use crate::crypto::pin::PinFormat;
+use crate::crypto::pin_verification::PinVerificationData;
+use crate::crypto::cvv::CvvType;
+use crate::crypto::emv::{self, ArpcMethod};
+use crate::iso8583::track::TrackData;
+use crate::crypto::Cipher;
+use crate::crypto::dukpt::KeyUsage;
+use crate::iso8583::config::KeyScheme;
+use crate::crypto::mac::{truncate_mac, MacAlgo};
+use crate::iso8583::defaults::{DefaultValue, FieldDefault, Generator, Stan, generate};
+use crate::iso8583::amount::{Amount, AmountField};
+use crate::iso8583::currency::currency;
+use crate::iso8583::rules::{Condition, FieldRule, Presence, Violation, operand_position, violations_to_string};
 
 // Reads the spec definitions from YAML file
 lazy_static! {
@@ -37,6 +48,17 @@ static ref ALL_SPECS: std::collections::HashMap<String,Spec> ={
 
     specs
 };
+
+static ref BUILTIN_SPECS: std::collections::HashMap<String,Spec> ={
+    let mut specs=HashMap::<String,Spec>::new();
+    for name in crate::iso8583::yaml_de::BUILTIN_SPECS.iter() {
+        match crate::iso8583::yaml_de::builtin(name){
+            Ok(spec)=> specs.insert(name.to_string(),spec),
+            Err(e)=> panic!("{}", e.msg)
+        };
+    }
+    specs
+};
 }
 
 /// This struct is the definition of the specification - layout of fields etc..
@@ -46,6 +68,12 @@ pub struct Spec {
     pub(in crate::iso8583) id: u32,
     pub(in crate::iso8583) messages: Vec<MessageSegment>,
     pub(in crate::iso8583) header_fields: Vec<Box<dyn Field>>,
+    /// Fields (like a TPDU or a network header) that precede the message on the wire
+    pub(in crate::iso8583) wire_header_fields: Vec<Box<dyn Field>>,
+    /// Pairs of wire header fields whose values are swapped in a response (e.g. source and destination NII)
+    pub(in crate::iso8583) wire_header_swaps: Vec<(String, String)>,
+    /// If true, messages are validated against the rules of their segment after parse and before assemble
+    pub(in crate::iso8583) validate: bool,
 }
 
 /// This struct represents a segment in the Spec (a auth request, a response etc)
@@ -55,15 +83,25 @@ pub struct MessageSegment {
     pub(in crate::iso8583) id: u32,
     pub(in crate::iso8583) selector: Vec<String>,
     pub(in crate::iso8583) fields: Vec<Box<dyn Field>>,
+    /// Presence rules for fields of the segment
+    pub(in crate::iso8583) rules: Vec<FieldRule>,
+    /// Default values (constant or generated) for fields of the segment
+    pub(in crate::iso8583) defaults: Vec<FieldDefault>,
+    /// Amount formats (and their currency fields) for fields of the segment
+    pub(in crate::iso8583) amounts: Vec<AmountField>,
 }
 
 
 impl From<&YMessageSegment> for MessageSegment {
     fn from(yms: &YMessageSegment) -> Self {
         let mut fields: Vec<Box<dyn Field>> = Vec::<Box<dyn Field>>::new();
+        let mut defaults: Vec<FieldDefault> = vec![];
+        let mut amounts: Vec<AmountField> = vec![];
 
         yms.fields.iter().for_each(|f| {
             fields.push(Into::<Box<dyn Field>>::into(f));
+            f.collect_defaults(&mut defaults);
+            f.collect_amounts(&mut amounts);
         });
 
 
@@ -72,6 +110,9 @@ impl From<&YMessageSegment> for MessageSegment {
             id: yms.id,
             selector: yms.selector.iter().map(|s| s.clone()).collect(),
             fields,
+            rules: yms.rules.clone(),
+            defaults,
+            amounts,
         }
     }
 }
@@ -84,6 +125,11 @@ impl MessageSegment {
         return self.name.as_str();
     }
 
+    /// Returns the presence rules of the segment
+    pub fn rules(&self) -> &Vec<FieldRule> {
+        &self.rules
+    }
+
     /// Returns a field given it's name if defined in the spec or a IsoError if the field is not found
     pub fn field_by_name(&self, name: &String) -> Result<&dyn Field, IsoError> {
         match self.fields.iter().find(|field| -> bool{
@@ -95,8 +141,14 @@ impl MessageSegment {
         }) {
             None => {
                 //try bitmap
-                let bmp = self.field_by_name(&"bitmap".to_string()).unwrap();
-                Ok(bmp.child_by_name(name))
+                let children = match self.fields.iter().find(|f| f.name() == "bitmap") {
+                    Some(bmp) => bmp.children(),
+                    None => vec![]
+                };
+                match children.into_iter().find(|f| f.name() == name) {
+                    Some(f) => Ok(f),
+                    None => Err(IsoError { msg: format!("field {} is not defined in {}", name, self.name) })
+                }
             }
             Some(f) => {
                 Ok(f.as_ref())
@@ -110,6 +162,16 @@ impl Spec {
         &self.name
     }
 
+    /// Returns a wire header field given its name (if defined in the spec)
+    pub fn wire_header_field(&self, name: &str) -> Option<&dyn Field> {
+        self.wire_header_fields.iter().find(|f| f.name() == name).map(|f| f.as_ref())
+    }
+
+    /// Returns true if the spec defines a wire header (TPDU etc) that precedes the message
+    pub fn has_wire_header(&self) -> bool {
+        !self.wire_header_fields.is_empty()
+    }
+
     /// Returns a message segment given its name or a IsoError if such a segment is not present
     pub fn get_message(&self, name: &str) -> Result<&MessageSegment, IsoError> {
         for msg in &self.messages {
@@ -139,6 +201,13 @@ impl Spec {
 
         let mut in_buf = Cursor::new(data);
 
+        // skip the wire header (if any), the selector follows it
+        for f in &self.wire_header_fields {
+            if let Err(e) = f.parse(&mut in_buf, &mut f2d_map) {
+                return Err(IsoError { msg: e.msg });
+            }
+        }
+
         for f in &self.header_fields {
             match f.parse(&mut in_buf, &mut f2d_map) {
                 Ok(_) => {
@@ -161,6 +230,7 @@ impl Spec {
 }
 
 /// This struct represents a parsed message for a given spec
+#[derive(Clone)]
 pub struct IsoMsg {
     // The spec associated with this IsoMsg
     pub spec: &'static Spec,
@@ -170,6 +240,8 @@ pub struct IsoMsg {
     pub fd_map: std::collections::HashMap<String, Vec<u8>>,
     /// the bitmap on the iso message
     pub bmp: bitmap::Bitmap,
+    /// the data the message was parsed from (None for a message created via new_msg or once it is changed)
+    raw: Option<Vec<u8>>,
 }
 
 /// Operations on IsoMsg
@@ -199,8 +271,15 @@ impl IsoMsg {
         }
     }
 
-    /// Returns the value of a top level field like message_type
+    /// Returns the value of a top level field like message_type (or a wire header field like a TPDU)
     pub fn get_field_value(&self, name: &String) -> Result<String, IsoError> {
+        if let Some(f) = self.spec.wire_header_field(name) {
+            return match self.fd_map.get(name) {
+                Some(v) => Ok(f.to_string(v)),
+                None => Err(IsoError { msg: format!("no value for wire header field : {}", name) })
+            };
+        }
+
         match self.msg.fields.iter().find(|f| -> bool {
             if f.name() == name {
                 true
@@ -217,11 +296,23 @@ impl IsoMsg {
         }
     }
 
-    /// sets a top-level field like message_type etc
+    /// Returns the data the message was parsed from, None if the message was created via new_msg or has been
+    /// changed (via set, set_on etc) since it was parsed. Changes made directly to fd_map or bmp are not tracked
+    pub fn raw(&self) -> Option<&Vec<u8>> {
+        self.raw.as_ref()
+    }
+
+    /// sets a top-level field like message_type etc (or a wire header field like a TPDU)
     pub fn set(&mut self, name: &str, val: &str) -> Result<(), IsoError> {
+        self.raw = None;
+        if let Some(f) = self.spec.wire_header_field(name) {
+            self.fd_map.insert(f.name().clone(), to_raw(f, val)?);
+            return Ok(());
+        }
+
         match self.msg.field_by_name(&name.to_string()) {
             Ok(f) => {
-                self.fd_map.insert(f.name().clone(), f.to_raw(val));
+                self.fd_map.insert(f.name().clone(), to_raw(f, val)?);
                 Ok(())
             }
             Err(e) => Err(e)
@@ -230,10 +321,11 @@ impl IsoMsg {
 
     /// Sets a field in the bitmap with the given value
     pub fn set_on(&mut self, pos: u32, val: &str) -> Result<(), IsoError> {
+        self.raw = None;
         match self.msg.field_by_name(&"bitmap".to_string()) {
             Ok(f) => {
                 let cf = f.child_by_pos(pos);
-                self.fd_map.insert(cf.name().clone(), cf.to_raw(val));
+                self.fd_map.insert(cf.name().clone(), to_raw(cf, val)?);
                 self.bmp.set_on(pos);
                 Ok(())
             }
@@ -241,8 +333,11 @@ impl IsoMsg {
         }
     }
 
-    /// Echoes (sets the value with the identical field in req_msg) for given positions in the bitmap
+    /// Echoes (sets the value with the identical field in req_msg) for given positions in the bitmap. The
+    /// received bytes are copied as is when they read the same in this message (so that text that doesn't
+    /// survive a round trip, like ISO-8859-1 in a ASCII field, is echoed unchanged)
     pub fn echo_from(&mut self, req_msg: &IsoMsg, positions: &[u32]) -> Result<(), IsoError> {
+        self.raw = None;
         match self.msg.field_by_name(&"bitmap".to_string()) {
             Ok(f) => {
                 for pos in positions {
@@ -250,7 +345,13 @@ impl IsoMsg {
                     match req_msg.bmp_child_value(*pos) {
                         Ok(res) => {
                             debug!("echoing .. {}: {}", pos, res);
-                            self.fd_map.insert(cf.name().clone(), cf.to_raw(res.as_str()));
+                            let req_raw = req_msg.msg.field_by_name(&"bitmap".to_string()).ok().
+                                and_then(|bf| req_msg.fd_map.get(bf.child_by_pos(*pos).name()));
+                            let raw = match req_raw {
+                                Some(raw) if cf.to_string(raw) == res => raw.clone(),
+                                _ => to_raw(cf, res.as_str())?
+                            };
+                            self.fd_map.insert(cf.name().clone(), raw);
                             self.bmp.set_on(*pos);
                         }
                         Err(e) => {
@@ -264,10 +365,224 @@ impl IsoMsg {
         }
     }
 
+    /// Sets the fields that have a default value or a generator defined in the spec (and are not already set),
+    /// the STAN is the next value from stan (typically the per-connection sequence of a client)
+    pub fn apply_defaults(&mut self, stan: &Stan) -> Result<(), IsoError> {
+        let now = chrono::Local::now();
+
+        // the RRN includes the STAN, so generate it last
+        let (rrn_defaults, defaults): (Vec<&FieldDefault>, Vec<&FieldDefault>) = self.msg.defaults.iter().
+            partition(|d| matches!(d.value, DefaultValue::Generated(Generator::Rrn)));
+
+        for d in defaults.iter().chain(rrn_defaults.iter()) {
+            if self.fd_map.contains_key(&d.name) {
+                continue;
+            }
+
+            let val = match d.value {
+                DefaultValue::Constant(ref v) => v.clone(),
+                DefaultValue::Generated(ref g) => {
+                    let f11 = if self.bmp.is_on(11) { self.bmp_child_value(11).ok() } else { None };
+                    generate(g, d.len, stan, f11.as_deref(), &now)
+                }
+            };
+
+            if d.position > 0 {
+                self.set_on(d.position, val.as_str())?;
+            } else {
+                self.set(d.name.as_str(), val.as_str())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the amount field at pos along with the exponent and the code of its currency (from the
+    /// currency field declared in the spec, if any)
+    fn amount_field(&self, pos: u32) -> Result<(&AmountField, u32, Option<String>), IsoError> {
+        let af = match self.msg.amounts.iter().find(|a| a.position == pos) {
+            Some(af) => af,
+            None => return Err(IsoError { msg: format!("field at position {} is not a amount field in {}", pos, self.msg.name) })
+        };
+
+        match af.currency {
+            Some(cur_pos) => {
+                let code = self.bmp_child_value(cur_pos).map_err(|_| IsoError { msg: format!("no currency (field {}) for amount field {}", cur_pos, af.name) })?;
+                match currency(code.as_str()) {
+                    Some(c) => Ok((af, c.exponent, Some(c.numeric.to_string()))),
+                    None => Err(IsoError { msg: format!("unknown currency code {} for amount field {}", code, af.name) })
+                }
+            }
+            None => Ok((af, 0, None))
+        }
+    }
+
+    /// Returns the value of the amount field at pos (a Amount or SignedAmount in the spec) as a decimal amount,
+    /// the exponent is that of the ISO 4217 currency in the currency field (like F49) declared in the spec
+    pub fn get_amount(&self, pos: u32) -> Result<Amount, IsoError> {
+        let (af, exponent, currency) = self.amount_field(pos)?;
+        let val = self.bmp_child_value(pos)?;
+        Amount::parse(af.format, val.as_str(), exponent, currency)
+    }
+
+    /// Sets the amount field at pos, if the amount has a currency the currency field (like F49) is set too
+    pub fn set_amount(&mut self, pos: u32, amount: &Amount) -> Result<(), IsoError> {
+        let af = match self.msg.amounts.iter().find(|a| a.position == pos) {
+            Some(af) => af.clone(),
+            None => return Err(IsoError { msg: format!("field at position {} is not a amount field in {}", pos, self.msg.name) })
+        };
+
+        // the amount is formatted and the currency checked before any field is set
+        let val = amount.format(af.format, af.len)?;
+        let cur = match (af.currency, &amount.currency) {
+            (Some(cur_pos), Some(code)) => {
+                match currency(code) {
+                    Some(c) if c.exponent == amount.exponent => Some((cur_pos, c.numeric)),
+                    Some(c) => return Err(IsoError { msg: format!("amount exponent {} does not match the exponent {} of currency {}", amount.exponent, c.exponent, c.alpha) }),
+                    None => return Err(IsoError { msg: format!("unknown currency code: {}", code) })
+                }
+            }
+            // a amount without a currency must have the exponent of the currency already in the message (if any)
+            (Some(cur_pos), None) => {
+                if let Ok(code) = self.bmp_child_value(cur_pos) {
+                    match currency(code.as_str()) {
+                        Some(c) if c.exponent == amount.exponent => None,
+                        Some(c) => return Err(IsoError { msg: format!("amount exponent {} does not match the exponent {} of currency {} (field {})", amount.exponent, c.exponent, c.alpha, cur_pos) }),
+                        None => return Err(IsoError { msg: format!("unknown currency code {} for amount field {}", code, af.name) })
+                    }
+                } else {
+                    None
+                }
+            }
+            _ => None
+        };
+
+        self.set_on(pos, val.as_str())?;
+        match cur {
+            Some((cur_pos, numeric)) => self.set_on(cur_pos, numeric),
+            None => Ok(())
+        }
+    }
+
+    /// Copies the wire header from req_msg (swapping fields like source/destination as defined
+    /// in the spec), so that the response is routed back to the originator
+    pub fn echo_wire_header(&mut self, req_msg: &IsoMsg) -> Result<(), IsoError> {
+        self.raw = None;
+        for f in &self.spec.wire_header_fields {
+            let src_name = match self.spec.wire_header_swaps.iter().find(|(n, _)| n == f.name()) {
+                Some((_, other)) => other,
+                None => f.name(),
+            };
+
+            match req_msg.fd_map.get(src_name) {
+                Some(v) => {
+                    self.fd_map.insert(f.name().clone(), v.clone());
+                }
+                None => {
+                    return Err(IsoError { msg: format!("no value for wire header field {} in request", src_name) });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the value of a operand in a rule condition - a bitmap position like F52 or the name of a field
+    fn operand_value(&self, operand: &str) -> Option<String> {
+        match operand_position(operand) {
+            Some(pos) => {
+                if pos > 0 && pos <= 192 && self.bmp.is_on(pos) {
+                    self.bmp_child_value(pos).ok()
+                } else {
+                    None
+                }
+            }
+            None => {
+                match self.msg.field_by_name(&operand.to_string()) {
+                    Ok(f) => self.fd_map.get(operand).map(|v| f.to_string(v)),
+                    Err(_) => self.get_field_value(&operand.to_string()).ok()
+                }
+            }
+        }
+    }
+
+    /// Validates the message against the rules of its segment and returns all violations (if any). Fields
+    /// with an Echo rule are only checked for presence, see `IsoMsg::validate_response`
+    pub fn validate(&self) -> Result<(), Vec<Violation>> {
+        self.check_rules(None)
+    }
+
+    /// Validates the (response) message against the rules of its segment, in addition fields with an Echo
+    /// rule should have the same value as in req_msg
+    pub fn validate_response(&self, req_msg: &IsoMsg) -> Result<(), Vec<Violation>> {
+        self.check_rules(Some(req_msg))
+    }
+
+    fn check_rules(&self, req_msg: Option<&IsoMsg>) -> Result<(), Vec<Violation>> {
+        let mut violations = vec![];
+
+        for rule in &self.msg.rules {
+            if let Some(ref expr) = rule.condition {
+                match Condition::parse(expr) {
+                    Ok(cond) => {
+                        if !cond.eval(&|operand| self.operand_value(operand)) {
+                            continue;
+                        }
+                    }
+                    Err(e) => {
+                        violations.push(Violation { position: rule.position, field: String::new(), msg: e.msg });
+                        continue;
+                    }
+                }
+            }
+
+            let field = match self.msg.field_by_name(&"bitmap".to_string()) {
+                Ok(bmp) => bmp.children().into_iter().find(|f| f.position() == rule.position),
+                Err(_) => None
+            };
+            let field_name = field.map_or(String::new(), |f| f.name().clone());
+            let present = rule.position > 0 && rule.position <= 192 && self.bmp.is_on(rule.position) &&
+                self.fd_map.contains_key(&field_name);
+
+            match rule.presence {
+                Presence::Mandatory | Presence::Conditional | Presence::Echo if !present => {
+                    violations.push(Violation { position: rule.position, field: field_name, msg: format!("{:?} field is missing", rule.presence) });
+                }
+                Presence::Forbidden if present => {
+                    violations.push(Violation { position: rule.position, field: field_name, msg: "field should not be present".to_string() });
+                }
+                Presence::Echo => {
+                    if let Some(req) = req_msg {
+                        let req_val = req.bmp_child_value(rule.position).ok();
+                        let val = self.bmp_child_value(rule.position).ok();
+                        if req_val != val {
+                            violations.push(Violation {
+                                position: rule.position,
+                                field: field_name,
+                                msg: format!("value {:?} is not echoed from request - {:?}", val.unwrap_or_default(), req_val.unwrap_or_default()),
+                            });
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
     /// Assembles the messages into a Vec<u8> or a IsoError on failure
     pub fn assemble(&self) -> Result<Vec<u8>, IsoError> {
+        if self.spec.validate {
+            if let Err(violations) = self.validate() {
+                return Err(IsoError { msg: format!("{} failed validation: {}", self.msg.name, violations_to_string(&violations)) });
+            }
+        }
+
         let mut out_buf: Vec<u8> = Vec::new();
-        for f in &self.msg.fields {
+        for f in self.spec.wire_header_fields.iter().chain(self.msg.fields.iter()) {
             match f.assemble(&mut out_buf, &self) {
                 Ok(_) => {}
                 Err(e) => {
@@ -284,7 +599,8 @@ impl IsoMsg {
             return Err(IsoError { msg: format!("missing pin_format or key in call to set_pin") });
         }
 
-        match generate_pin_block(&cfg.get_pin_fmt().as_ref().unwrap(), pin, pan, &hex::decode(cfg.get_pin_key().as_ref().unwrap().as_str()).unwrap()) {
+        let key = self.pin_key(cfg)?;
+        match cfg.get_security_module().generate_pin_block(cfg.get_pin_fmt().as_ref().unwrap(), pin, pan, &key) {
             Ok(v) => {
                 self.set_on(52, hex::encode(v).as_str())
             }
@@ -294,40 +610,251 @@ impl IsoMsg {
         }
     }
 
-    /// Sets F64 or F128 based on algo, padding and key provided via cfg
-    pub fn set_mac(&mut self, cfg: &Config) -> Result<(), IsoError> {
-        if cfg.get_mac_algo().is_none() || cfg.get_mac_padding().is_none() || cfg.get_mac_key().is_none() {
-            return Err(IsoError { msg: format!("missing mac_algo or padding or key in call to set_mac") });
-        }
+    /// Verifies the PIN block in F52 against pin with the format and key provided via cfg
+    pub fn verify_pin(&self, pin: &str, pan: &str, cfg: &Config) -> Result<bool, IsoError> {
+        let fmt = match cfg.get_pin_fmt() {
+            Some(fmt) => fmt,
+            None => return Err(IsoError { msg: "missing pin_format in call to verify_pin".to_string() })
+        };
 
+        let pin_block = hex::decode(self.bmp_child_value(52)?).map_err(|e| IsoError { msg: format!("invalid PIN block in F52: {}", e) })?;
+        let key = self.pin_key(cfg)?;
+        cfg.get_security_module().verify_pin(fmt, pin, &pin_block, pan, &key).map_err(|e| IsoError { msg: e.msg })
+    }
 
-        if self.bmp.is_on(1) {
-            self.set_on(128, "0000000000000000")
+    /// Verifies the PIN block in F52 (with the format and key provided via cfg) against the PIN verification data of
+    /// the card (like a IBM 3624 offset or a Visa PVV) with the PVK provided via cfg
+    pub fn verify_pin_data(&self, pan: &str, data: &PinVerificationData, cfg: &Config) -> Result<bool, IsoError> {
+        let (fmt, pvk) = match (cfg.get_pin_fmt(), cfg.get_pvk()) {
+            (Some(fmt), Some(pvk)) => (fmt, pvk),
+            _ => return Err(IsoError { msg: "missing pin_format or PVK in call to verify_pin_data".to_string() })
+        };
+
+        let pin_block = hex::decode(self.bmp_child_value(52)?).map_err(|e| IsoError { msg: format!("invalid PIN block in F52: {}", e) })?;
+        let key = self.pin_key(cfg)?;
+        cfg.get_security_module().verify_pin_data(fmt, &pin_block, pan, &key, pvk, data).map_err(|e| IsoError { msg: e.msg })
+    }
+
+    /// Returns the track data of the message - from the track 2 data (F35) or else the track 1 data (F45)
+    pub fn get_track_data(&self) -> Result<TrackData, IsoError> {
+        if self.bmp.is_on(35) {
+            TrackData::parse_track2(self.bmp_child_value(35)?.as_str())
+        } else if self.bmp.is_on(45) {
+            TrackData::parse_track1(self.bmp_child_value(45)?.as_str())
         } else {
-            self.set_on(64, "0000000000000000")
-        }.unwrap();
+            Err(IsoError { msg: "no track data (F35 or F45)".to_string() })
+        }
+    }
 
+    /// Verifies the card verification value with the CVK provided via cfg - the CVV or iCVV in the discretionary data
+    /// of the track data (F35/F45) or the CVV2 in F48 (with the PAN in F2 and the expiry date in F14), at the
+    /// positions provided via cfg
+    pub fn verify_cvv(&self, cvv_type: CvvType, cfg: &Config) -> Result<bool, IsoError> {
+        let cvk = match cfg.get_cvk() {
+            Some(cvk) => cvk,
+            None => return Err(IsoError { msg: "missing CVK in call to verify_cvv".to_string() })
+        };
 
-        let data: Vec<u8> = match self.assemble() {
-            Ok(v) => {
-                v
+        let (pan, expiry, service_code, data, pos) = match cvv_type {
+            CvvType::Cvv2 => (self.bmp_child_value(2)?, self.bmp_child_value(14)?, String::new(), self.bmp_child_value(48)?, cfg.get_cvv2_position()),
+            _ => {
+                let td = self.get_track_data()?;
+                (td.pan, td.expiry, td.service_code, td.discretionary_data, cfg.get_cvv_position())
             }
-            Err(e) => {
-                return Err(e);
+        };
+        let cvv = match data.get(pos..pos + 3) {
+            Some(cvv) => cvv,
+            None => return Err(IsoError { msg: format!("no {:?} at position {} of {}", cvv_type, pos, data) })
+        };
+
+        cfg.get_security_module().verify_cvv(&pan, &expiry, cvv_type.service_code(&service_code), cvk, cvv).map_err(|e| IsoError { msg: e.msg })
+    }
+
+    /// Returns the ICC data (F55, as hex) as a list of EMV tags (like "9F26") and values
+    pub fn get_icc_data(&self) -> Result<Vec<(String, Vec<u8>)>, IsoError> {
+        let data = hex::decode(self.bmp_child_value(55)?).map_err(|e| IsoError { msg: format!("invalid ICC data in F55: {}", e) })?;
+        emv::parse_tlv(&data).map_err(|e| IsoError { msg: e.msg })
+    }
+
+    /// Returns the session key (derived by the security module of cfg) and the ARQC of the application cryptogram in
+    /// the ICC data - the PAN is that of F2 (or the track data) and the PAN sequence number that of F23 (or tag 5F34)
+    fn arqc(&self, icc_data: &[(String, Vec<u8>)], cfg: &Config) -> Result<(String, Vec<u8>), IsoError> {
+        let imk = match cfg.get_imk() {
+            Some(imk) => imk,
+            None => return Err(IsoError { msg: "missing IMK for EMV".to_string() })
+        };
+        let tag = |t: &str| match icc_data.iter().find(|(tag, _)| tag == t) {
+            Some((_, v)) => Ok(v.clone()),
+            None => Err(IsoError { msg: format!("no tag {} in ICC data", t) })
+        };
+
+        let pan = match self.bmp_child_value(2) {
+            Ok(pan) => pan,
+            Err(_) => self.get_track_data()?.pan
+        };
+        let psn = match (self.bmp_child_value(23), tag("5F34")) {
+            (Ok(psn), _) if psn.len() >= 2 => psn[psn.len() - 2..].to_string(),
+            (_, Ok(psn)) => hex::encode(psn),
+            _ => "00".to_string()
+        };
+
+        let sk = cfg.get_security_module().derive_emv_session_key(imk, &pan, &psn, &tag("9F36")?, cfg.get_session_key_derivation())
+            .map_err(|e| IsoError { msg: e.msg })?;
+        Ok((sk, tag("9F26")?))
+    }
+
+    /// Verifies the ARQC (tag 9F26) in F55 on the data of the tags provided via cfg, with the session key derived from
+    /// the IMK provided via cfg
+    pub fn verify_arqc(&self, cfg: &Config) -> Result<bool, IsoError> {
+        let icc_data = self.get_icc_data()?;
+        let (sk, arqc) = self.arqc(&icc_data, cfg)?;
+
+        let mut data = vec![];
+        for t in cfg.get_arqc_tags() {
+            match icc_data.iter().find(|(tag, _)| tag == t) {
+                Some((_, v)) => data.extend_from_slice(v),
+                None => return Err(IsoError { msg: format!("no tag {} in ICC data for the ARQC", t) })
+            }
+        }
+
+        cfg.get_security_module().verify_mac(&MacAlgo::RetailMac, cfg.get_arqc_padding(), &data, &sk, &arqc).map_err(|e| IsoError { msg: e.msg })
+    }
+
+    /// Sets the issuer authentication data (tag 91) with the ARPC for the ARQC of req (the request of this response
+    /// message) in F55, the other tags of F55 (if any) are retained
+    pub fn set_arpc(&mut self, req: &IsoMsg, method: &ArpcMethod, cfg: &Config) -> Result<(), IsoError> {
+        let (sk, arqc) = req.arqc(&req.get_icc_data()?, cfg)?;
+        let arpc = cfg.get_security_module().generate_arpc(&sk, &arqc, method).map_err(|e| IsoError { msg: e.msg })?;
+
+        let mut icc_data = if self.bmp.is_on(55) { self.get_icc_data()? } else { vec![] };
+        icc_data.retain(|(tag, _)| tag != "91");
+        icc_data.push(("91".to_string(), emv::issuer_authentication_data(&arpc, method)));
+
+        let mut data = vec![];
+        for (tag, v) in &icc_data {
+            data.extend(emv::encode_tlv(tag, v).map_err(|e| IsoError { msg: e.msg })?);
+        }
+        self.set_on(55, hex::encode_upper(data).as_str())
+    }
+
+    /// Returns the KSN (in the field at the KSN position of cfg) for DUKPT, 10 bytes for TDES and 12 bytes for AES
+    fn ksn(&self, cfg: &Config, len: usize) -> Result<Vec<u8>, IsoError> {
+        let pos = cfg.get_ksn_position();
+        let val = self.bmp_child_value(pos).map_err(|_| IsoError { msg: format!("no KSN in field {} for DUKPT", pos) })?;
+        match hex::decode(&val) {
+            Ok(ksn) if ksn.len() == len => Ok(ksn),
+            _ => Err(IsoError { msg: format!("invalid KSN in field {}: {}", pos, val) })
+        }
+    }
+
+    /// Returns the PIN key of cfg, for DUKPT the PIN key of the transaction is derived (by the security module of cfg)
+    /// from the BDK and the KSN
+    fn pin_key(&self, cfg: &Config) -> Result<String, IsoError> {
+        let key = match cfg.get_pin_key() {
+            Some(k) => k,
+            None => return Err(IsoError { msg: "missing PIN key".to_string() })
+        };
+
+        let (ksn, key_type) = match cfg.get_pin_key_scheme() {
+            KeyScheme::Static => return Ok(key.clone()),
+            KeyScheme::Dukpt => (self.ksn(cfg, 10)?, None),
+            KeyScheme::AesDukpt => match cfg.get_pin_fmt() {
+                Some(PinFormat::ISO4) => (self.ksn(cfg, 12)?, None),
+                _ => (self.ksn(cfg, 12)?, Some(Cipher::TdesEde2))
             }
         };
+        cfg.get_security_module().derive_key(key, &ksn, KeyUsage::Pin, key_type).map_err(|e| IsoError { msg: e.msg })
+    }
+
+    /// Returns the MAC key of cfg, for DUKPT the MAC key (request or response, based on the message type) of the
+    /// transaction is derived (by the security module of cfg) from the BDK and the KSN
+    fn mac_key(&self, cfg: &Config) -> Result<String, IsoError> {
+        let key = match cfg.get_mac_key() {
+            Some(k) => k,
+            None => return Err(IsoError { msg: "missing MAC key".to_string() })
+        };
+
+        if cfg.get_mac_key_scheme() == KeyScheme::Static {
+            return Ok(key.clone());
+        }
+
+        // the 3rd digit of the MTI is the message function - odd for responses
+        let is_response = match self.get_field_value(&"message_type".to_string()) {
+            Ok(mti) => mti.chars().nth(2).and_then(|c| c.to_digit(10)).is_some_and(|d| d % 2 == 1),
+            Err(_) => false
+        };
+        let usage = if is_response { KeyUsage::MacResponse } else { KeyUsage::MacRequest };
+        let (ksn, key_type) = match (cfg.get_mac_key_scheme(), cfg.get_mac_algo()) {
+            (KeyScheme::AesDukpt, Some(MacAlgo::AesCmac)) | (KeyScheme::AesDukpt, Some(MacAlgo::HmacSha256)) => (self.ksn(cfg, 12)?, None),
+            (KeyScheme::AesDukpt, _) => (self.ksn(cfg, 12)?, Some(Cipher::TdesEde2)),
+            _ => (self.ksn(cfg, 10)?, None)
+        };
+        cfg.get_security_module().derive_key(key, &ksn, usage, key_type).map_err(|e| IsoError { msg: e.msg })
+    }
+
+    /// Translates the PIN block in F52 from the PIN format and key of in_cfg to those of out_cfg (and sets F53 if
+    /// out_cfg has a security control info), as a switch does when forwarding a request from the acquirer to the issuer.
+    /// Both keys should be keys of the same security module (in_cfg and out_cfg should have the same module)
+    pub fn translate_pin(&mut self, pan: &str, in_cfg: &Config, out_cfg: &Config) -> Result<(), IsoError> {
+        if !std::ptr::addr_eq(in_cfg.get_security_module(), out_cfg.get_security_module()) {
+            return Err(IsoError { msg: "the PIN keys of translate_pin should be keys of the same security module".to_string() });
+        }
+        let (in_fmt, out_fmt) = match (in_cfg.get_pin_fmt(), in_cfg.get_pin_key(), out_cfg.get_pin_fmt(), out_cfg.get_pin_key()) {
+            (Some(in_fmt), Some(_), Some(out_fmt), Some(_)) => (in_fmt, out_fmt),
+            _ => return Err(IsoError { msg: "missing pin_format or key in call to translate_pin".to_string() })
+        };
+
+        let pin_block = match self.bmp_child_value(52) {
+            Ok(v) => hex::decode(v).map_err(|e| IsoError { msg: format!("invalid PIN block in F52: {}", e) })?,
+            Err(e) => return Err(e)
+        };
+
+        let in_key = self.pin_key(in_cfg)?;
+        let out_key = self.pin_key(out_cfg)?;
+        let pin_block = match in_cfg.get_security_module().translate_pin_block(in_fmt, &in_key, out_fmt, &out_key, &pin_block, pan) {
+            Ok(v) => v,
+            Err(e) => return Err(IsoError { msg: e.msg })
+        };
+
+        // a ISO-4 PIN block (16 bytes) doesn't fit in a F52 of 8 bytes
+        if let Some(len) = self.msg.field_by_name(&"bitmap".to_string())?.child_by_pos(52).fixed_len() {
+            if pin_block.len() != len as usize {
+                return Err(IsoError { msg: format!("{:?} PIN block of {} bytes does not fit in F52 of {} bytes", out_fmt, pin_block.len(), len) });
+            }
+        }
+        self.set_on(52, hex::encode(pin_block).as_str())?;
+
+        match out_cfg.get_security_control_info() {
+            Some(sci) => self.set_on(53, sci.as_str()),
+            None => Ok(())
+        }
+    }
+
+    /// Sets F64 or F128 based on algo, padding, key and MAC input (the full message or a list of fields) provided via cfg
+    pub fn set_mac(&mut self, cfg: &Config) -> Result<(), IsoError> {
+        if cfg.get_mac_algo().is_none() || cfg.get_mac_padding().is_none() || cfg.get_mac_key().is_none() {
+            return Err(IsoError { msg: "missing mac_algo or padding or key in call to set_mac".to_string() });
+        }
+
+
+        let mac_len = cfg.get_mac_length();
+        let pos = if self.bmp.is_on(1) { 128 } else { 64 };
+        self.set_on(pos, "00".repeat(mac_len).as_str())?;
+
+
+        let data: Vec<u8> = self.mac_message()?;
 
         debug!("generating mac on: {}", hex::encode(&data));
 
-        match generate_mac(&cfg.get_mac_algo().as_ref().unwrap(), &cfg.get_mac_padding().as_ref().unwrap(),
-                           &data[0..data.len() - 8].to_vec(), &hex::decode(cfg.get_mac_key().as_ref().unwrap()).unwrap()) {
+        let data = match cfg.get_mac_input() {
+            MacInput::FullMessage => data[0..data.len() - mac_len].to_vec(),
+            MacInput::Fields(fields) => fields.mac_data(|f| self.operand_value(f))
+        };
+
+        let key = self.mac_key(cfg)?;
+        match cfg.get_security_module().generate_mac(cfg.get_mac_algo().as_ref().unwrap(), cfg.get_mac_padding().as_ref().unwrap(),
+                                                     &data, &key).and_then(|v| Ok(truncate_mac(v, mac_len)?)) {
             Ok(v) => {
-                let pos: u32;
-                if self.bmp.is_on(1) {
-                    pos = 128;
-                } else {
-                    pos = 64;
-                }
                 self.set_on(pos, hex::encode(v).as_str()).unwrap_or_default();
                 Ok(())
             }
@@ -336,31 +863,182 @@ impl IsoMsg {
             }
         }
     }
+
+    /// Verifies the MAC in F64 or F128 with the algo, padding, key and MAC input provided via cfg. For the full message,
+    /// the MAC is computed on the data exactly as received (or on the assembled message if it wasn't parsed) from the
+    /// MTI on, i.e without the wire header. The MAC can be truncated
+    pub fn verify_mac(&self, cfg: &Config) -> Result<bool, IsoError> {
+        let (algo, padding) = match (cfg.get_mac_algo(), cfg.get_mac_padding()) {
+            (Some(algo), Some(padding)) => (algo, padding),
+            _ => return Err(IsoError { msg: "missing mac_algo or padding in call to verify_mac".to_string() })
+        };
+
+        let pos = if self.bmp.is_on(1) { 128 } else { 64 };
+        if !self.bmp.is_on(pos) {
+            return Err(IsoError { msg: format!("no MAC in F{}", pos) });
+        }
+        let expected_mac = hex::decode(self.bmp_child_value(pos)?).map_err(|e| IsoError { msg: format!("invalid MAC in F{}: {}", pos, e) })?;
+
+        let data = match cfg.get_mac_input() {
+            MacInput::FullMessage => {
+                let mut mac_field = vec![];
+                let f = self.msg.field_by_name(&"bitmap".to_string())?.child_by_pos(pos);
+                f.assemble(&mut mac_field, self).map_err(|e| IsoError { msg: e.msg })?;
+
+                let mut data = self.mac_message()?;
+                if !data.ends_with(&mac_field) {
+                    return Err(IsoError { msg: format!("F{} is not the last field of the message", pos) });
+                }
+                data.truncate(data.len() - mac_field.len());
+                data
+            }
+            MacInput::Fields(fields) => fields.mac_data(|f| self.operand_value(f))
+        };
+
+        let key = self.mac_key(cfg)?;
+        cfg.get_security_module().verify_mac(algo, padding, &data, &key, &expected_mac).map_err(|e| IsoError { msg: e.msg })
+    }
+}
+
+/// Returns val in the wire format of field f, fixed fields are checked for their length
+fn to_raw(f: &dyn Field, val: &str) -> Result<Vec<u8>, IsoError> {
+    let raw = f.to_raw(val);
+    match f.fixed_len() {
+        Some(len) if raw.len() != len as usize => {
+            Err(IsoError { msg: format!("invalid length {} for fixed field {} (expected {}): {}", raw.len(), f.name(), len, val) })
+        }
+        _ => Ok(raw)
+    }
+}
+
+fn collect_children(f: &'static dyn Field, ordered_fields: &mut Vec<&'static dyn Field>) {
+    ordered_fields.push(f);
+    f.children().into_iter().for_each(|f| collect_children(f, ordered_fields));
+}
+
+impl IsoMsg {
+    /// Returns the message as received (or assembled if it wasn't parsed) without the wire header (like a TPDU) that
+    /// is not covered by the MAC
+    fn mac_message(&self) -> Result<Vec<u8>, IsoError> {
+        let mut wire_header = vec![];
+        for f in &self.spec.wire_header_fields {
+            f.assemble(&mut wire_header, self).map_err(|e| IsoError { msg: e.msg })?;
+        }
+
+        let mut data = match &self.raw {
+            Some(raw) => raw.clone(),
+            None => self.assemble()?
+        };
+        if !data.starts_with(&wire_header) {
+            return Err(IsoError { msg: "wire header does not match the message data".to_string() });
+        }
+        Ok(data.split_off(wire_header.len()))
+    }
+
+    /// Returns all the fields of the message (wire header, header and bitmap fields) in the order of the spec
+    fn ordered_fields(&self) -> Vec<&'static dyn Field> {
+        let mut ordered_fields: Vec<&'static dyn Field> = vec![];
+        self.spec.wire_header_fields.iter().for_each(|f| ordered_fields.push(f.as_ref()));
+        self.msg.fields.iter().for_each(|f| collect_children(f.as_ref(), &mut ordered_fields));
+        ordered_fields
+    }
+
+    /// Compares this message with other and returns the fields that were added (present only in other), removed
+    /// (present only in this message) or changed, in the order of the spec
+    pub fn diff(&self, other: &IsoMsg) -> Vec<FieldDiff> {
+        let mut fields = self.ordered_fields();
+        other.ordered_fields().into_iter().for_each(|f| {
+            if !fields.iter().any(|e| e.name() == f.name()) {
+                fields.push(f);
+            }
+        });
+
+        let mut diffs = vec![];
+        for f in fields.into_iter().filter(|f| f.children().is_empty()) {
+            let old = self.fd_map.get(f.name()).map(|v| f.to_string(v));
+            let new = other.fd_map.get(f.name()).map(|v| f.to_string(v));
+            let kind = match (&old, &new) {
+                (None, Some(_)) => DiffKind::Added,
+                (Some(_), None) => DiffKind::Removed,
+                (Some(o), Some(n)) if o != n => DiffKind::Changed,
+                _ => continue
+            };
+            diffs.push(FieldDiff { name: f.name().clone(), position: f.position(), kind, old, new });
+        }
+        diffs
+    }
+}
+
+/// This enum represents the kind of difference in a field between two messages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiffKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// This struct represents a difference in a field between two messages (see `IsoMsg::diff`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDiff {
+    pub name: String,
+    /// Position of the field in the bitmap (or 0 for a top level field)
+    pub position: u32,
+    pub kind: DiffKind,
+    /// The value in the first message (if present)
+    pub old: Option<String>,
+    /// The value in the other message (if present)
+    pub new: Option<String>,
+}
+
+impl Display for FieldDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let pos = if self.position > 0 { format!(" ({:03})", self.position) } else { String::new() };
+        match self.kind {
+            DiffKind::Added => write!(f, "+ {}{}: {}", self.name, pos, self.new.as_deref().unwrap_or_default()),
+            DiffKind::Removed => write!(f, "- {}{}: {}", self.name, pos, self.old.as_deref().unwrap_or_default()),
+            DiffKind::Changed => write!(f, "~ {}{}: {} -> {}", self.name, pos, self.old.as_deref().unwrap_or_default(),
+                                        self.new.as_deref().unwrap_or_default()),
+        }
+    }
+}
+
+/// Two messages are equal if they are of the same spec and segment and have the same field values
+impl PartialEq for IsoMsg {
+    fn eq(&self, other: &Self) -> bool {
+        self.spec.name == other.spec.name && self.msg.name == other.msg.name &&
+            self.bmp == other.bmp && self.fd_map == other.fd_map
+    }
 }
 
-fn collect_children(f: &dyn Field, ordered_fields: &mut Vec<String>) {
-    ordered_fields.push(f.name().clone());
-    f.children().iter().for_each(|f| collect_children(*f, ordered_fields));
+impl std::fmt::Debug for IsoMsg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let fields: Vec<(String, String)> = self.ordered_fields().into_iter().filter_map(|fld| {
+            self.fd_map.get(fld.name()).map(|v| (fld.name().clone(), fld.to_string(v)))
+        }).collect();
+
+        f.debug_struct("IsoMsg")
+            .field("spec", &self.spec.name)
+            .field("msg", &self.msg.name)
+            .field("bmp", &self.bmp)
+            .field("fields", &fields)
+            .finish()
+    }
 }
 
 impl Display for IsoMsg {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
         let mut res = "".to_string();
-        let mut ordered_fields = vec![];
-        self.msg.fields.iter().for_each(|f| collect_children(f.as_ref(), &mut ordered_fields));
 
         res = res + format!("\n{:20.40} : {:5}  : {} ", "-Field-", "-Position-", "-Field Value-").as_str();
-        for f in ordered_fields {
-            if self.fd_map.contains_key(f.as_str()) {
-                let field = self.msg.field_by_name(&f).unwrap();
-                let field_value = &self.fd_map.get(f.as_str()).unwrap();
+        for field in self.ordered_fields() {
+            if let Some(field_value) = self.fd_map.get(field.name()) {
                 let mut pos_str: String = String::new();
                 if field.position() > 0 {
                     pos_str = format!("{:03}", field.position());
                 }
 
                 //debug!("** formatting {}",field.name());
-                res = res + format!("\n{:20.40} : {:^10}  : {} ", f, pos_str.as_str(), field.to_string(field_value)).as_str();
+                res += format!("\n{:20.40} : {:^10}  : {} ", field.name(), pos_str.as_str(), field.to_string(field_value)).as_str();
             }
         }
         f.write_str(&res).unwrap();
@@ -374,6 +1052,15 @@ pub fn spec(_name: &str) -> &'static Spec {
     ALL_SPECS.iter().find_map(|(_k, v)| Some(v)).unwrap()
 }
 
+/// Returns a built-in spec given its name - one of ISO8583_1987_ASCII, ISO8583_1987_BINARY, ISO8583_1993_ASCII,
+/// ISO8583_1993_BINARY, ISO8583_2003_ASCII or ISO8583_2003_BINARY
+pub fn builtin_spec(name: &str) -> Result<&'static Spec, IsoError> {
+    match BUILTIN_SPECS.get(name) {
+        Some(spec) => Ok(spec),
+        None => Err(IsoError { msg: format!("no such built-in spec - {}", name) })
+    }
+}
+
 /// Returns a empty IsoMsg that can be used to create a message
 pub fn new_msg(spec: &'static Spec, seg: &'static MessageSegment) -> IsoMsg {
     IsoMsg {
@@ -381,9 +1068,18 @@ pub fn new_msg(spec: &'static Spec, seg: &'static MessageSegment) -> IsoMsg {
         msg: seg,
         fd_map: HashMap::new(),
         bmp: Bitmap::new(0, 0, 0),
+        raw: None,
     }
 }
 
+/// Returns a empty IsoMsg that is a response to req_msg, the wire header (if any) of req_msg is copied
+/// with the source and destination fields swapped
+pub fn new_response_msg(req_msg: &IsoMsg, seg: &'static MessageSegment) -> Result<IsoMsg, IsoError> {
+    let mut resp_msg = new_msg(req_msg.spec, seg);
+    resp_msg.echo_wire_header(req_msg)?;
+    Ok(resp_msg)
+}
+
 impl Spec {
     /// Returns a IsoMsg after parsing data or an ParseError on failure
     pub fn parse(&'static self, data: &mut Vec<u8>) -> Result<IsoMsg, ParseError> {
@@ -397,10 +1093,16 @@ impl Spec {
             msg: &msg.unwrap(),
             fd_map: HashMap::new(),
             bmp: Bitmap::new(0, 0, 0),
+            raw: None,
         };
 
         let mut cp_data = Cursor::new(data);
 
+        for f in &self.wire_header_fields {
+            debug!("parsing wire header field : {}", f.name());
+            f.parse(&mut cp_data, &mut iso_msg.fd_map)?;
+        }
+
         for f in &iso_msg.msg.fields {
             debug!("parsing field : {}", f.name());
             let res = match f.parse(&mut cp_data, &mut iso_msg.fd_map) {
@@ -419,6 +1121,793 @@ impl Spec {
                 return Result::Err(res.err().unwrap());
             }
         }
+
+        let len = cp_data.position() as usize;
+        iso_msg.raw = Some(cp_data.into_inner()[0..len].to_vec());
+
+        if self.validate {
+            if let Err(violations) = iso_msg.validate() {
+                return Err(ParseError { msg: format!("{} failed validation: {}", iso_msg.msg.name, violations_to_string(&violations)) });
+            }
+        }
         Ok(iso_msg)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::iso8583::iso_spec::{builtin_spec, new_msg, new_response_msg, DiffKind, FieldDiff, Spec};
+    use crate::iso8583::yaml_de::read_spec;
+    use crate::iso8583::defaults::Stan;
+    use crate::iso8583::amount::Amount;
+    use crate::iso8583::config::{Config, MacFields, MacInput};
+    use crate::crypto::pin::{PinFormat, verify_pin};
+    use crate::crypto::pin_verification::PinVerificationData;
+    use crate::crypto::cvv::CvvType;
+    use crate::crypto::emv::{ArpcMethod, SessionKeyDerivation};
+    use crate::crypto::mac::{generate_mac, MacAlgo, PaddingType};
+    use crate::crypto::Cipher;
+    use crate::crypto::dukpt::{self, KeyUsage};
+    use crate::crypto::security_module::{SecurityModule, SoftwareSecurityModule};
+    use crate::crypto::CryptoError;
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /// A security module whose keys are referred to by name (like the keys of a HSM)
+    struct NamedKeySecurityModule {
+        keys: HashMap<String, String>,
+    }
+
+    impl NamedKeySecurityModule {
+        fn key(&self, name: &str) -> Result<&str, CryptoError> {
+            self.keys.get(name).map(|k| k.as_str()).ok_or(CryptoError { msg: format!("unknown key: {}", name) })
+        }
+    }
+
+    impl SecurityModule for NamedKeySecurityModule {
+        fn generate_pin_block(&self, fmt: &PinFormat, pin: &str, pan: &str, key: &str) -> Result<Vec<u8>, CryptoError> {
+            SoftwareSecurityModule {}.generate_pin_block(fmt, pin, pan, self.key(key)?)
+        }
+
+        fn verify_pin(&self, fmt: &PinFormat, expected_pin: &str, pin_block: &[u8], pan: &str, key: &str) -> Result<bool, CryptoError> {
+            SoftwareSecurityModule {}.verify_pin(fmt, expected_pin, pin_block, pan, self.key(key)?)
+        }
+
+        fn translate_pin_block(&self, in_fmt: &PinFormat, in_key: &str, out_fmt: &PinFormat, out_key: &str, pin_block: &[u8], pan: &str) -> Result<Vec<u8>, CryptoError> {
+            SoftwareSecurityModule {}.translate_pin_block(in_fmt, self.key(in_key)?, out_fmt, self.key(out_key)?, pin_block, pan)
+        }
+
+        fn generate_mac(&self, algo: &MacAlgo, padding: &PaddingType, data: &[u8], key: &str) -> Result<Vec<u8>, CryptoError> {
+            SoftwareSecurityModule {}.generate_mac(algo, padding, data, self.key(key)?)
+        }
+
+        fn verify_mac(&self, algo: &MacAlgo, padding: &PaddingType, data: &[u8], key: &str, mac: &[u8]) -> Result<bool, CryptoError> {
+            SoftwareSecurityModule {}.verify_mac(algo, padding, data, self.key(key)?, mac)
+        }
+
+        fn derive_key(&self, _bdk: &str, _ksn: &[u8], _usage: KeyUsage, _key_type: Option<Cipher>) -> Result<String, CryptoError> {
+            Err(CryptoError { msg: "DUKPT is not supported".to_string() })
+        }
+    }
+
+    fn tpdu_spec() -> &'static Spec {
+        let path = Path::new(".").join("sample_spec").join("sample_spec_tpdu.yaml");
+        Box::leak(Box::new(read_spec(path.to_str().unwrap()).unwrap()))
+    }
+
+    #[test]
+    fn test_wire_header_assemble_parse() {
+        let spec = tpdu_spec();
+        let seg = spec.get_message_from_header("0100").unwrap();
+
+        let mut iso_msg = new_msg(spec, seg);
+        iso_msg.set("tpdu_id", "60").unwrap();
+        iso_msg.set("tpdu_dest", "0001").unwrap();
+        iso_msg.set("tpdu_src", "0002").unwrap();
+        iso_msg.set("message_type", "0100").unwrap();
+        iso_msg.set_on(3, "004000").unwrap();
+        iso_msg.set_on(11, "779581").unwrap();
+
+        let mut data = iso_msg.assemble().unwrap();
+        assert_eq!(hex::encode(&data[0..9]), "600001000230313030");
+
+        let parsed = spec.parse(&mut data).unwrap();
+        assert_eq!(parsed.get_field_value(&"tpdu_id".to_string()).unwrap(), "60");
+        assert_eq!(parsed.get_field_value(&"tpdu_dest".to_string()).unwrap(), "0001");
+        assert_eq!(parsed.get_field_value(&"tpdu_src".to_string()).unwrap(), "0002");
+        assert_eq!(parsed.get_field_value(&"message_type".to_string()).unwrap(), "0100");
+        assert_eq!(parsed.bmp_child_value(11).unwrap(), "779581");
+    }
+
+    #[test]
+    fn test_wire_header_swapped_in_response() {
+        let spec = tpdu_spec();
+        let seg = spec.get_message_from_header("0100").unwrap();
+
+        let mut req_msg = new_msg(spec, seg);
+        req_msg.set("tpdu_id", "60").unwrap();
+        req_msg.set("tpdu_dest", "0001").unwrap();
+        req_msg.set("tpdu_src", "0002").unwrap();
+
+        let mut resp_msg = new_response_msg(&req_msg, spec.get_message_from_header("0110").unwrap()).unwrap();
+        resp_msg.set("message_type", "0110").unwrap();
+        resp_msg.set_on(39, "000").unwrap();
+
+        assert_eq!(resp_msg.get_field_value(&"tpdu_id".to_string()).unwrap(), "60");
+        assert_eq!(resp_msg.get_field_value(&"tpdu_dest".to_string()).unwrap(), "0002");
+        assert_eq!(resp_msg.get_field_value(&"tpdu_src".to_string()).unwrap(), "0001");
+        assert_eq!(hex::encode(&resp_msg.assemble().unwrap()[0..5]), "6000020001");
+    }
+
+    #[test]
+    fn test_wire_header_missing_in_request() {
+        let spec = tpdu_spec();
+        let req_msg = new_msg(spec, spec.get_message_from_header("0100").unwrap());
+        assert!(new_response_msg(&req_msg, spec.get_message_from_header("0110").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_builtin_1987_ascii() {
+        let spec = builtin_spec("ISO8583_1987_ASCII").unwrap();
+        let seg = spec.get_message_from_header("0200").unwrap();
+
+        let mut iso_msg = new_msg(spec, seg);
+        iso_msg.set("message_type", "0200").unwrap();
+        iso_msg.set_on(2, "4111111111111111").unwrap();
+        iso_msg.set_on(3, "000000").unwrap();
+        iso_msg.set_on(4, "000000001000").unwrap();
+        iso_msg.set_on(11, "000001").unwrap();
+        iso_msg.set_on(41, "TERM0001").unwrap();
+        iso_msg.set_on(102, "ACC1").unwrap();
+
+        let mut data = iso_msg.assemble().unwrap();
+        assert_eq!(String::from_utf8(data[0..36].to_vec()).unwrap(), "0200F0200000008000000000000004000000");
+
+        let parsed = spec.parse(&mut data).unwrap();
+        assert_eq!(parsed.msg.name(), "ISO8583_1987");
+        assert_eq!(parsed.bmp_child_value(2).unwrap(), "4111111111111111");
+        assert_eq!(parsed.bmp_child_value(41).unwrap(), "TERM0001");
+        assert_eq!(parsed.bmp_child_value(102).unwrap(), "ACC1");
+    }
+
+    #[test]
+    fn test_builtin_1993_binary() {
+        let spec = builtin_spec("ISO8583_1993_BINARY").unwrap();
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("1100").unwrap());
+        iso_msg.set("message_type", "1100").unwrap();
+        iso_msg.set_on(3, "000000").unwrap();
+        iso_msg.set_on(12, "200101120000").unwrap();
+        iso_msg.set_on(24, "100").unwrap();
+
+        let mut data = iso_msg.assemble().unwrap();
+        assert_eq!(hex::encode(&data[4..12]), "2010010000000000");
+
+        let parsed = spec.parse(&mut data).unwrap();
+        assert_eq!(parsed.bmp_child_value(12).unwrap(), "200101120000");
+        assert_eq!(parsed.bmp_child_value(24).unwrap(), "100");
+    }
+
+    #[test]
+    fn test_builtin_2003_overrides_1993() {
+        let spec = builtin_spec("ISO8583_2003_ASCII").unwrap();
+        assert!(spec.get_message_from_header("1100").is_err());
+
+        let seg = spec.get_message_from_header("2100").unwrap();
+        assert_eq!(seg.name(), "ISO8583_2003");
+        assert_eq!(seg.field_by_name(&"date_time_local_txn".to_string()).unwrap().position(), 12);
+        assert_eq!(seg.field_by_name(&"action_code".to_string()).unwrap().position(), 39);
+
+        let mut iso_msg = new_msg(spec, seg);
+        iso_msg.set("message_type", "2100").unwrap();
+        iso_msg.set_on(12, "20200101120000").unwrap();
+        let mut data = iso_msg.assemble().unwrap();
+        assert_eq!(spec.parse(&mut data).unwrap().bmp_child_value(12).unwrap(), "20200101120000");
+
+        assert!(builtin_spec("ISO8583_2020").is_err());
+    }
+
+    #[test]
+    fn test_spec_extends_builtin() {
+        let path = Path::new(".").join("sample_spec").join("sample_spec_extends.yaml");
+        let spec: &'static Spec = Box::leak(Box::new(read_spec(path.to_str().unwrap()).unwrap()));
+        assert_eq!(spec.name(), "SampleExtendedSpec");
+
+        assert!(spec.get_message_from_header("0200").is_err());
+
+        let seg = spec.get_message_from_header("0110").unwrap();
+        assert_eq!(seg.field_by_name(&"merchant_data".to_string()).unwrap().position(), 48);
+        assert_eq!(seg.field_by_name(&"pan".to_string()).unwrap().position(), 2);
+
+        let mut iso_msg = new_msg(spec, seg);
+        iso_msg.set("message_type", "0110").unwrap();
+        iso_msg.set_on(39, "00").unwrap();
+        iso_msg.set_on(48, "ABC").unwrap();
+        let mut data = iso_msg.assemble().unwrap();
+        assert_eq!(hex::encode(&data[data.len() - 5..]), "3033c1c2c3");
+
+        let parsed = spec.parse(&mut data).unwrap();
+        assert_eq!(parsed.bmp_child_value(48).unwrap(), "ABC");
+    }
+
+    #[test]
+    fn test_spec_extends_spec_file() {
+        let path = Path::new(".").join("sample_spec").join("sample_spec_child.yaml");
+        let spec: &'static Spec = Box::leak(Box::new(read_spec(path.to_str().unwrap()).unwrap()));
+        assert_eq!(spec.messages.len(), 3);
+
+        // F63 is redefined in the dictionary of the child spec and applies to all the inherited segments
+        for mti in ["1100", "1420", "1200"].iter() {
+            let seg = spec.get_message_from_header(mti).unwrap();
+            let mut iso_msg = new_msg(spec, seg);
+            iso_msg.set("message_type", mti).unwrap();
+            iso_msg.set_on(63, "007").unwrap();
+            let data = iso_msg.assemble().unwrap();
+            assert_eq!(String::from_utf8(data[data.len() - 5..].to_vec()).unwrap(), "03007");
+        }
+
+        // the financial segment inherits the fields of the authorization segment
+        let seg = spec.get_message_from_header("1210").unwrap();
+        assert_eq!(seg.name(), "1200 - Financial");
+        assert_eq!(seg.field_by_name(&"terminal_id".to_string()).unwrap().position(), 41);
+        assert_eq!(seg.field_by_name(&"pin_data".to_string()).unwrap().position(), 52);
+        assert!(spec.get_message_from_header("1420").unwrap().field_by_name(&"mac_1".to_string()).is_err());
+    }
+
+    fn sample_spec() -> &'static Spec {
+        let path = Path::new(".").join("sample_spec").join("sample_spec.yaml");
+        Box::leak(Box::new(read_spec(path.to_str().unwrap()).unwrap()))
+    }
+
+    #[test]
+    fn test_validate() {
+        let spec = sample_spec();
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("1100").unwrap());
+        iso_msg.set("message_type", "1100").unwrap();
+        iso_msg.set_on(2, "4567909845671235").unwrap();
+        iso_msg.set_on(4, "000000000029").unwrap();
+        iso_msg.set_on(52, "0102030405060708").unwrap();
+
+        let violations = iso_msg.validate().err().unwrap();
+        assert_eq!(violations.iter().map(|v| v.position).collect::<Vec<u32>>(), vec![3, 11, 14]);
+        assert_eq!(violations[0].field, "proc_code");
+
+        iso_msg.set_on(3, "004000").unwrap();
+        iso_msg.set_on(11, "779581").unwrap();
+        iso_msg.set_on(14, "2204").unwrap();
+        assert!(iso_msg.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_response() {
+        let spec = sample_spec();
+        let mut req_msg = new_msg(spec, spec.get_message_from_header("1100").unwrap());
+        req_msg.set("message_type", "1100").unwrap();
+        req_msg.set_on(2, "4567909845671235").unwrap();
+        req_msg.set_on(3, "004000").unwrap();
+        req_msg.set_on(4, "000000000029").unwrap();
+        req_msg.set_on(11, "779581").unwrap();
+
+        let mut resp_msg = new_msg(spec, spec.get_message_from_header("1110").unwrap());
+        resp_msg.set("message_type", "1110").unwrap();
+        resp_msg.echo_from(&req_msg, &[2, 3, 4]).unwrap();
+        resp_msg.set_on(11, "000001").unwrap();
+        resp_msg.set_on(52, "0102030405060708").unwrap();
+
+        let violations = resp_msg.validate_response(&req_msg).err().unwrap();
+        assert_eq!(violations.iter().map(|v| v.position).collect::<Vec<u32>>(), vec![11, 14, 39, 52]);
+
+        let mut resp_msg = new_msg(spec, spec.get_message_from_header("1110").unwrap());
+        resp_msg.set("message_type", "1110").unwrap();
+        resp_msg.echo_from(&req_msg, &[2, 3, 4, 11]).unwrap();
+        resp_msg.set_on(39, "000").unwrap();
+        assert!(resp_msg.validate_response(&req_msg).is_ok());
+    }
+
+    #[test]
+    fn test_validate_on_assemble_and_parse() {
+        let path = Path::new(".").join("sample_spec").join("sample_spec.yaml");
+        let mut spec = read_spec(path.to_str().unwrap()).unwrap();
+        spec.validate = true;
+        let spec: &'static Spec = Box::leak(Box::new(spec));
+
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("1100").unwrap());
+        iso_msg.set("message_type", "1100").unwrap();
+        iso_msg.set_on(2, "4567909845671235").unwrap();
+        iso_msg.set_on(3, "004000").unwrap();
+        iso_msg.set_on(4, "000000000029").unwrap();
+
+        let err = iso_msg.assemble().err().unwrap();
+        assert_eq!(err.msg, "1100 - Authorization failed validation: stan (011): Echo field is missing");
+
+        iso_msg.set_on(11, "779581").unwrap();
+        let mut data = iso_msg.assemble().unwrap();
+        assert!(spec.parse(&mut data).is_ok());
+    }
+
+    #[test]
+    fn test_apply_defaults() {
+        let spec = builtin_spec("ISO8583_1987_ASCII").unwrap();
+        let stan = Stan::new();
+
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("0200").unwrap());
+        iso_msg.set("message_type", "0200").unwrap();
+        iso_msg.apply_defaults(&stan).unwrap();
+
+        assert_eq!(iso_msg.bmp_child_value(7).unwrap().len(), 10);
+        assert_eq!(iso_msg.bmp_child_value(11).unwrap(), "000001");
+        assert_eq!(iso_msg.bmp_child_value(12).unwrap().len(), 6);
+        assert_eq!(iso_msg.bmp_child_value(13).unwrap().len(), 4);
+        assert!(iso_msg.bmp_child_value(37).unwrap().ends_with("000001"));
+        assert!(!iso_msg.bmp.is_on(2));
+
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("0200").unwrap());
+        iso_msg.set("message_type", "0200").unwrap();
+        iso_msg.apply_defaults(&stan).unwrap();
+        assert_eq!(iso_msg.bmp_child_value(11).unwrap(), "000002");
+    }
+
+    #[test]
+    fn test_apply_defaults_keeps_values() {
+        let spec = sample_spec();
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("1100").unwrap());
+        iso_msg.set("message_type", "1100").unwrap();
+        iso_msg.set_on(11, "779581").unwrap();
+        iso_msg.apply_defaults(&Stan::new()).unwrap();
+
+        assert_eq!(iso_msg.bmp_child_value(11).unwrap(), "779581");
+        assert_eq!(iso_msg.bmp_child_value(19).unwrap(), "840");
+    }
+
+    #[test]
+    fn test_echo_fixed_non_ascii() {
+        let spec = builtin_spec("ISO8583_1987_ASCII").unwrap();
+        let mut req_msg = new_msg(spec, spec.get_message_from_header("0100").unwrap());
+        req_msg.set("message_type", "0100").unwrap();
+        req_msg.set_on(11, "779581").unwrap();
+        req_msg.set_on(43, format!("{:<40}", "Cafe Paris").as_str()).unwrap();
+        let mut data = req_msg.assemble().unwrap();
+
+        // the card acceptor name in ISO-8859-1 (not valid UTF-8)
+        let i = data.windows(4).position(|w| w == b"Cafe").unwrap();
+        data[i + 3] = 0xe9;
+        let req_msg = spec.parse(&mut data).unwrap();
+        let name = req_msg.bmp_child_value(43).unwrap();
+        assert!(name.starts_with("Caf\u{e9} Paris"));
+
+        // the received bytes are echoed unchanged
+        let mut resp_msg = new_msg(spec, spec.get_message_from_header("0110").unwrap());
+        resp_msg.set("message_type", "0110").unwrap();
+        resp_msg.echo_from(&req_msg, &[11, 43]).unwrap();
+        resp_msg.set_on(39, "00").unwrap();
+        let resp_data = resp_msg.assemble().unwrap();
+        assert_eq!(&resp_data[resp_data.len() - 40..], &data[data.len() - 40..]);
+
+        // as a ASCII (UTF-8) string the name is 41 bytes
+        let err = resp_msg.set_on(43, name.as_str()).err().unwrap();
+        assert_eq!(err.msg, format!("invalid length 41 for fixed field card_acceptor_name_location (expected 40): {}", name));
+        assert!(resp_msg.set_on(11, "77958").is_err());
+    }
+
+    #[test]
+    fn test_amounts() {
+        let spec = builtin_spec("ISO8583_1987_ASCII").unwrap();
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("0200").unwrap());
+        iso_msg.set("message_type", "0200").unwrap();
+        iso_msg.set_on(4, "000000012550").unwrap();
+        iso_msg.set_on(49, "840").unwrap();
+        iso_msg.set_on(28, "D00000150").unwrap();
+
+        let amt = iso_msg.get_amount(4).unwrap();
+        assert_eq!(amt.to_string(), "125.50");
+        assert_eq!(amt.currency.as_deref(), Some("840"));
+        assert_eq!(iso_msg.get_amount(28).unwrap().to_string(), "-1.50");
+
+        // F29 (settlement fee) is in the settlement currency (F50)
+        iso_msg.set_amount(29, &Amount::in_currency(-2500, "JPY").unwrap()).unwrap();
+        assert_eq!(iso_msg.bmp_child_value(29).unwrap(), "D00002500");
+        assert_eq!(iso_msg.bmp_child_value(50).unwrap(), "392");
+        assert_eq!(iso_msg.get_amount(29).unwrap().to_string(), "-2500");
+
+        iso_msg.set_on(30, "X00000150").unwrap();
+        assert!(iso_msg.get_amount(30).is_err());
+        assert!(iso_msg.get_amount(31).is_err());
+        assert!(iso_msg.get_amount(2).is_err());
+        // F49 is 840 (USD), so the amount must have 2 decimals
+        assert!(iso_msg.set_amount(4, &Amount::new(100, 3)).is_err());
+        iso_msg.set_amount(4, &Amount::new(100, 2)).unwrap();
+        assert_eq!(iso_msg.get_amount(4).unwrap().to_string(), "1.00");
+        assert!(iso_msg.set_amount(5, &Amount { minor_units: 100, exponent: 3, currency: Some("840".to_string()) }).is_err());
+
+        // a amount that does not fit leaves both the amount and the currency fields as they were
+        assert!(iso_msg.set_amount(29, &Amount::in_currency(i64::MIN, "EUR").unwrap()).is_err());
+        assert_eq!(iso_msg.bmp_child_value(29).unwrap(), "D00002500");
+        assert_eq!(iso_msg.bmp_child_value(50).unwrap(), "392");
+    }
+
+    #[test]
+    fn test_clone_eq_and_diff() {
+        let spec = sample_spec();
+        let mut req_msg = new_msg(spec, spec.get_message_from_header("1100").unwrap());
+        req_msg.set("message_type", "1100").unwrap();
+        req_msg.set_on(4, "000000000100").unwrap();
+        req_msg.set_on(11, "779581").unwrap();
+
+        let mut rev_msg = req_msg.clone();
+        assert_eq!(rev_msg, req_msg);
+        assert!(rev_msg.diff(&req_msg).is_empty());
+
+        req_msg.set_on(2, "4567909845671234").unwrap();
+        rev_msg.set("message_type", "1420").unwrap();
+        rev_msg.set_on(39, "400").unwrap();
+        assert_ne!(rev_msg, req_msg);
+
+        let diffs: Vec<String> = req_msg.diff(&rev_msg).iter().map(|d| d.to_string()).collect();
+        assert_eq!(diffs, vec!["~ message_type: 1100 -> 1420", "- pan (002): 4567909845671234", "+ action_code (039): 400"]);
+
+        let diffs = rev_msg.diff(&req_msg);
+        assert_eq!(diffs[1], FieldDiff { name: "pan".to_string(), position: 2, kind: DiffKind::Added, old: None, new: Some("4567909845671234".to_string()) });
+        assert!(format!("{:?}", req_msg).contains("(\"stan\", \"779581\")"));
+    }
+
+    #[test]
+    fn test_translate_pin() {
+        let spec = builtin_spec("ISO8583_1987_ASCII").unwrap();
+        let pan = "4111111111111111";
+        let mut acq_cfg = Config::new();
+        acq_cfg.with_pin(PinFormat::ISO0, "e0f4543f3e2a2c5ffc7e5e5a222e3e4d".to_string());
+        let mut iss_cfg = Config::new();
+        iss_cfg.with_pin(PinFormat::ISO3, "00112233445566778899aabbccddeeff".to_string())
+            .with_security_control_info("2602000000000000".to_string());
+
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("0200").unwrap());
+        iso_msg.set("message_type", "0200").unwrap();
+        iso_msg.set_pin("1234", pan, &acq_cfg).unwrap();
+        iso_msg.translate_pin(pan, &acq_cfg, &iss_cfg).unwrap();
+
+        let pin_block = hex::decode(iso_msg.bmp_child_value(52).unwrap()).unwrap();
+        let iss_key = hex::decode("00112233445566778899aabbccddeeff").unwrap();
+        assert!(verify_pin(&PinFormat::ISO3, "1234", &pin_block, pan, &iss_key).unwrap());
+        assert_eq!(iso_msg.bmp_child_value(53).unwrap(), "2602000000000000");
+
+        assert!(iso_msg.translate_pin(pan, &acq_cfg, &Config::new()).is_err());
+
+        // F52 is 8 bytes, so a ISO-4 PIN block can't be set
+        let mut aes_cfg = Config::new();
+        aes_cfg.with_pin(PinFormat::ISO4, "000102030405060708090a0b0c0d0e0f".to_string());
+        let err = iso_msg.translate_pin(pan, &iss_cfg, &aes_cfg).err().unwrap();
+        assert_eq!(err.msg, "ISO4 PIN block of 16 bytes does not fit in F52 of 8 bytes");
+        assert_eq!(hex::decode(iso_msg.bmp_child_value(52).unwrap()).unwrap(), pin_block);
+    }
+
+    #[test]
+    fn test_dukpt_pin_and_mac() {
+        let spec = builtin_spec("ISO8583_1993_ASCII").unwrap();
+        let bdk = "0123456789ABCDEFFEDCBA9876543210".to_string();
+        let mut cfg = Config::new();
+        cfg.with_dukpt_pin(PinFormat::ISO0, bdk.clone())
+            .with_dukpt_mac(MacAlgo::RetailMac, PaddingType::Type1, bdk.clone());
+
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("1200").unwrap());
+        iso_msg.set("message_type", "1200").unwrap();
+        iso_msg.set_on(4, "000000001000").unwrap();
+        assert!(iso_msg.set_pin("1234", "4012345678909", &cfg).is_err());
+
+        iso_msg.set_on(53, "FFFF9876543210E00001").unwrap();
+        iso_msg.set_pin("1234", "4012345678909", &cfg).unwrap();
+        assert_eq!(iso_msg.bmp_child_value(52).unwrap(), "1b9c1845eb993a7a");
+        assert!(iso_msg.verify_pin("1234", "4012345678909", &cfg).unwrap());
+        assert!(!iso_msg.verify_pin("4321", "4012345678909", &cfg).unwrap());
+
+        iso_msg.set_mac(&cfg).unwrap();
+        let data = iso_msg.assemble().unwrap();
+        let mac_key = dukpt::derive_key(&hex::decode(&bdk).unwrap(), &hex::decode("FFFF9876543210E00001").unwrap(), KeyUsage::MacRequest).unwrap();
+        let mac = generate_mac(&MacAlgo::RetailMac, &PaddingType::Type1, &data[0..data.len() - 8], &mac_key).ok().unwrap();
+        assert_eq!(iso_msg.bmp_child_value(64).unwrap(), hex::encode(mac));
+
+        // the KSN can be in another field
+        let spec = builtin_spec("ISO8583_1987_ASCII").unwrap();
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("0200").unwrap());
+        iso_msg.set("message_type", "0200").unwrap();
+        iso_msg.set_on(48, "FFFF9876543210E00001").unwrap();
+        cfg.with_ksn_position(48);
+        iso_msg.set_pin("1234", "4012345678909", &cfg).unwrap();
+        assert_eq!(iso_msg.bmp_child_value(52).unwrap(), "1b9c1845eb993a7a");
+    }
+
+    #[test]
+    fn test_aes_dukpt_pin_and_mac() {
+        let spec = builtin_spec("ISO8583_1993_ASCII").unwrap();
+        let bdk = "FEDCBA9876543210F1F1F1F1F1F1F1F1".to_string();
+        let mut cfg = Config::new();
+        cfg.with_aes_dukpt_pin(PinFormat::ISO4, bdk.clone());
+
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("1200").unwrap());
+        iso_msg.set("message_type", "1200").unwrap();
+        iso_msg.set_on(53, "FFFF9876543210E00001").unwrap();
+        assert!(iso_msg.pin_key(&cfg).is_err());
+
+        iso_msg.set_on(53, "123456789012345600000001").unwrap();
+        assert_eq!(iso_msg.pin_key(&cfg).unwrap(), "af8cb133a78f8dc2d1359f18527593fb");
+
+        // a TDES working key for the other PIN block formats
+        cfg.with_aes_dukpt_pin(PinFormat::ISO0, bdk.clone())
+            .with_aes_dukpt_mac(MacAlgo::RetailMac, PaddingType::Type1, bdk.clone());
+        assert_eq!(iso_msg.pin_key(&cfg).unwrap(), "630c706d9546e47d4449313f61c4d4ab");
+        iso_msg.set_pin("1234", "4012345678909", &cfg).unwrap();
+        assert!(iso_msg.verify_pin("1234", "4012345678909", &cfg).unwrap());
+
+        iso_msg.set_mac(&cfg).unwrap();
+        let data = iso_msg.assemble().unwrap();
+        let mac_key = dukpt::derive_aes_key(&hex::decode(&bdk).unwrap(), &hex::decode("123456789012345600000001").unwrap(), KeyUsage::MacRequest, Cipher::TdesEde2).unwrap();
+        let mac = generate_mac(&MacAlgo::RetailMac, &PaddingType::Type1, &data[0..data.len() - 8], &mac_key).ok().unwrap();
+        assert_eq!(iso_msg.bmp_child_value(64).unwrap(), hex::encode(mac));
+    }
+
+    #[test]
+    fn test_set_mac_aes_cmac_hmac() {
+        let spec = builtin_spec("ISO8583_1993_ASCII").unwrap();
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("1200").unwrap());
+        iso_msg.set("message_type", "1200").unwrap();
+        iso_msg.set_on(4, "000000001000").unwrap();
+
+        let key = "2b7e151628aed2a6abf7158809cf4f3c".to_string();
+        let mut cfg = Config::new();
+        cfg.with_mac(MacAlgo::AesCmac, PaddingType::Type1, key.clone());
+        iso_msg.set_mac(&cfg).unwrap();
+        let data = iso_msg.assemble().unwrap();
+        let mac = generate_mac(&MacAlgo::AesCmac, &PaddingType::Type1, &data[0..data.len() - 8], &hex::decode(&key).unwrap()).ok().unwrap();
+        assert_eq!(iso_msg.bmp_child_value(64).unwrap(), hex::encode(&mac[0..8]));
+
+        cfg.with_mac(MacAlgo::HmacSha256, PaddingType::Type1, key.clone());
+        iso_msg.set_mac(&cfg).unwrap();
+        let data = iso_msg.assemble().unwrap();
+        let mac = generate_mac(&MacAlgo::HmacSha256, &PaddingType::Type1, &data[0..data.len() - 8], &hex::decode(&key).unwrap()).ok().unwrap();
+        assert_eq!(iso_msg.bmp_child_value(64).unwrap(), hex::encode(&mac[0..8]));
+
+        // the MAC can't be truncated to less than 4 bytes and a AES-CMAC key should be a AES key
+        cfg.with_mac_length(3);
+        assert!(iso_msg.set_mac(&cfg).is_err());
+        cfg.with_mac(MacAlgo::AesCmac, PaddingType::Type1, "e0f4543f3e2a2c5f".to_string()).with_mac_length(8);
+        assert!(iso_msg.set_mac(&cfg).is_err());
+    }
+
+    #[test]
+    fn test_verify_mac() {
+        let spec = builtin_spec("ISO8583_1993_ASCII").unwrap();
+        let mut cfg = Config::new();
+        cfg.with_mac(MacAlgo::RetailMac, PaddingType::Type1, "e0f4543f3e2a2c5ffc7e5e5a222e3e4d".to_string());
+
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("1200").unwrap());
+        iso_msg.set("message_type", "1200").unwrap();
+        iso_msg.set_on(4, "000000001000").unwrap();
+        iso_msg.set_on(11, "779581").unwrap();
+        assert!(iso_msg.verify_mac(&cfg).is_err());
+        iso_msg.set_mac(&cfg).unwrap();
+        assert!(iso_msg.verify_mac(&cfg).unwrap());
+
+        let mut data = iso_msg.assemble().unwrap();
+        let parsed = spec.parse(&mut data.clone()).unwrap();
+        assert_eq!(parsed.raw(), Some(&data));
+        assert!(parsed.verify_mac(&cfg).unwrap());
+
+        // the MAC is verified on the data as received, until the message is changed
+        let mut modified = parsed.clone();
+        assert!(modified.raw().is_some());
+        modified.set_on(11, "779582").unwrap();
+        assert!(modified.raw().is_none());
+        assert!(!modified.verify_mac(&cfg).unwrap());
+
+        // tampered data
+        let pos = data.len() - 9;
+        data[pos] ^= 0x01;
+        assert!(!spec.parse(&mut data).unwrap().verify_mac(&cfg).unwrap());
+
+        // a wrong key
+        cfg.with_mac(MacAlgo::RetailMac, PaddingType::Type1, "00112233445566778899aabbccddeeff".to_string());
+        assert!(!parsed.verify_mac(&cfg).unwrap());
+    }
+
+    #[test]
+    fn test_mac_excludes_wire_header() {
+        let spec = tpdu_spec();
+        let mut cfg = Config::new();
+        cfg.with_mac(MacAlgo::RetailMac, PaddingType::Type1, "e0f4543f3e2a2c5ffc7e5e5a222e3e4d".to_string());
+
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("0100").unwrap());
+        iso_msg.set("tpdu_id", "60").unwrap();
+        iso_msg.set("tpdu_dest", "0001").unwrap();
+        iso_msg.set("tpdu_src", "0002").unwrap();
+        iso_msg.set("message_type", "0100").unwrap();
+        iso_msg.set_on(11, "779581").unwrap();
+        iso_msg.set_mac(&cfg).unwrap();
+
+        // the MAC is on the data from the MTI to the MAC (not including the 5 byte TPDU)
+        let data = iso_msg.assemble().unwrap();
+        let mac = generate_mac(&MacAlgo::RetailMac, &PaddingType::Type1, &data[5..data.len() - 8],
+                               &hex::decode("e0f4543f3e2a2c5ffc7e5e5a222e3e4d").unwrap()).ok().unwrap();
+        assert_eq!(&data[data.len() - 8..], &mac[..]);
+        assert!(spec.parse(&mut data.clone()).unwrap().verify_mac(&cfg).unwrap());
+
+        // a TPDU rewritten in transit (like by a NAC) does not invalidate the MAC
+        let mut routed = data.clone();
+        routed[1..5].copy_from_slice(&hex::decode("00030004").unwrap());
+        assert!(spec.parse(&mut routed).unwrap().verify_mac(&cfg).unwrap());
+    }
+
+    #[test]
+    fn test_selective_field_mac() {
+        let spec = builtin_spec("ISO8583_1987_ASCII").unwrap();
+        let key = "e0f4543f3e2a2c5ffc7e5e5a222e3e4d".to_string();
+        let mut cfg = Config::new();
+        cfg.with_mac(MacAlgo::RetailMac, PaddingType::Type1, key.clone())
+            .with_mac_input(MacInput::Fields(MacFields {
+                separator: " ".to_string(),
+                ..MacFields::new(&["message_type", "F2", "F3", "F4", "F11", "F38", "F39"])
+            }));
+
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("0200").unwrap());
+        iso_msg.set("message_type", "0200").unwrap();
+        iso_msg.set_on(2, "4111111111111111").unwrap();
+        iso_msg.set_on(3, "004000").unwrap();
+        iso_msg.set_on(4, "000000001000").unwrap();
+        iso_msg.set_on(11, "779581").unwrap();
+        iso_msg.set_on(41, "TERM0001").unwrap();
+        iso_msg.set_mac(&cfg).unwrap();
+
+        let mac = generate_mac(&MacAlgo::RetailMac, &PaddingType::Type1, b"0200 4111111111111111 004000 000000001000 779581",
+                               &hex::decode(&key).unwrap()).ok().unwrap();
+        assert_eq!(iso_msg.bmp_child_value(64).unwrap(), hex::encode(mac));
+
+        // fields that are not MAC'd can change
+        let mut parsed = spec.parse(&mut iso_msg.assemble().unwrap()).unwrap();
+        assert!(parsed.verify_mac(&cfg).unwrap());
+        parsed.set_on(41, "TERM0002").unwrap();
+        assert!(parsed.verify_mac(&cfg).unwrap());
+        parsed.set_on(4, "000000002000").unwrap();
+        assert!(!parsed.verify_mac(&cfg).unwrap());
+
+        // the full message MAC doesn't verify
+        cfg.with_mac_input(MacInput::FullMessage);
+        assert!(!iso_msg.verify_mac(&cfg).unwrap());
+    }
+
+    #[test]
+    fn test_security_module() {
+        let spec = builtin_spec("ISO8583_1987_ASCII").unwrap();
+        let pan = "4111111111111111";
+        let sm: Arc<dyn SecurityModule> = Arc::new(NamedKeySecurityModule {
+            keys: vec![("ZPK.ACQ", "e0f4543f3e2a2c5ffc7e5e5a222e3e4d"), ("ZPK.ISS", "00112233445566778899aabbccddeeff"),
+                       ("TAK", "0123456789abcdeffedcba9876543210")].into_iter().map(|(n, k)| (n.to_string(), k.to_string())).collect()
+        });
+
+        let mut acq_cfg = Config::new();
+        acq_cfg.with_pin(PinFormat::ISO0, "ZPK.ACQ".to_string())
+            .with_mac(MacAlgo::RetailMac, PaddingType::Type1, "TAK".to_string())
+            .with_security_module(sm.clone());
+        let mut iss_cfg = Config::new();
+        iss_cfg.with_pin(PinFormat::ISO3, "ZPK.ISS".to_string()).with_security_module(sm.clone());
+
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("0200").unwrap());
+        iso_msg.set("message_type", "0200").unwrap();
+        iso_msg.set_on(4, "000000001000").unwrap();
+        iso_msg.set_pin("1234", pan, &acq_cfg).unwrap();
+        assert!(iso_msg.verify_pin("1234", pan, &acq_cfg).unwrap());
+        iso_msg.translate_pin(pan, &acq_cfg, &iss_cfg).unwrap();
+        assert!(iso_msg.verify_pin("1234", pan, &iss_cfg).unwrap());
+        // the keys should be keys of the same security module
+        let mut sw_cfg = Config::new();
+        sw_cfg.with_pin(PinFormat::ISO3, "00112233445566778899aabbccddeeff".to_string());
+        assert!(iso_msg.translate_pin(pan, &iss_cfg, &sw_cfg).is_err());
+
+        iso_msg.set_mac(&acq_cfg).unwrap();
+        assert!(iso_msg.verify_mac(&acq_cfg).unwrap());
+
+        // the same result as the software security module with the clear keys
+        let mut cfg = Config::new();
+        cfg.with_pin(PinFormat::ISO3, "00112233445566778899aabbccddeeff".to_string())
+            .with_mac(MacAlgo::RetailMac, PaddingType::Type1, "0123456789abcdeffedcba9876543210".to_string());
+        assert!(iso_msg.verify_pin("1234", pan, &cfg).unwrap());
+        assert!(iso_msg.verify_mac(&cfg).unwrap());
+
+        // F53 is a fixed 16 in ISO8583:1987, so the KSN is in F48
+        iss_cfg.with_dukpt_pin(PinFormat::ISO0, "BDK".to_string()).with_ksn_position(48);
+        iso_msg.set_on(48, "FFFF9876543210E00001").unwrap();
+        assert!(iso_msg.set_pin("1234", pan, &iss_cfg).is_err());
+        iss_cfg.with_pin(PinFormat::ISO0, "ZPK.UNKNOWN".to_string());
+        assert!(iso_msg.set_pin("1234", pan, &iss_cfg).is_err());
+    }
+
+    #[test]
+    fn test_verify_pin_data() {
+        let spec = builtin_spec("ISO8583_1987_ASCII").unwrap();
+        let pan = "4111111111111111";
+        let mut cfg = Config::new();
+        cfg.with_pin(PinFormat::ISO0, "e0f4543f3e2a2c5ffc7e5e5a222e3e4d".to_string());
+
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("0200").unwrap());
+        iso_msg.set("message_type", "0200").unwrap();
+        iso_msg.set_pin("1234", pan, &cfg).unwrap();
+
+        let pvv = PinVerificationData::VisaPvv { pvki: 1, pvv: "9464".to_string() };
+        assert!(iso_msg.verify_pin_data(pan, &pvv, &cfg).is_err());
+
+        cfg.with_pvk("0123456789ABCDEFFEDCBA9876543210".to_string());
+        assert!(iso_msg.verify_pin_data(pan, &pvv, &cfg).unwrap());
+        assert!(!iso_msg.verify_pin_data(pan, &PinVerificationData::VisaPvv { pvki: 2, pvv: "9464".to_string() }, &cfg).unwrap());
+
+        let offset = PinVerificationData::Ibm3624 { validation_data: pan.to_string(), dec_table: "0123456789012345".to_string(), offset: "5305".to_string() };
+        assert!(iso_msg.verify_pin_data(pan, &offset, &cfg).unwrap());
+        iso_msg.set_pin("1235", pan, &cfg).unwrap();
+        assert!(!iso_msg.verify_pin_data(pan, &offset, &cfg).unwrap());
+    }
+
+    #[test]
+    fn test_verify_cvv() {
+        let spec = builtin_spec("ISO8583_1987_ASCII").unwrap();
+        let mut cfg = Config::new();
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("0100").unwrap());
+        iso_msg.set("message_type", "0100").unwrap();
+        assert!(iso_msg.get_track_data().is_err());
+        assert!(iso_msg.verify_cvv(CvvType::Cvv, &cfg).is_err());
+
+        cfg.with_cvk("0123456789ABCDEFFEDCBA9876543210".to_string()).with_cvv_position(5);
+        iso_msg.set_on(45, "B4123456789012345^DOE/JOHN^870110100000561000000").unwrap();
+        assert_eq!(iso_msg.get_track_data().unwrap().name.as_deref(), Some("DOE/JOHN"));
+        assert!(iso_msg.verify_cvv(CvvType::Cvv, &cfg).unwrap());
+
+        // F35 takes precedence over F45
+        iso_msg.set_on(35, "4123456789012345=870110100000562").unwrap();
+        assert!(!iso_msg.verify_cvv(CvvType::Cvv, &cfg).unwrap());
+        iso_msg.set_on(35, "4123456789012345=870110100000651").unwrap();
+        assert!(iso_msg.verify_cvv(CvvType::Icvv, &cfg).unwrap());
+        assert!(!iso_msg.verify_cvv(CvvType::Cvv, &cfg).unwrap());
+        cfg.with_cvv_position(13);
+        assert!(iso_msg.verify_cvv(CvvType::Cvv, &cfg).is_err());
+
+        iso_msg.set_on(2, "4123456789012345").unwrap();
+        iso_msg.set_on(14, "8701").unwrap();
+        iso_msg.set_on(48, "636").unwrap();
+        assert!(iso_msg.verify_cvv(CvvType::Cvv2, &cfg).unwrap());
+        iso_msg.set_on(48, "AB636").unwrap();
+        assert!(!iso_msg.verify_cvv(CvvType::Cvv2, &cfg).unwrap());
+        cfg.with_cvv2_position(2);
+        assert!(iso_msg.verify_cvv(CvvType::Cvv2, &cfg).unwrap());
+    }
+
+    #[test]
+    fn test_arqc_arpc() {
+        let spec = builtin_spec("ISO8583_1987_ASCII").unwrap();
+        let mut cfg = Config::new();
+        let mut req = new_msg(spec, spec.get_message_from_header("0100").unwrap());
+        req.set("message_type", "0100").unwrap();
+        req.set_on(2, "5413330089010434").unwrap();
+        req.set_on(55, "9F2608D9F38036608A59E69F02060000000010009F03060000000000009F1A0208269505000000000\
+                        05F2A0208269A032510189C01009F37041234567882021800\
+                        9F360200019F10120110A00003220000000000000000000000FF").unwrap();
+        assert_eq!(req.get_icc_data().unwrap().len(), 12);
+        assert!(req.verify_arqc(&cfg).is_err());
+
+        cfg.with_emv("0123456789ABCDEFFEDCBA9876543210".to_string(), SessionKeyDerivation::Common, PaddingType::Type2);
+        assert!(req.verify_arqc(&cfg).unwrap());
+
+        // a different PAN sequence number
+        req.set_on(23, "001").unwrap();
+        assert!(!req.verify_arqc(&cfg).unwrap());
+        req.set_on(23, "000").unwrap();
+
+        let mut resp = new_msg(spec, spec.get_message_from_header("0110").unwrap());
+        resp.set("message_type", "0110").unwrap();
+        resp.set_on(55, "8A023030").unwrap();
+        resp.set_arpc(&req, &ArpcMethod::Method1 { arc: b"00".to_vec() }, &cfg).unwrap();
+        assert_eq!(resp.bmp_child_value(55).unwrap(), "8A023030910A74C99AEED052B5233030");
+        resp.set_arpc(&req, &ArpcMethod::Method2 { csu: vec![0x00, 0x82, 0x00, 0x00], prop_auth_data: vec![] }, &cfg).unwrap();
+        assert_eq!(resp.bmp_child_value(55).unwrap(), "8A023030910815E8E32D00820000");
+
+        // the ARQC isn't computed on the other tags
+        cfg.with_arqc_tags(&["9F02", "9F36"]);
+        assert!(!req.verify_arqc(&cfg).unwrap());
+        cfg.with_arqc_tags(&["9F02", "9F99"]);
+        assert!(req.verify_arqc(&cfg).is_err());
+    }
+}
@@ -6,8 +6,14 @@ pub mod iso_spec;
 pub mod server;
 mod test;
 mod yaml_de;
+mod code_page;
 pub mod mli;
 pub mod config;
+pub mod rules;
+pub mod defaults;
+pub mod amount;
+pub mod currency;
+pub mod track;
 
 /// IsoError represents a generic error throughout this and dependent sub-modules
 #[derive(Debug)]
@@ -0,0 +1,149 @@
+//! This module contains the presence rules (mandatory, conditional, echo etc) that apply to the fields
+//! of a message segment and the conditions that can be attached to them
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::iso8583::IsoError;
+
+/// This enum represents the presence of a field in a message
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Presence {
+    /// The field should always be present
+    Mandatory,
+    /// The field may or may not be present
+    Optional,
+    /// The field should be present when the condition on the rule is true
+    Conditional,
+    /// The field should be present and have the same value as in the request (applies to responses)
+    Echo,
+    /// The field should not be present
+    Forbidden,
+}
+
+/// This struct represents a presence rule for a field (by position in the bitmap) in a segment
+///
+/// A condition is an expression on other fields like "F52", "!F14", "F3 == 004000" or "message_type != 1110",
+/// terms can be combined with && and || (&& binds tighter). A rule with a condition applies only when the
+/// condition is true
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FieldRule {
+    pub position: u32,
+    pub presence: Presence,
+    pub condition: Option<String>,
+}
+
+/// This struct represents a violation of a FieldRule by a message
+#[derive(Debug)]
+pub struct Violation {
+    /// Position of the field
+    pub position: u32,
+    /// Name of the field
+    pub field: String,
+    pub msg: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({:03}): {}", self.field, self.position, self.msg)
+    }
+}
+
+/// Returns a single message that describes all the violations
+pub fn violations_to_string(violations: &[Violation]) -> String {
+    violations.iter().map(|v| v.to_string()).collect::<Vec<String>>().join("; ")
+}
+
+/// This enum represents a parsed condition
+#[derive(Debug)]
+pub(in crate::iso8583) enum Condition {
+    Or(Vec<Condition>),
+    And(Vec<Condition>),
+    Not(Box<Condition>),
+    Present(String),
+    Eq(String, String),
+    Ne(String, String),
+}
+
+impl Condition {
+    /// Parses expr into a Condition or returns a IsoError if expr is invalid
+    pub(in crate::iso8583) fn parse(expr: &str) -> Result<Condition, IsoError> {
+        let mut or_terms = vec![];
+        for or_term in expr.split("||") {
+            let mut and_terms = vec![];
+            for term in or_term.split("&&") {
+                and_terms.push(Condition::parse_term(term.trim(), expr)?);
+            }
+            or_terms.push(Condition::And(and_terms));
+        }
+        Ok(Condition::Or(or_terms))
+    }
+
+    fn parse_term(term: &str, expr: &str) -> Result<Condition, IsoError> {
+        let res = if let Some(idx) = term.find("!=") {
+            Condition::Ne(term[..idx].trim().to_string(), term[idx + 2..].trim().to_string())
+        } else if let Some(idx) = term.find("==") {
+            Condition::Eq(term[..idx].trim().to_string(), term[idx + 2..].trim().to_string())
+        } else if let Some(operand) = term.strip_prefix('!') {
+            Condition::Not(Box::new(Condition::Present(operand.trim().to_string())))
+        } else {
+            Condition::Present(term.to_string())
+        };
+
+        match res {
+            Condition::Present(ref operand) | Condition::Eq(ref operand, _) | Condition::Ne(ref operand, _) if operand.is_empty() => {
+                Err(IsoError { msg: format!("invalid term '{}' in condition - {}", term, expr) })
+            }
+            _ => Ok(res)
+        }
+    }
+
+    /// Evaluates the condition, value_of returns the value of a operand (field name or F<position>) if present
+    pub(in crate::iso8583) fn eval(&self, value_of: &dyn Fn(&str) -> Option<String>) -> bool {
+        match self {
+            Condition::Or(terms) => terms.iter().any(|t| t.eval(value_of)),
+            Condition::And(terms) => terms.iter().all(|t| t.eval(value_of)),
+            Condition::Not(term) => !term.eval(value_of),
+            Condition::Present(operand) => value_of(operand).is_some(),
+            Condition::Eq(operand, val) => value_of(operand).as_ref() == Some(val),
+            Condition::Ne(operand, val) => value_of(operand).as_ref() != Some(val),
+        }
+    }
+}
+
+/// Returns the position if the operand is a reference to a bitmap position (like F52 or f52)
+pub(in crate::iso8583) fn operand_position(operand: &str) -> Option<u32> {
+    if operand.starts_with('F') || operand.starts_with('f') {
+        operand[1..].parse::<u32>().ok()
+    } else {
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::iso8583::rules::Condition;
+
+    fn value_of(operand: &str) -> Option<String> {
+        match operand {
+            "F3" => Some("004000".to_string()),
+            "F52" => Some("0102030405060708".to_string()),
+            "message_type" => Some("1100".to_string()),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn test_conditions() {
+        assert!(Condition::parse("F52").unwrap().eval(&value_of));
+        assert!(!Condition::parse("F53").unwrap().eval(&value_of));
+        assert!(Condition::parse("!F53").unwrap().eval(&value_of));
+        assert!(Condition::parse("F3 == 004000 && message_type != 1110").unwrap().eval(&value_of));
+        assert!(!Condition::parse("F3 == 000000 && F52").unwrap().eval(&value_of));
+        assert!(Condition::parse("F3 == 000000 || F52").unwrap().eval(&value_of));
+        assert!(Condition::parse("F14 != 2204").unwrap().eval(&value_of));
+        assert!(Condition::parse("F3 == ").is_ok());
+        assert!(Condition::parse("F3 && == 1100").is_err());
+    }
+}
@@ -7,6 +7,7 @@ use witchcraft_metrics::{Meter, ExponentiallyDecayingReservoir, Histogram};
 use hexdump::hexdump_iter;
 
 use crate::iso8583::IsoError;
+use crate::iso8583::config::Config;
 use crate::iso8583::iso_spec::{IsoMsg, Spec};
 use crate::iso8583::mli::{MLI, MLI2E, MLI2I, MLI4E, MLI4I, MLIType};
 
@@ -15,6 +16,16 @@ pub struct IsoServerError {
     pub msg: String
 }
 
+/// This enum represents how the server handles a request whose MAC (F64/F128) fails verification
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MacPolicy {
+    /// The request is logged and doesn't reach `MsgProcessor::process`, the response (if any) is that of
+    /// `MsgProcessor::decline_mac_failure` (by default the request is dropped and the acquirer times out)
+    Reject,
+    /// The request is logged and handed to `MsgProcessor::process_mac_failure`
+    Flag,
+}
+
 /// This struct represents a IsoServer
 pub struct ISOServer {
     /// The listen address for this server
@@ -26,11 +37,25 @@ pub struct ISOServer {
     pub(crate) msg_processor: Arc<Box<dyn MsgProcessor>>,
     txn_rate_metric: Meter,
     response_time_metric: witchcraft_metrics::Histogram,
+    /// The MAC policy and the config (MAC algo, padding and key) to verify incoming requests with
+    mac_verification: Option<(MacPolicy, Arc<Config>)>,
 }
 
 /// This trait whose implementation is used by the IsoServer to handle incoming requests
 pub trait MsgProcessor: Send + Sync {
     fn process(&self, iso_server: &ISOServer, msg: &mut Vec<u8>) -> Result<(Vec<u8>, IsoMsg), IsoError>;
+
+    /// Handles a request whose MAC failed verification (with MacPolicy::Flag), by default the request is
+    /// processed like any other request
+    fn process_mac_failure(&self, iso_server: &ISOServer, msg: &mut Vec<u8>) -> Result<(Vec<u8>, IsoMsg), IsoError> {
+        self.process(iso_server, msg)
+    }
+
+    /// Returns the response (like a decline with a security violation action code) to a request whose MAC failed
+    /// verification (with MacPolicy::Reject), by default there is no response and the request is dropped
+    fn decline_mac_failure(&self, _iso_server: &ISOServer, _msg: &mut Vec<u8>) -> Result<(Vec<u8>, IsoMsg), IsoError> {
+        Err(IsoError { msg: "request with a invalid MAC dropped".to_string() })
+    }
 }
 
 impl ISOServer {
@@ -67,6 +92,7 @@ impl ISOServer {
                         msg_processor: Arc::new(msg_processor),
                         txn_rate_metric: Meter::new(),
                         response_time_metric: Histogram::new(ExponentiallyDecayingReservoir::new()),
+                        mac_verification: None,
                     })
                 } else {
                     Err(IsoServerError { msg: format!("invalid host_port: {} : unresolvable?", &host_port) })
@@ -86,6 +112,36 @@ impl ISOServer {
         &self.response_time_metric
     }
 
+    /// Use the server with a builder pattern - the MAC (F64/F128) of incoming requests is verified with the MAC algo,
+    /// padding and key of cfg before they reach the MsgProcessor and requests that fail verification (including
+    /// those without a MAC) are handled as per policy
+    pub fn with_mac_verification(&mut self, policy: MacPolicy, cfg: Config) -> &mut ISOServer {
+        self.mac_verification = Some((policy, Arc::new(cfg)));
+        self
+    }
+
+    /// Returns true if the MAC of the request in data verifies (or if there is no MAC verification)
+    fn verify_mac(&self, data: &[u8]) -> bool {
+        let cfg = match &self.mac_verification {
+            Some((_, cfg)) => cfg,
+            None => return true
+        };
+
+        match self.spec.parse(&mut data.to_vec()) {
+            Ok(iso_msg) => match iso_msg.verify_mac(cfg) {
+                Ok(res) => res,
+                Err(e) => {
+                    warn!("failed to verify MAC of {} - {}", iso_msg.msg.name(), e.msg);
+                    false
+                }
+            },
+            Err(e) => {
+                warn!("failed to parse request to verify MAC - {}", e.msg);
+                false
+            }
+        }
+    }
+
     /// Starts the server in a separate thread
     pub fn start(&self) -> JoinHandle<()> {
         let server = ISOServer {
@@ -95,6 +151,7 @@ impl ISOServer {
             msg_processor: self.msg_processor.clone(),
             txn_rate_metric: Meter::new(),
             response_time_metric: Histogram::new(ExponentiallyDecayingReservoir::new()),
+            mac_verification: self.mac_verification.clone(),
         };
 
         std::thread::spawn(move || {
@@ -118,6 +175,7 @@ fn new_client(iso_server: &ISOServer, stream_: TcpStream) {
         msg_processor: iso_server.msg_processor.clone(),
         txn_rate_metric: Meter::new(),
         response_time_metric: Histogram::new(ExponentiallyDecayingReservoir::new()),
+        mac_verification: iso_server.mac_verification.clone(),
     };
 
     std::thread::spawn(move || {
@@ -160,7 +218,23 @@ fn new_client(iso_server: &ISOServer, stream_: TcpStream) {
                     reading_mli = true;
 
                     debug!("received request: \n{}\n len = {}", get_hexdump(&data), mli);
-                    match server.msg_processor.process(&server, &mut data) {
+                    let res = match server.mac_verification {
+                        Some((policy, _)) if !server.verify_mac(&data) => {
+                            match policy {
+                                MacPolicy::Reject => {
+                                    error!("rejecting request with a invalid MAC from {}", stream.peer_addr().unwrap());
+                                    server.msg_processor.decline_mac_failure(&server, &mut data)
+                                }
+                                MacPolicy::Flag => {
+                                    warn!("request with a invalid MAC from {}", stream.peer_addr().unwrap());
+                                    server.msg_processor.process_mac_failure(&server, &mut data)
+                                }
+                            }
+                        }
+                        _ => server.msg_processor.process(&server, &mut data)
+                    };
+
+                    match res {
                         Ok(resp) => {
                             debug!("iso_response : {} \n parsed :\n --- {} \n --- \n", get_hexdump(&resp.0), resp.1);
                             match server.mli.create(&(resp.0).len()) {
@@ -197,4 +271,48 @@ pub(in crate::iso8583) fn get_hexdump(data: &Vec<u8>) -> String {
 }
 
 
+#[cfg(test)]
+mod tests {
+    use crate::iso8583::server::{ISOServer, MacPolicy, MsgProcessor};
+    use crate::iso8583::iso_spec::{builtin_spec, new_msg, IsoMsg};
+    use crate::iso8583::mli::MLIType;
+    use crate::iso8583::config::Config;
+    use crate::iso8583::IsoError;
+    use crate::crypto::mac::{MacAlgo, PaddingType};
 
+    struct EchoMsgProcessor {}
+
+    impl MsgProcessor for EchoMsgProcessor {
+        fn process(&self, iso_server: &ISOServer, msg: &mut Vec<u8>) -> Result<(Vec<u8>, IsoMsg), IsoError> {
+            let iso_msg = iso_server.spec.parse(msg).map_err(|e| IsoError { msg: e.msg })?;
+            Ok((msg.clone(), iso_msg))
+        }
+    }
+
+    #[test]
+    fn test_verify_mac() {
+        let spec = builtin_spec("ISO8583_1987_ASCII").unwrap();
+        let mut server = ISOServer::new("127.0.0.1:0".to_string(), spec, MLIType::MLI2E, Box::new(EchoMsgProcessor {})).ok().unwrap();
+        assert!(server.verify_mac(&[]));
+
+        let mut cfg = Config::new();
+        cfg.with_mac(MacAlgo::RetailMac, PaddingType::Type1, "e0f4543f3e2a2c5ffc7e5e5a222e3e4d".to_string());
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("0200").unwrap());
+        iso_msg.set("message_type", "0200").unwrap();
+        iso_msg.set_on(4, "000000001000").unwrap();
+        let without_mac = iso_msg.assemble().unwrap();
+        iso_msg.set_mac(&cfg).unwrap();
+        let mut data = iso_msg.assemble().unwrap();
+
+        server.with_mac_verification(MacPolicy::Reject, cfg);
+        assert!(server.verify_mac(&data));
+        assert!(!server.verify_mac(&without_mac));
+        assert!(!server.verify_mac(&[0x30, 0x32]));
+
+        let pos = data.len() - 1;
+        data[pos] ^= 0x01;
+        assert!(!server.verify_mac(&data));
+        // no response by default
+        assert!(server.msg_processor.decline_mac_failure(&server, &mut data).is_err());
+    }
+}
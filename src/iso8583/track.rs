@@ -0,0 +1,101 @@
+//! This module contains the parsing of the magnetic stripe track data (track 2 in F35 and track 1 in F45), which
+//! can be read via `IsoMsg::get_track_data`
+
+use crate::iso8583::IsoError;
+
+/// This struct represents the track data of a card
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackData {
+    pub pan: String,
+    /// The name of the card holder (only in track 1)
+    pub name: Option<String>,
+    /// The expiry date (YYMM)
+    pub expiry: String,
+    pub service_code: String,
+    /// The issuer discretionary data (like the PVKI, PVV and CVV)
+    pub discretionary_data: String,
+}
+
+impl TrackData {
+    /// Parses track 2 data - PAN, separator ('=' or 'D'), expiry (YYMM), service code and discretionary data
+    /// (the start and end sentinels are optional)
+    pub fn parse_track2(val: &str) -> Result<TrackData, IsoError> {
+        let track = val.trim_start_matches(';').trim_end_matches('?');
+        let (pan, rest) = match track.find(['=', 'D']) {
+            Some(i) => (&track[0..i], &track[i + 1..]),
+            None => return Err(IsoError { msg: format!("invalid track 2 data - no separator: {}", val) })
+        };
+
+        TrackData::new(pan, None, rest).map_err(|e| IsoError { msg: format!("invalid track 2 data - {}", e.msg) })
+    }
+
+    /// Parses track 1 data - format code ('B'), PAN, '^', name, '^', expiry (YYMM), service code and discretionary
+    /// data (the start and end sentinels are optional)
+    pub fn parse_track1(val: &str) -> Result<TrackData, IsoError> {
+        let track = val.trim_start_matches('%').trim_end_matches('?');
+        let parts: Vec<&str> = track.splitn(3, '^').collect();
+        if parts.len() != 3 || !parts[0].starts_with('B') {
+            return Err(IsoError { msg: format!("invalid track 1 data: {}", val) });
+        }
+
+        TrackData::new(&parts[0][1..], Some(parts[1].trim_end().to_string()), parts[2])
+            .map_err(|e| IsoError { msg: format!("invalid track 1 data - {}", e.msg) })
+    }
+
+    fn new(pan: &str, name: Option<String>, rest: &str) -> Result<TrackData, IsoError> {
+        if pan.is_empty() || pan.len() > 19 || !pan.chars().all(|c| c.is_ascii_digit()) {
+            return Err(IsoError { msg: format!("invalid PAN: {}", pan) });
+        }
+        // get (unlike a slice) doesn't panic on a non-ASCII character within the first 7 bytes
+        match rest.get(0..7) {
+            Some(s) if s.chars().all(|c| c.is_ascii_digit()) => (),
+            _ => return Err(IsoError { msg: format!("invalid expiry or service code: {}", rest) })
+        }
+
+        Ok(TrackData {
+            pan: pan.to_string(),
+            name,
+            expiry: rest[0..4].to_string(),
+            service_code: rest[4..7].to_string(),
+            discretionary_data: rest[7..].to_string(),
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::iso8583::track::TrackData;
+
+    #[test]
+    fn test_track2() {
+        let td = TrackData::parse_track2("4123456789012345=87011010123456789").unwrap();
+        assert_eq!(td.pan, "4123456789012345");
+        assert_eq!(td.expiry, "8701");
+        assert_eq!(td.service_code, "101");
+        assert_eq!(td.discretionary_data, "0123456789");
+        assert!(td.name.is_none());
+
+        assert_eq!(TrackData::parse_track2(";4123456789012345D8701101?").unwrap().discretionary_data, "");
+        assert!(TrackData::parse_track2("4123456789012345").is_err());
+        assert!(TrackData::parse_track2("4123456789012345=87011").is_err());
+        assert!(TrackData::parse_track2("41234567A9012345=8701101").is_err());
+        assert!(TrackData::parse_track2("4111111111111111=123456é").is_err());
+        assert!(TrackData::parse_track2("4111111111111111=é8701101").is_err());
+        assert_eq!(TrackData::parse_track2("4111111111111111=8701101é").unwrap().discretionary_data, "é");
+    }
+
+    #[test]
+    fn test_track1() {
+        let td = TrackData::parse_track1("%B4123456789012345^DOE/JOHN                  ^8701101000000561000000?").unwrap();
+        assert_eq!(td.pan, "4123456789012345");
+        assert_eq!(td.name.as_deref(), Some("DOE/JOHN"));
+        assert_eq!(td.expiry, "8701");
+        assert_eq!(td.service_code, "101");
+        assert_eq!(td.discretionary_data, "000000561000000");
+
+        assert!(TrackData::parse_track1("B4123456789012345^DOE/JOHN").is_err());
+        assert!(TrackData::parse_track1("A4123456789012345^DOE/JOHN^8701101").is_err());
+        assert!(TrackData::parse_track1("B4123456789012345^DOE/JOHN^870110é").is_err());
+    }
+}
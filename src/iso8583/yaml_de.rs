@@ -1,13 +1,17 @@
 //! This module contains implementation of spec deserialization logic from a YAML file
 use std::io::Read;
+use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
-use crate::iso8583::bitmap::BmpField;
+use crate::iso8583::bitmap::{BitmapFormat, BmpField};
 use crate::iso8583::field::{Encoding, Field, FixedField, VarField};
 use crate::iso8583::iso_spec::{MessageSegment, Spec};
 use crate::iso8583::IsoError;
+use crate::iso8583::rules::{Condition, FieldRule};
+use crate::iso8583::defaults::{DefaultValue, FieldDefault, Generator};
+use crate::iso8583::amount::{AmountField, FieldFormat};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct YField {
     pub name: String,
     pub id: u32,
@@ -18,6 +22,55 @@ pub struct YField {
     pub data_encoding: Encoding,
     pub position: Option<u32>,
     pub children: Option<Vec<YField>>,
+    /// Positions of fields from the spec's dictionary that are children of this (bitmapped) field
+    pub positions: Option<Vec<u32>>,
+    /// A constant default value for the field
+    pub default: Option<String>,
+    /// A generator for the default value of the field (like Stan or TransmissionDateTime)
+    pub generator: Option<Generator>,
+    /// Name of the wire header field whose value this field takes in a response (e.g. TPDU source/destination)
+    pub swap_with: Option<String>,
+    /// Format of the field value (like a Amount or a SignedAmount with a C/D prefix)
+    pub format: Option<FieldFormat>,
+    /// Position of the field that holds the currency code of a amount field (like 49)
+    pub currency: Option<u32>,
+    /// Layout of a bitmapped field on the wire - Binary (8 bytes, the default) or Hex (16 characters)
+    pub bitmap_format: Option<BitmapFormat>,
+}
+
+impl YField {
+    /// Collects the default value of this field and its children (if any) into defaults
+    pub(crate) fn collect_defaults(&self, defaults: &mut Vec<FieldDefault>) {
+        let value = match (&self.generator, &self.default) {
+            (Some(g), _) => Some(DefaultValue::Generated(*g)),
+            (None, Some(v)) => Some(DefaultValue::Constant(v.clone())),
+            (None, None) => None
+        };
+
+        if let Some(value) = value {
+            defaults.push(FieldDefault {
+                name: self.name.clone(),
+                position: self.position.unwrap_or(0),
+                len: self.len,
+                value,
+            });
+        }
+        self.children.iter().flatten().for_each(|c| c.collect_defaults(defaults));
+    }
+
+    /// Collects the amount formats of this field and its children (if any) into amounts
+    pub(crate) fn collect_amounts(&self, amounts: &mut Vec<AmountField>) {
+        if let (Some(format), Some(position)) = (self.format, self.position) {
+            amounts.push(AmountField {
+                name: self.name.clone(),
+                position,
+                len: self.len,
+                format,
+                currency: self.currency,
+            });
+        }
+        self.children.iter().flatten().for_each(|c| c.collect_amounts(amounts));
+    }
 }
 
 impl Into<Box<dyn Field>> for &YField {
@@ -55,6 +108,7 @@ impl Into<Box<dyn Field>> for &YField {
                     name: self.name.clone(),
                     id: self.id,
                     encoding: self.data_encoding.clone(),
+                    format: self.bitmap_format.unwrap_or_default(),
                     children,
                 })
             }
@@ -71,8 +125,23 @@ impl Into<Box<dyn Field>> for &YField {
 pub struct YSpec {
     pub(crate) name: String,
     pub(crate) id: u32,
+    /// Name of a built-in spec (like ISO8583_1987_ASCII) or path of a spec file (relative to this file) that
+    /// this spec extends/overrides
+    pub(crate) extends: Option<String>,
+    /// Fields (by position) that can be referenced by the bitmap of any segment in the spec
+    #[serde(default)]
+    pub(crate) dictionary: Vec<YField>,
+    #[serde(default)]
     pub(crate) messages: Vec<YMessageSegment>,
+    /// If true, the segments of the spec this spec extends that are not defined here are inherited too
+    #[serde(default)]
+    pub(crate) inherit_messages: bool,
+    #[serde(default)]
     pub(crate) header_fields: Vec<YField>,
+    pub(crate) wire_header: Option<Vec<YField>>,
+    /// If true, messages are validated against the rules of their segment after parse and before assemble
+    #[serde(default)]
+    pub(crate) validate: bool,
 }
 
 
@@ -86,6 +155,18 @@ impl Into<Spec> for YSpec {
         });
 
 
+        let mut wire_header_fields: Vec<Box<dyn Field>> = vec![];
+        let mut wire_header_swaps: Vec<(String, String)> = vec![];
+
+        if let Some(ref y_wire_header) = self.wire_header {
+            y_wire_header.iter().for_each(|f| {
+                wire_header_fields.push(Into::<Box<dyn Field>>::into(f));
+                if let Some(ref other) = f.swap_with {
+                    wire_header_swaps.push((f.name.clone(), other.clone()));
+                }
+            });
+        }
+
         let mut messages: Vec<MessageSegment> = vec![];
         self.messages.iter().for_each(|m| {
             messages.push(MessageSegment::from(m));
@@ -97,21 +178,274 @@ impl Into<Spec> for YSpec {
             id: self.id,
             messages,
             header_fields,
+            wire_header_fields,
+            wire_header_swaps,
+            validate: self.validate,
         }
     }
 }
 
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct YMessageSegment {
     pub(crate) name: String,
     pub(crate) id: u32,
     pub(crate) selector: Vec<String>,
+    /// Name of a segment whose fields this segment inherits (and overrides)
+    pub(crate) extends: Option<String>,
+    #[serde(default)]
     pub(crate) fields: Vec<YField>,
+    /// Presence rules for the fields of the segment
+    #[serde(default)]
+    pub(crate) rules: Vec<FieldRule>,
 }
 
 
-pub fn read_spec(spec_file: &str) -> Result<Spec, IsoError> {
+/// Names of all the built-in specs that can be extended or loaded via `iso_spec::builtin_spec`
+pub(crate) const BUILTIN_SPECS: [&str; 6] = ["ISO8583_1987_ASCII", "ISO8583_1987_BINARY",
+    "ISO8583_1993_ASCII", "ISO8583_1993_BINARY",
+    "ISO8583_2003_ASCII", "ISO8583_2003_BINARY"];
+
+const ISO8583_1987: &str = include_str!("specs/iso8583_1987.yaml");
+const ISO8583_1993: &str = include_str!("specs/iso8583_1993.yaml");
+const ISO8583_2003: &str = include_str!("specs/iso8583_2003.yaml");
+
+
+/// Returns the (resolved) definition of a built-in spec given its name. The _ASCII variants have the bitmap
+/// as hex characters while the _BINARY variants have a 8 byte binary bitmap
+pub(crate) fn builtin_y_spec(name: &str) -> Result<YSpec, IsoError> {
+    let (yaml_str, bmp_encoding, bmp_format) = match name {
+        "ISO8583_1987" | "ISO8583_1987_ASCII" => (ISO8583_1987, Encoding::ASCII, BitmapFormat::Hex),
+        "ISO8583_1987_BINARY" => (ISO8583_1987, Encoding::BINARY, BitmapFormat::Binary),
+        "ISO8583_1993" | "ISO8583_1993_ASCII" => (ISO8583_1993, Encoding::ASCII, BitmapFormat::Hex),
+        "ISO8583_1993_BINARY" => (ISO8583_1993, Encoding::BINARY, BitmapFormat::Binary),
+        "ISO8583_2003" | "ISO8583_2003_ASCII" => (ISO8583_2003, Encoding::ASCII, BitmapFormat::Hex),
+        "ISO8583_2003_BINARY" => (ISO8583_2003, Encoding::BINARY, BitmapFormat::Binary),
+        _ => return Err(IsoError { msg: format!("no such built-in spec - {}", name) })
+    };
+
+    let mut y_spec = match serde_yaml::from_str::<YSpec>(yaml_str) {
+        Ok(y_spec) => resolve(y_spec, None, &mut vec![])?,
+        Err(e) => return Err(IsoError { msg: format!("failed to read built-in spec {}: {}", name, e) })
+    };
+
+    y_spec.name = name.to_string();
+    y_spec.messages.iter_mut().for_each(|m| {
+        m.fields.iter_mut().filter(|f| f.field_type == "Bitmapped").for_each(|f| {
+            f.data_encoding = bmp_encoding;
+            f.bitmap_format = Some(bmp_format);
+        });
+    });
+    Ok(y_spec)
+}
+
+/// Returns a built-in spec given its name
+pub(crate) fn builtin(name: &str) -> Result<Spec, IsoError> {
+    Ok(expand(builtin_y_spec(name)?)?.into())
+}
+
+/// Resolves the spec it extends (if any) and returns y_spec merged over it. A base that is not
+/// a built-in spec is a spec file relative to base_dir, visited are the (canonical) paths of the spec files
+/// read so far and a spec file that extends itself (directly or not) is a error
+fn resolve(y_spec: YSpec, base_dir: Option<&Path>, visited: &mut Vec<PathBuf>) -> Result<YSpec, IsoError> {
+    match y_spec.extends {
+        Some(ref base_name) => {
+            let is_builtin = BUILTIN_SPECS.iter().any(|n| *n == base_name || n.strip_suffix("_ASCII") == Some(base_name));
+            let base = if is_builtin {
+                builtin_y_spec(base_name.as_str())?
+            } else {
+                let base_file = match base_dir {
+                    Some(dir) => dir.join(base_name),
+                    None => Path::new(base_name).to_path_buf()
+                };
+                read_y_spec(&base_file, visited)?
+            };
+            Ok(merge_spec(base, y_spec))
+        }
+        None => Ok(y_spec)
+    }
+}
+
+/// Expands the segments of y_spec - segments that extend another segment inherit its fields and
+/// the positions referenced by a bitmap are replaced with fields from the dictionary
+fn expand(mut y_spec: YSpec) -> Result<YSpec, IsoError> {
+    let mut messages: Vec<YMessageSegment> = vec![];
+    for m in &y_spec.messages {
+        messages.push(inherit_segment(&y_spec.messages, m, 0)?);
+    }
+
+    for m in messages.iter_mut() {
+        for f in m.fields.iter_mut() {
+            expand_positions(&y_spec.dictionary, f, m.name.as_str())?;
+        }
+
+        for r in m.rules.iter().filter(|r| r.condition.is_some()) {
+            if let Err(e) = Condition::parse(r.condition.as_ref().unwrap()) {
+                return Err(IsoError { msg: format!("invalid rule for position {} in {}: {}", r.position, m.name, e.msg) });
+            }
+        }
+    }
+
+    y_spec.messages = messages;
+    Ok(y_spec)
+}
+
+/// Returns seg merged over the segment it extends (if any)
+fn inherit_segment(messages: &[YMessageSegment], seg: &YMessageSegment, depth: u32) -> Result<YMessageSegment, IsoError> {
+    match seg.extends {
+        Some(ref parent_name) => {
+            if depth > messages.len() as u32 {
+                return Err(IsoError { msg: format!("cyclic extends in segment - {}", seg.name) });
+            }
+            match messages.iter().find(|m| &m.name == parent_name) {
+                Some(parent) => {
+                    let parent = inherit_segment(messages, parent, depth + 1)?;
+                    let mut res = merge_segment(&parent, seg);
+                    res.extends = None;
+                    Ok(res)
+                }
+                None => Err(IsoError { msg: format!("segment {} extends undefined segment - {}", seg.name, parent_name) })
+            }
+        }
+        None => Ok(seg.clone())
+    }
+}
+
+/// Replaces the positions referenced by field (if any) with the fields at those positions in the dictionary
+fn expand_positions(dictionary: &[YField], field: &mut YField, seg_name: &str) -> Result<(), IsoError> {
+    if let Some(positions) = field.positions.take() {
+        let mut children: Vec<YField> = vec![];
+        for pos in positions {
+            match dictionary.iter().find(|f| f.position == Some(pos)) {
+                Some(f) => children.push(f.clone()),
+                None => {
+                    return Err(IsoError { msg: format!("position {} referenced in {} is not defined in the dictionary", pos, seg_name) });
+                }
+            }
+        }
+        field.children = Some(merge_children(&children, field.children.as_deref().unwrap_or(&[])));
+    }
+    Ok(())
+}
+
+/// Merges y_spec over base. Header fields and wire header of base are used when not defined in y_spec and
+/// the dictionary of y_spec is merged over that of base. Every segment in y_spec is merged over the base segment
+/// of the same name (or the first base segment when there isn't one and the segment doesn't extend another
+/// segment) and if y_spec has no segments, the segments of base are used. When y_spec inherits messages, a
+/// segment without a base segment of the same name is a new segment and the remaining base segments follow the
+/// segments of y_spec
+fn merge_spec(base: YSpec, y_spec: YSpec) -> YSpec {
+    let mut messages: Vec<YMessageSegment> = if y_spec.messages.is_empty() {
+        base.messages.clone()
+    } else {
+        y_spec.messages.iter().map(|m| {
+            // a segment is merged over the base segment of the same name or (when the base segments are not
+            // inherited) over the base segment it extends, any other segment is a new one
+            let bm = match base.messages.iter().find(|bm| bm.name == m.name) {
+                Some(bm) => Some((bm, m.extends.clone().or(bm.extends.clone()))),
+                None if y_spec.inherit_messages => None,
+                None => match m.extends {
+                    Some(ref e) if !y_spec.messages.iter().any(|om| om.name == *e) => {
+                        base.messages.iter().find(|bm| bm.name == *e).map(|bm| (bm, bm.extends.clone()))
+                    }
+                    _ => None
+                }
+            };
+            match bm {
+                Some((bm, extends)) => {
+                    let mut res = merge_segment(bm, m);
+                    res.extends = extends;
+                    res
+                }
+                None => m.clone()
+            }
+        }).collect()
+    };
+
+    if y_spec.inherit_messages {
+        base.messages.iter().filter(|bm| !y_spec.messages.iter().any(|m| m.name == bm.name)).
+            for_each(|bm| messages.push(bm.clone()));
+    }
+
+    YSpec {
+        name: y_spec.name,
+        id: y_spec.id,
+        extends: None,
+        dictionary: merge_children(&base.dictionary, &y_spec.dictionary),
+        messages,
+        inherit_messages: false,
+        header_fields: if y_spec.header_fields.is_empty() { base.header_fields } else { y_spec.header_fields },
+        wire_header: y_spec.wire_header.or(base.wire_header),
+        validate: y_spec.validate || base.validate,
+    }
+}
+
+/// Returns a segment with the fields of seg merged over the fields of base, the rules of seg replace the
+/// rules of base for the same position
+fn merge_segment(base: &YMessageSegment, seg: &YMessageSegment) -> YMessageSegment {
+    let mut rules: Vec<FieldRule> = base.rules.iter().
+        filter(|br| !seg.rules.iter().any(|r| r.position == br.position)).
+        cloned().collect();
+    rules.extend(seg.rules.iter().cloned());
+
+    YMessageSegment {
+        rules,
+        name: seg.name.clone(),
+        id: seg.id,
+        selector: seg.selector.clone(),
+        extends: seg.extends.clone(),
+        fields: merge_fields(&base.fields, &seg.fields),
+    }
+}
+
+/// Merges fields over base_fields - top level fields are matched by name and replaced, children of a bitmap
+/// are matched by position and replaced (or added)
+fn merge_fields(base_fields: &[YField], fields: &[YField]) -> Vec<YField> {
+    let mut res: Vec<YField> = base_fields.iter().map(|bf| {
+        match fields.iter().find(|f| f.name == bf.name) {
+            Some(f) if f.field_type == "Bitmapped" && bf.field_type == "Bitmapped" => {
+                let mut merged = f.clone();
+                merged.bitmap_format = f.bitmap_format.or(bf.bitmap_format);
+                merged.children = Some(merge_children(bf.children.as_deref().unwrap_or(&[]),
+                                                      f.children.as_deref().unwrap_or(&[])));
+                if bf.positions.is_some() || f.positions.is_some() {
+                    let mut positions = bf.positions.clone().unwrap_or_default();
+                    for p in f.positions.iter().flatten() {
+                        if !positions.contains(p) {
+                            positions.push(*p);
+                        }
+                    }
+                    merged.positions = Some(positions);
+                }
+                merged
+            }
+            Some(f) => f.clone(),
+            None => bf.clone()
+        }
+    }).collect();
+
+    fields.iter().filter(|f| !base_fields.iter().any(|bf| bf.name == f.name)).for_each(|f| res.push(f.clone()));
+    res
+}
+
+fn merge_children(base_children: &[YField], children: &[YField]) -> Vec<YField> {
+    let mut res: Vec<YField> = base_children.iter().
+        filter(|bc| !children.iter().any(|c| c.position == bc.position)).
+        cloned().collect();
+
+    children.iter().for_each(|c| res.push(c.clone()));
+    res.sort_by_key(|c| c.position.unwrap_or(0));
+    res
+}
+
+/// Reads the spec in spec_file and resolves the spec it extends (if any)
+fn read_y_spec(spec_file: &Path, visited: &mut Vec<PathBuf>) -> Result<YSpec, IsoError> {
+    let canonical = spec_file.canonicalize().unwrap_or_else(|_| spec_file.to_path_buf());
+    if visited.contains(&canonical) {
+        return Err(IsoError { msg: format!("{}: cyclic extends of spec files", spec_file.display()) });
+    }
+    visited.push(canonical);
+
     match std::fs::File::open(spec_file) {
         Ok(f) => {
             let mut yaml_str = String::new();
@@ -119,21 +453,27 @@ pub fn read_spec(spec_file: &str) -> Result<Spec, IsoError> {
 
             match serde_yaml::from_str::<YSpec>(&yaml_str) {
                 Ok(y_spec) => {
-                    Ok(y_spec.into())
+                    resolve(y_spec, spec_file.parent(), visited)
                 }
-                Err(e) => Err(IsoError { msg: e.to_string() })
+                Err(e) => Err(IsoError { msg: format!("{}: {}", spec_file.display(), e) })
             }
         }
         Err(e) => {
-            Err(IsoError { msg: e.to_string() })
+            Err(IsoError { msg: format!("{}: {}", spec_file.display(), e) })
         }
     }
 }
 
+pub fn read_spec(spec_file: &str) -> Result<Spec, IsoError> {
+    Ok(expand(read_y_spec(Path::new(spec_file), &mut vec![])?)?.into())
+}
+
 
 #[cfg(test)]
 mod tests {
-    use crate::iso8583::yaml_de::read_spec;
+    use crate::iso8583::yaml_de::{builtin_y_spec, expand, read_spec};
+    use crate::iso8583::bitmap::BitmapFormat;
+    use crate::iso8583::iso_spec::{new_msg, Spec};
     use std::path::Path;
 
     #[test]
@@ -148,4 +488,100 @@ mod tests {
             Err(e) => assert!(false, e)
         };
     }
+
+    #[test]
+    fn test_deserialize_yaml_spec_with_wire_header() {
+        let path = Path::new(".").join("sample_spec").join("sample_spec_tpdu.yaml");
+
+        match read_spec(path.to_str().unwrap()) {
+            Ok(spec) => {
+                assert_eq!(3, spec.wire_header_fields.len());
+                assert_eq!(2, spec.wire_header_swaps.len());
+            }
+            Err(e) => panic!("{}", e.msg)
+        };
+    }
+
+    #[test]
+    fn test_bitmap_format() {
+        // a ASCII bitmap without a bitmap_format is 8 binary bytes on the wire (as before) and
+        // 16 hex characters only when bitmap_format is Hex (like in the built-in _ASCII specs)
+        for format in [None, Some(BitmapFormat::Hex)].iter() {
+            let mut y_spec = builtin_y_spec("ISO8583_1987_ASCII").unwrap();
+            y_spec.messages.iter_mut().for_each(|m| {
+                m.fields.iter_mut().filter(|f| f.field_type == "Bitmapped").for_each(|f| f.bitmap_format = *format)
+            });
+            let spec: &'static Spec = Box::leak(Box::new(expand(y_spec).unwrap().into()));
+
+            let mut iso_msg = new_msg(spec, spec.get_message_from_header("0200").unwrap());
+            iso_msg.set("message_type", "0200").unwrap();
+            iso_msg.set_on(2, "4111111111111111").unwrap();
+            iso_msg.set_on(3, "000000").unwrap();
+            iso_msg.set_on(4, "000000001000").unwrap();
+            iso_msg.set_on(11, "000001").unwrap();
+
+            let mut data = iso_msg.assemble().unwrap();
+            match format {
+                None => assert_eq!(hex::encode(&data[4..12]), "7020000000000000"),
+                Some(_) => assert_eq!(String::from_utf8(data[4..20].to_vec()).unwrap(), "7020000000000000")
+            }
+            assert_eq!(spec.parse(&mut data).unwrap().bmp_child_value(11).unwrap(), "000001");
+        }
+    }
+
+    #[test]
+    fn test_cyclic_spec_files() {
+        let dir = std::env::temp_dir().join(format!("iso8583_rs_cyclic_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.yaml"), "name: A\nid: 1\nextends: b.yaml\nmessages: []\n").unwrap();
+        std::fs::write(dir.join("b.yaml"), "name: B\nid: 2\nextends: a.yaml\nmessages: []\n").unwrap();
+
+        match read_spec(dir.join("a.yaml").to_str().unwrap()) {
+            Ok(_) => panic!("expected a error for cyclic extends"),
+            Err(e) => assert!(e.msg.contains("cyclic extends"), "{}", e.msg)
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unmatched_segment_is_new() {
+        let dir = std::env::temp_dir().join(format!("iso8583_rs_unmatched_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("spec.yaml"), r#"
+name: NetworkMgmt
+id: 1
+extends: ISO8583_1987_ASCII
+messages:
+  - name: "0800 - Network Management"
+    id: 1
+    selector:
+      - "0800"
+    fields:
+      - name: "message_type"
+        id: 1
+        type: Fixed
+        len: 4
+        data_encoding: ASCII
+      - name: "bitmap"
+        id: 2
+        type: Bitmapped
+        len: 0
+        data_encoding: ASCII
+        children:
+          - name: "stan"
+            id: 3
+            type: Fixed
+            len: 6
+            data_encoding: ASCII
+            position: 11
+"#).unwrap();
+
+        let spec = read_spec(dir.join("spec.yaml").to_str().unwrap()).unwrap();
+        let seg = spec.get_message_from_header("0800").unwrap();
+        assert_eq!(seg.field_by_name(&"stan".to_string()).unwrap().position(), 11);
+        // not merged over the (first) built-in segment
+        assert!(seg.field_by_name(&"pan".to_string()).is_err());
+        assert!(spec.get_message_from_header("0200").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }